@@ -12,50 +12,133 @@ def dataset_jsonl(
     on_error=None,
     sample_rate=None,
     num_threads=None,
-    f=None
+    f=None,
+    raw=None,
+    dtype="float32",
+    seek_mode="accurate",
+    cache_dir=None
 ):
     """
     Creates a reader object from a jsonl file.
+
+    Each line should contain a `path` and a `duration` field, lines with a null duration are
+    skipped. Paths can refer to members of uncompressed tar files, e.g. WebDataset shards, using
+    the `shard.tar#member.flac` syntax, and to members of zip files using the
+    `archive.zip!/dir/member.wav` syntax. When `raw` is set, the files are read as headerless pcm
+    data, see `DatasetReader`. `dtype` sets the type of the returned arrays, `seek_mode` how
+    segments are seeked to, and `cache_dir` where decoded pcm data gets cached, see
+    `DatasetReader`.
     """
     pass
 
 @staticmethod
-def durations(filenames):
+def durations(filenames, *, exact_duration=False):
     """
     Returns the durations for the audio files passed as input.
 
     The input argument is a list of filenames. For each of these files, the duration in seconds is
     returned as a float, None is returned if the files cannot be open or properly read.
+    When `exact_duration` is set, the durations are computed by scanning the audio packets rather
+    than using the frame counts from the file headers.
+    """
+    pass
+
+@staticmethod
+def pack(manifest_jsonl, out_dir, *, sample_rate, dtype="float32", shard_size_gb=1.0, num_threads=None):
+    """
+    Decodes the audio files from a jsonl manifest and writes them as packed pcm shards.
+
+    The manifest uses the same format as for `dataset_jsonl`. Each file is resampled to
+    `sample_rate` and stored as `dtype` data, one of "int16", "int32", "float32", or "float64",
+    in `out_dir`. The files are written one after the other in shards of at most `shard_size_gb`
+    gigabytes, a file larger than this gets a shard of its own. An `index.json` file records where
+    each file is stored and the resulting directory can be read with `DatasetReader.from_packed`.
+    Files that cannot be decoded are logged and skipped, the number of files that have been packed
+    is returned.
     """
     pass
 
 @staticmethod
-def read(filename, start_sec=None, duration_sec=None, sample_rate=None):
+def read(
+    filename, start_sec=None, duration_sec=None, sample_rate=None, *, format_hint=None, track=None, dtype="float32"
+):
     """
     Reads the content of an audio file and returns it as a numpy array.
 
     The input argument is a filename. Its content is decoded the audio data for the whole file and
     return it as a two dimensional numpy array as well as the sample rate.
+    Rather than a filename, the input can also be some bytes or a python file-like object that
+    supports `read` and `seek`, in which case `format_hint` can be used to specify the file
+    extension of the underlying data.
+    For files with multiple tracks, `track` can be used to select the track to decode either via
+    its index or its language.
+    `dtype` can be "int16", "int32", "float32", or "float64". Integer samples are returned without
+    going through float values unless some resampling is required.
     """
     pass
 
 @staticmethod
-def read_opus(filename):
+def read_metadata(filename, *, format_hint=None):
+    """
+    Reads the tags of an audio file, e.g. ID3 tags, vorbis comments, RIFF INFO chunks or MP4
+    metadata.
+
+    This returns a dictionary with the following keys: title, artist, album, language, comments,
+    and has_cover. Missing tags are set to None.
+    As for `read`, the input can also be an archive member path, some bytes, or a python file-like
+    object that supports `read` and `seek`, `format_hint` then specifies the file extension.
+    """
+    pass
+
+@staticmethod
+def read_opus(filename, *, with_tags=False):
     """
     Reads the whole content of an ogg/opus encoded file.
 
     This returns a two dimensional array as well as the sample rate. Currently all opus audio is
     encoded at 48kHz so this value is always returned.
+    The pre-skip samples from the OpusHead header and the padding after the last granule
+    position are removed.
+    When `with_tags` is set, a dictionary containing the vorbis comments from the OpusTags header
+    is returned as a third element. If a key appears multiple times, the last value is kept.
     """
     pass
 
 @staticmethod
-def read_opus_bytes(bytes):
+def read_opus_bytes(bytes, *, with_tags=False):
     """
     Reads bytes corresponding to an ogg/opus encoded file.
 
     This returns a two dimensional array as well as the sample rate. Currently all opus audio is
     encoded at 48kHz so this value is always returned.
+    The pre-skip samples from the OpusHead header and the padding after the last granule
+    position are removed.
+    When `with_tags` is set, a dictionary containing the vorbis comments from the OpusTags header
+    is returned as a third element. If a key appears multiple times, the last value is kept.
+    """
+    pass
+
+@staticmethod
+def read_raw(source, dtype, channels, sample_rate, offset=0, count=None):
+    """
+    Reads headerless pcm data, interleaved by channel, from a file or from some bytes.
+
+    `dtype` describes the samples and their endianness, e.g. "s16le", "s24le", "s32be", "f32le",
+    "f64le", or "u8". `offset` and `count` are expressed in samples per channel, by default all
+    the data after `offset` is read. The returned array has shape `(channels, samples)`.
+    """
+    pass
+
+@staticmethod
+def read_wav_metadata(filename):
+    """
+    Reads the broadcast wave metadata and the markers of a wav file.
+
+    This returns a dictionary with a `bext` key, None if the file has no `bext` chunk, and a
+    `markers` key containing a list of dictionaries with `position` and `label` keys, see
+    `write_wav`.
+    As for `read`, the input can also be an archive member path, some bytes, or a python file-like
+    object that supports `read` and `seek`.
     """
     pass
 
@@ -67,22 +150,83 @@ def resample(pcm, src_sample_rate, dst_sample_rate):
     pass
 
 @staticmethod
-def write_opus(filename, data, sample_rate):
+def scan_audio(paths, out_jsonl, *, num_threads=None, exact_duration=False, with_metadata=False):
+    """
+    Scans some audio files and writes a jsonl manifest with one line per file.
+
+    Each line contains the path, duration, sample rate, number of channels, codec, container, bits
+    per sample, file size and an error message if the file could not be read. The resulting file
+    can be loaded with `dataset_jsonl`, files that failed to read have a null duration and are
+    skipped. The number of files that could be read successfully is returned.
+    When `exact_duration` is set, the durations are computed by scanning the audio packets rather
+    than using the frame counts from the file headers. When `with_metadata` is set, the tags found
+    in each file are included under the `metadata` key, see `read_metadata`.
+    """
+    pass
+
+@staticmethod
+def write_flac(filename, data, sample_rate, *, bits_per_sample=16, compression_level=5):
+    """
+    Writes an audio file using the flac format based on pcm data from a numpy array.
+
+    The input array can either have a single dimension or have shape `(channels, samples)` with up
+    to 8 channels. The samples are quantized to `bits_per_sample`, either 16 or 24, and
+    `compression_level` goes from 0 (fastest) to 8 (smallest output). The array must contain at
+    least one sample.
+    `filename` can also be a python file-like object that supports `write`.
+    """
+    pass
+
+@staticmethod
+def write_opus(filename, data, sample_rate, *, tags=None):
     """
     Writes an opus file containing the input pcm data.
 
     Opus content is always encoded at 48kHz so the pcm data is resampled if sample_rate is
     different from 48000.
+    The optional `tags` dictionary is written as vorbis comments in the OpusTags header, e.g.
+    `{"SPEAKER": "alice", "LICENSE": "cc-by"}`.
+    `filename` can also be a python file-like object that supports `write`.
+    """
+    pass
+
+@staticmethod
+def write_opus_bytes(data, sample_rate, *, tags=None):
+    """
+    Encodes some pcm data as an ogg/opus stream and returns the resulting bytes, see `write_opus`.
+    """
+    pass
+
+@staticmethod
+def write_raw(filename, data, dtype):
+    """
+    Writes some pcm data as headerless pcm, interleaved by channel, see `read_raw` for the
+    supported values of `dtype`.
+
+    The input array can either have a single dimension or have shape `(channels, samples)`.
+    `filename` can also be a python file-like object that supports `write`.
     """
     pass
 
 @staticmethod
-def write_wav(filename, data, sample_rate):
+def write_wav(filename, data, sample_rate, *, bext=None, markers=None):
     """
     Writes an audio file using the wav format based on pcm data from a numpy array.
 
     This only supports a single channel at the moment so the input array data is expected to have a
-    single dimension.
+    single dimension. The RF64 format is used for files larger than 4GiB.
+    `filename` can also be a python file-like object that supports `write`.
+    The optional `bext` dictionary is written as broadcast wave metadata, its keys are
+    description, originator, originator_reference, origination_date, origination_time,
+    time_reference, and coding_history. `markers` is a list of dictionaries with a `position` key,
+    in samples, and an optional `label` key, these are written in the `cue ` and `LIST` chunks.
+    """
+    pass
+
+@staticmethod
+def write_wav_bytes(data, sample_rate, *, bext=None, markers=None):
+    """
+    Encodes some pcm data using the wav format and returns the resulting bytes, see `write_wav`.
     """
     pass
 
@@ -91,6 +235,12 @@ class DatasetIter:
         """ """
         pass
 
+    def close(self):
+        """
+        Stops the worker threads and waits for them to exit, the iteration ends after this.
+        """
+        pass
+
 class DatasetReader:
     def __init__(
         self,
@@ -102,10 +252,33 @@ class DatasetReader:
         on_error=None,
         sample_rate=None,
         num_threads=None,
-        f=None
+        f=None,
+        raw=None,
+        dtype="float32",
+        seek_mode="accurate",
+        cache_dir=None
     ):
         pass
 
+    @staticmethod
+    def from_packed(
+        packed_dir,
+        *,
+        duration_sec,
+        channel_len_per_thread=1,
+        pad_last_segment=False,
+        on_error=None,
+        num_threads=None,
+        f=None,
+        dtype="float32"
+    ):
+        """
+        Creates a reader object on the pcm shards written by `pack` in `packed_dir`. The segments
+        are read from memory maps of the shards without any decoding, the data has the sample rate
+        that was used when packing. `dtype` sets the type of the returned arrays.
+        """
+        pass
+
     def num_threads(self, num_threads):
         """ """
         pass
@@ -114,6 +287,14 @@ class DatasetReader:
         """ """
         pass
 
+    def ordered(self, ordered):
+        """
+        When `ordered` is false, the worker threads pick the next segment to process from a shared
+        queue and the samples are returned as soon as they are ready rather than in order, so that a
+        slow segment does not stall the iteration while other threads have results available.
+        """
+        pass
+
     def pad_last_segment(self, p):
         """ """
         pass
@@ -121,6 +302,11 @@ class DatasetReader:
     def seq(self, *, skip=0, step_by=1):
         """
         Sequential reading.
+
+        Each worker thread handles blocks of 4 consecutive segments so that they are decoded from
+        the same open file without seeking. The channel length per thread is raised to at least 4
+        in this mode, so that all the threads are kept busy while the results of a block are
+        consumed.
         """
         pass
 
@@ -131,7 +317,7 @@ class DatasetReader:
         pass
 
 class FileReader:
-    def __init__(self, path):
+    def __init__(self, path, *, exact_duration=False, track=None):
         pass
 
     @property
@@ -141,23 +327,54 @@ class FileReader:
         """
         pass
 
-    def decode(self, start_sec, duration_sec):
+    def chunks(self, chunk_sec, hop_sec=None):
+        """
+        Returns an iterator over windows of `chunk_sec` seconds, each window starting `hop_sec`
+        seconds after the previous one, `hop_sec` defaults to `chunk_sec`. The file is decoded
+        sequentially a single time, overlapping samples are reused rather than decoded again. The
+        last window is shorter when the end of the file is reached.
+        """
+        pass
+
+    def decode(self, start_sec, duration_sec, *, dtype="float32", seek_mode="accurate"):
         """
         Decodes the audio data from `start_sec` to `start_sec + duration_sec` and return the PCM
         data as a two dimensional numpy array. The first dimension is the channel, the second one
         is time.
         If the end of the file is reached, the decoding stops and the already decoded data is
         returned.
+        `dtype` can be "int16", "int32", "float32", or "float64", integer samples are not
+        converted through float values.
+        `seek_mode` can be "accurate" or "coarse", the latter avoids decoding or scanning from an
+        earlier position but the data may start slightly off `start_sec`, see `last_start_sec`.
         """
         pass
 
-    def decode_all(self):
+    def decode_all(self, *, dtype="float32"):
         """
         Decodes the audio data for the whole file and return it as a two dimensional numpy array.
         """
         pass
 
-    def decode_with_padding(self, start_sec, duration_sec):
+    def decode_into(self, out, start_sec):
+        """
+        Decodes the audio data starting at `start_sec` into `out`, a preallocated and writable
+        float32 array with shape `(channels, samples)` using a C-contiguous layout. The number of
+        samples written per channel is returned, this is smaller than the array length when the
+        end of the file is reached in which case the remaining values are left untouched.
+        """
+        pass
+
+    def decode_samples(self, start_sample, num_samples, *, dtype="float32", seek_mode="accurate"):
+        """
+        Decodes `num_samples` samples per channel starting from sample `start_sample` and return
+        the PCM data as a two dimensional numpy array. Positions are expressed in samples at the
+        file sample rate so the returned array has exactly `num_samples` columns, unless the end of
+        the file is reached in which case the already decoded data is returned.
+        """
+        pass
+
+    def decode_with_padding(self, start_sec, duration_sec, *, dtype="float32", seek_mode="accurate"):
         """
         Decodes the audio data from `start_sec` to `start_sec + duration_sec` and return the PCM
         data as a two dimensional numpy array. The first dimension is the channel, the second one
@@ -174,6 +391,38 @@ class FileReader:
         """
         pass
 
+    @staticmethod
+    def from_bytes(data, *, format_hint=None, exact_duration=False, track=None):
+        """
+        Creates a reader on some in-memory encoded audio data. `format_hint` is an optional file
+        extension describing the format of the data, e.g. "mp3".
+        """
+        pass
+
+    @property
+    def last_start_sec(self):
+        """
+        The start time in seconds of the data returned by the last decode call, this can differ
+        from the requested start time when using coarse seeking.
+        """
+        pass
+
+    @property
+    def metadata(self):
+        """
+        The tags found in the file as a dictionary with the following keys: title, artist, album,
+        language, comments, and has_cover.
+        """
+        pass
+
+    @property
+    def num_seeks(self):
+        """
+        The number of times the decode calls had to seek, decoding a segment that starts at or
+        shortly after the end of the previous one resumes from there without seeking.
+        """
+        pass
+
     @property
     def sample_rate(self):
         """
@@ -181,6 +430,50 @@ class FileReader:
         """
         pass
 
+    @property
+    def tracks(self):
+        """
+        The list of tracks available in the file. Each track is described by a dictionary with the
+        following keys: codec, channels, sample_rate, and language.
+        """
+        pass
+
+class FileReaderChunks:
+    """
+    An iterator over fixed size windows of an audio file, see `FileReader.chunks`.
+    """
+
+class OpusFileWriter:
+    """
+    Writes an ogg/opus file incrementally. A page is written for each chunk of data, the pages are
+    buffered and the file can be decoded up to the last flush if the process gets interrupted. The
+    end of stream is marked on `close`. `filename` can also be a python file-like object that
+    supports `write`.
+    """
+    def __init__(self, filename, sample_rate, *, channels=1, tags=None):
+        pass
+
+    def close(self):
+        """
+        Encodes the remaining data and marks the end of the stream, this is a no-op if the writer
+        has already been closed.
+        """
+        pass
+
+    def flush(self):
+        """
+        Writes the buffered pages, the data that does not fill a complete opus frame yet is only
+        encoded by the following writes or on `close`.
+        """
+        pass
+
+    def write(self, data):
+        """
+        Appends some pcm data to the file, the data should have a single dimension for mono audio
+        or shape `(channels, samples)`. Chunks can have any size.
+        """
+        pass
+
 class OpusStreamReader:
     def __init__(self, sample_rate, flush_every_n_samples=0):
         pass
@@ -191,8 +484,16 @@ class OpusStreamReader:
         """
         pass
 
+    def append_bytes_into(self, data, out):
+        """
+        Writes some ogg/opus bytes to the current stream and stores the decoded pcm data in `out`,
+        a preallocated and writable one dimensional float32 array. The number of samples written
+        is returned, samples that do not fit in `out` are kept for the next call.
+        """
+        pass
+
 class OpusStreamWriter:
-    def __init__(self, sample_rate):
+    def __init__(self, sample_rate, *, tags=None):
         pass
 
     def append_pcm(self, pcm):
@@ -201,3 +502,32 @@ class OpusStreamWriter:
         float values, the number of elements must be an allowed frame size, e.g. 960 or 1920.
         """
         pass
+
+class WavWriter:
+    """
+    Writes a wav file incrementally. The data is buffered and the header gets updated on `flush`
+    and `close`, so that the file remains readable up to the last flush if the process gets
+    interrupted. `filename` can also be a python file-like object that supports `write` and
+    `seek`.
+    """
+    def __init__(self, filename, sample_rate, *, channels=1):
+        pass
+
+    def close(self):
+        """
+        Finalizes the file, this is a no-op if the writer has already been closed.
+        """
+        pass
+
+    def flush(self):
+        """
+        Writes the buffered data and updates the header with the current length.
+        """
+        pass
+
+    def write(self, data):
+        """
+        Appends some pcm data to the file, the data should have a single dimension for mono audio
+        or shape `(channels, samples)`.
+        """
+        pass
@@ -0,0 +1,531 @@
+// Access to audio files stored inside archives without extracting them.
+//
+// Members of uncompressed tar files, e.g. WebDataset shards, are referred to using a path of the
+// form `shard.tar#member.flac`. Members of zip files use the `archive.zip!/dir/member.wav` syntax,
+// stored members are read in place whereas deflated members are decompressed in memory.
+// The index of the members of an archive is computed once and cached so that it gets shared
+// between the dataset workers, it is rebuilt if the size or modification time of the archive
+// changes.
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use symphonia::core::io::MediaSource;
+
+const TAR_BLOCK_SIZE: u64 = 512;
+// GNU long names and pax extended headers are read in memory, larger ones are rejected.
+const MAX_TAR_EXTENDED_HEADER_LEN: u64 = 1 << 20;
+const ZIP_EOCD_SIG: u32 = 0x06054b50;
+const ZIP64_EOCD_SIG: u32 = 0x06064b50;
+const ZIP64_EOCD_LOCATOR_SIG: u32 = 0x07064b50;
+const ZIP_CD_HEADER_SIG: u32 = 0x02014b50;
+const ZIP_LOCAL_HEADER_SIG: u32 = 0x04034b50;
+
+/// A bounded view on a range of bytes within a file, the positions are relative to the start of
+/// the range.
+pub struct SubFile {
+    file: std::fs::File,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl SubFile {
+    pub fn new(mut file: std::fs::File, start: u64, len: u64) -> std::io::Result<Self> {
+        file.seek(SeekFrom::Start(start))?;
+        Ok(Self { file, start, len, pos: 0 })
+    }
+}
+
+impl Read for SubFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.pos);
+        let to_read = u64::min(remaining, buf.len() as u64) as usize;
+        if to_read == 0 {
+            return Ok(0);
+        }
+        let n = self.file.read(&mut buf[..to_read])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SubFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::Current(delta) => self.pos as i64 + delta,
+            SeekFrom::End(delta) => self.len as i64 + delta,
+        };
+        if pos < 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before the start of the archive member",
+            ));
+        }
+        self.pos = pos as u64;
+        self.file.seek(SeekFrom::Start(self.start + self.pos))?;
+        Ok(self.pos)
+    }
+}
+
+impl MediaSource for SubFile {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.len)
+    }
+}
+
+/// The location of the data for a member of a tar file.
+#[derive(Debug, Clone, Copy)]
+struct TarMember {
+    offset: u64,
+    size: u64,
+}
+
+type TarIndex = HashMap<String, TarMember>;
+
+/// The size and modification time of an archive, used to detect that a cached index is stale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileVersion {
+    len: u64,
+    modified: Option<std::time::SystemTime>,
+}
+
+impl FileVersion {
+    fn new(path: &Path) -> Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(Self { len: metadata.len(), modified: metadata.modified().ok() })
+    }
+}
+
+type IndexCache<T> = LazyLock<Mutex<HashMap<PathBuf, (FileVersion, Arc<T>)>>>;
+
+/// Returns the cached index for `path`, or builds it if it is missing or out of date.
+fn cached_index<T>(
+    cache: &IndexCache<T>,
+    path: &Path,
+    build: fn(&Path) -> Result<T>,
+) -> Result<Arc<T>> {
+    let version = FileVersion::new(path)?;
+    if let Some((v, index)) = cache.lock().unwrap().get(path) {
+        if *v == version {
+            return Ok(index.clone());
+        }
+    }
+    // The lock is not held while indexing so that different archives can be indexed in parallel.
+    let index = Arc::new(build(path)?);
+    cache.lock().unwrap().insert(path.to_path_buf(), (version, index.clone()));
+    Ok(index)
+}
+
+static TAR_INDEXES: IndexCache<TarIndex> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn parse_octal(field: &[u8]) -> Result<u64> {
+    // Large values use a base-256 encoding, flagged by the high bit of the first byte.
+    if field.first().is_some_and(|v| v & 0x80 != 0) {
+        let v = field[1..].iter().fold(0u64, |acc, &v| (acc << 8) | v as u64);
+        return Ok(v);
+    }
+    let field = std::str::from_utf8(field)?;
+    let field = field.trim_matches(|c: char| c == '\0' || c == ' ');
+    if field.is_empty() {
+        return Ok(0);
+    }
+    Ok(u64::from_str_radix(field, 8)?)
+}
+
+fn parse_str(field: &[u8]) -> String {
+    let len = field.iter().position(|&v| v == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).to_string()
+}
+
+// Pax extended headers are made of records formatted as "<len> <key>=<value>\n".
+fn parse_pax_path(data: &[u8]) -> Option<String> {
+    let mut data = data;
+    let mut path = None;
+    while !data.is_empty() {
+        let space = data.iter().position(|&v| v == b' ')?;
+        let len: usize = std::str::from_utf8(&data[..space]).ok()?.parse().ok()?;
+        if len <= space || len > data.len() {
+            break;
+        }
+        let record = &data[space + 1..len];
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(String::from_utf8_lossy(value).to_string())
+        }
+        data = &data[len..];
+    }
+    path
+}
+
+fn index_tar(path: &Path) -> Result<TarIndex> {
+    let file = std::fs::File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut file = std::io::BufReader::new(file);
+    let mut index = TarIndex::new();
+    // The current position in the file, tracked so as to use relative seeks which preserve the
+    // BufReader buffer.
+    let mut pos = 0u64;
+    let mut offset = 0u64;
+    let mut long_name = None;
+    let mut header = [0u8; TAR_BLOCK_SIZE as usize];
+    while offset + TAR_BLOCK_SIZE <= file_len {
+        file.seek_relative(offset as i64 - pos as i64)?;
+        file.read_exact(&mut header)?;
+        pos = offset + TAR_BLOCK_SIZE;
+        // The archive ends with two zero blocks.
+        if header.iter().all(|&v| v == 0) {
+            break;
+        }
+        let size = parse_octal(&header[124..136]).context("invalid tar header size")?;
+        let typeflag = header[156];
+        match typeflag {
+            // GNU long names and pax extended headers apply to the next entry.
+            b'L' | b'x' => {
+                if size > MAX_TAR_EXTENDED_HEADER_LEN {
+                    anyhow::bail!("tar extended header at {offset} is too large ({size} bytes)")
+                }
+                let mut data = vec![0u8; size as usize];
+                file.read_exact(&mut data)?;
+                pos += size;
+                long_name =
+                    if typeflag == b'L' { Some(parse_str(&data)) } else { parse_pax_path(&data) };
+            }
+            b'K' | b'g' => {}
+            b'0' | b'\0' | b'7' => {
+                let name = match long_name.take() {
+                    Some(name) => name,
+                    None => {
+                        let name = parse_str(&header[0..100]);
+                        let prefix = if &header[257..262] == b"ustar" {
+                            parse_str(&header[345..500])
+                        } else {
+                            String::new()
+                        };
+                        if prefix.is_empty() {
+                            name
+                        } else {
+                            format!("{prefix}/{name}")
+                        }
+                    }
+                };
+                let name = name.strip_prefix("./").unwrap_or(&name).to_string();
+                index.insert(name, TarMember { offset: offset + TAR_BLOCK_SIZE, size });
+            }
+            _ => long_name = None,
+        }
+        offset += TAR_BLOCK_SIZE + size.div_ceil(TAR_BLOCK_SIZE) * TAR_BLOCK_SIZE;
+    }
+    Ok(index)
+}
+
+fn tar_index(path: &Path) -> Result<Arc<TarIndex>> {
+    cached_index(&TAR_INDEXES, path, index_tar)
+}
+
+/// The location of the data for a member of a zip file. The offset is the one of the local file
+/// header, the actual data starts after this header.
+#[derive(Debug, Clone, Copy)]
+struct ZipMember {
+    header_offset: u64,
+    compressed_size: u64,
+    size: u64,
+    method: u16,
+    encrypted: bool,
+}
+
+type ZipIndex = HashMap<String, ZipMember>;
+
+static ZIP_INDEXES: IndexCache<ZipIndex> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn u16_at(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([data[pos], data[pos + 1]])
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+fn u64_at(data: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap())
+}
+
+fn index_zip(path: &Path) -> Result<ZipIndex> {
+    let mut file = std::fs::File::open(path)?;
+    let file_len = file.metadata()?.len();
+
+    // The end of central directory record is at the end of the file, followed by a comment of at
+    // most 65535 bytes.
+    let tail_len = u64::min(file_len, 22 + 65535);
+    let mut tail = vec![0u8; tail_len as usize];
+    file.seek(SeekFrom::Start(file_len - tail_len))?;
+    file.read_exact(&mut tail)?;
+    let eocd = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&pos| u32_at(&tail, pos) == ZIP_EOCD_SIG)
+        .context("no end of central directory record, not a zip file")?;
+    let mut n_entries = u16_at(&tail, eocd + 10) as u64;
+    let mut cd_size = u32_at(&tail, eocd + 12) as u64;
+    let mut cd_offset = u32_at(&tail, eocd + 16) as u64;
+
+    // Large archives use a zip64 record, located via a locator placed just before the eocd.
+    if eocd >= 20 && u32_at(&tail, eocd - 20) == ZIP64_EOCD_LOCATOR_SIG {
+        let zip64_eocd_offset = u64_at(&tail, eocd - 20 + 8);
+        let mut record = [0u8; 56];
+        file.seek(SeekFrom::Start(zip64_eocd_offset))?;
+        file.read_exact(&mut record)?;
+        if u32_at(&record, 0) != ZIP64_EOCD_SIG {
+            anyhow::bail!("invalid zip64 end of central directory record")
+        }
+        n_entries = u64_at(&record, 32);
+        cd_size = u64_at(&record, 40);
+        cd_offset = u64_at(&record, 48);
+    }
+
+    let mut cd = vec![0u8; cd_size as usize];
+    file.seek(SeekFrom::Start(cd_offset))?;
+    file.read_exact(&mut cd)?;
+    let mut index = ZipIndex::new();
+    let mut pos = 0;
+    for _ in 0..n_entries {
+        if pos + 46 > cd.len() || u32_at(&cd, pos) != ZIP_CD_HEADER_SIG {
+            anyhow::bail!("invalid zip central directory entry")
+        }
+        let flags = u16_at(&cd, pos + 8);
+        let method = u16_at(&cd, pos + 10);
+        let mut compressed_size = u32_at(&cd, pos + 20) as u64;
+        let mut size = u32_at(&cd, pos + 24) as u64;
+        let name_len = u16_at(&cd, pos + 28) as usize;
+        let extra_len = u16_at(&cd, pos + 30) as usize;
+        let comment_len = u16_at(&cd, pos + 32) as usize;
+        let mut header_offset = u32_at(&cd, pos + 42) as u64;
+        let end = pos + 46 + name_len + extra_len + comment_len;
+        if end > cd.len() {
+            anyhow::bail!("invalid zip central directory entry")
+        }
+        let name = String::from_utf8_lossy(&cd[pos + 46..pos + 46 + name_len]).to_string();
+
+        // The zip64 extra field contains the values that did not fit in 32 bits, in this order.
+        let mut extra = &cd[pos + 46 + name_len..pos + 46 + name_len + extra_len];
+        while extra.len() >= 4 {
+            let id = u16_at(extra, 0);
+            let len = usize::min(u16_at(extra, 2) as usize, extra.len() - 4);
+            if id == 0x0001 {
+                let mut data = &extra[4..4 + len];
+                for v in [&mut size, &mut compressed_size, &mut header_offset] {
+                    if *v == u32::MAX as u64 && data.len() >= 8 {
+                        *v = u64_at(data, 0);
+                        data = &data[8..];
+                    }
+                }
+            }
+            extra = &extra[4 + len..];
+        }
+
+        if !name.ends_with('/') {
+            let encrypted = flags & 1 != 0;
+            let member = ZipMember { header_offset, compressed_size, size, method, encrypted };
+            index.insert(name, member);
+        }
+        pos = end;
+    }
+    Ok(index)
+}
+
+fn zip_index(path: &Path) -> Result<Arc<ZipIndex>> {
+    cached_index(&ZIP_INDEXES, path, index_zip)
+}
+
+fn open_zip_member(archive: &Path, name: &str) -> Result<Box<dyn MediaSource>> {
+    let index = zip_index(archive)?;
+    let member = index.get(name).with_context(|| format!("no member {name} in {archive:?}"))?;
+    if member.encrypted {
+        anyhow::bail!("member {name} in {archive:?} is encrypted")
+    }
+    let mut file = std::fs::File::open(archive)?;
+    let mut header = [0u8; 30];
+    file.seek(SeekFrom::Start(member.header_offset))?;
+    file.read_exact(&mut header)?;
+    if u32_at(&header, 0) != ZIP_LOCAL_HEADER_SIG {
+        anyhow::bail!("invalid local header for member {name} in {archive:?}")
+    }
+    // The extra field in the local header can differ from the one in the central directory.
+    let data_offset =
+        member.header_offset + 30 + u16_at(&header, 26) as u64 + u16_at(&header, 28) as u64;
+    let data = SubFile::new(file, data_offset, member.compressed_size)?;
+    match member.method {
+        // Stored
+        0 => Ok(Box::new(data)),
+        // Deflate
+        8 => {
+            let mut decoder = flate2::read::DeflateDecoder::new(data);
+            let mut out = Vec::with_capacity(member.size as usize);
+            decoder.read_to_end(&mut out)?;
+            Ok(Box::new(std::io::Cursor::new(out)))
+        }
+        m => anyhow::bail!("unsupported compression method {m} for member {name} in {archive:?}"),
+    }
+}
+
+/// A member of an archive, referred to by a path such as `shard.tar#member.flac` or
+/// `archive.zip!/dir/member.wav`.
+#[derive(Debug, Clone, Copy)]
+pub enum Member<'a> {
+    Tar { shard: &'a Path, name: &'a str },
+    Zip { archive: &'a Path, name: &'a str },
+}
+
+impl<'a> Member<'a> {
+    /// Returns `None` for paths that do not refer to an archive member. Paths that exist as is
+    /// are never treated as members, and the part before the separator has to be an existing
+    /// regular file, so that plain files such as `a.tar#b.wav` can still be read.
+    pub fn parse(path: &'a Path) -> Option<Self> {
+        let path_str = path.to_str()?;
+        let zip = path_str.match_indices(".zip!/").map(|(pos, _)| {
+            let (archive, name) = path_str.split_at(pos + 4);
+            Self::Zip { archive: Path::new(archive), name: &name[2..] }
+        });
+        let tar = path_str.match_indices(".tar#").map(|(pos, _)| {
+            let (shard, name) = path_str.split_at(pos + 4);
+            Self::Tar { shard: Path::new(shard), name: &name[1..] }
+        });
+        let mut members = zip.chain(tar).peekable();
+        if members.peek().is_none() || path.exists() {
+            return None;
+        }
+        members.find(|member| member.archive().is_file())
+    }
+
+    pub fn name(&self) -> &'a str {
+        match self {
+            Self::Tar { name, .. } | Self::Zip { name, .. } => name,
+        }
+    }
+
+    /// The path of the archive containing the member.
+    pub fn archive(&self) -> &'a Path {
+        match self {
+            Self::Tar { shard: archive, .. } | Self::Zip { archive, .. } => archive,
+        }
+    }
+
+    pub fn open(&self) -> Result<Box<dyn MediaSource>> {
+        match *self {
+            Self::Tar { shard, name } => {
+                let index = tar_index(shard)?;
+                let member =
+                    index.get(name).with_context(|| format!("no member {name} in {shard:?}"))?;
+                let file = std::fs::File::open(shard)?;
+                Ok(Box::new(SubFile::new(file, member.offset, member.size)?))
+            }
+            Self::Zip { archive, name } => open_zip_member(archive, name),
+        }
+    }
+
+    /// The size in bytes of the member, after decompression.
+    pub fn len(&self) -> Result<u64> {
+        match *self {
+            Self::Tar { shard, name } => {
+                let index = tar_index(shard)?;
+                let member =
+                    index.get(name).with_context(|| format!("no member {name} in {shard:?}"))?;
+                Ok(member.size)
+            }
+            Self::Zip { archive, name } => {
+                let index = zip_index(archive)?;
+                let member =
+                    index.get(name).with_context(|| format!("no member {name} in {archive:?}"))?;
+                Ok(member.size)
+            }
+        }
+    }
+}
+
+/// Opens a file or an archive member.
+pub fn open<P: AsRef<Path>>(path: P) -> Result<Box<dyn MediaSource>> {
+    let path = path.as_ref();
+    match Member::parse(path) {
+        None => Ok(Box::new(std::fs::File::open(path)?)),
+        Some(member) => member.open(),
+    }
+}
+
+/// Returns the size in bytes of a file or of an archive member.
+pub fn file_len<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let path = path.as_ref();
+    match Member::parse(path) {
+        None => Ok(std::fs::metadata(path)?.len()),
+        Some(member) => member.len(),
+    }
+}
+
+/// Returns the modification time of a file, archive members use the one of their archive.
+pub fn modified<P: AsRef<Path>>(path: P) -> Result<std::time::SystemTime> {
+    let path = path.as_ref();
+    let path = Member::parse(path).map_or(path, |member| member.archive());
+    Ok(std::fs::metadata(path)?.modified()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn plain_files_are_not_members() -> Result<()> {
+        let dir = TempDir::new("archive-parse")?;
+        let plain = dir.path().join("a.tar#b.wav");
+        std::fs::write(&plain, b"plain")?;
+        assert!(Member::parse(&plain).is_none());
+        let mut data = vec![];
+        open(&plain)?.read_to_end(&mut data)?;
+        assert_eq!(data, b"plain");
+        assert_eq!(file_len(&plain)?, 5);
+
+        // The part before the separator has to be an existing file.
+        assert!(Member::parse(&dir.path().join("missing.tar#b.wav")).is_none());
+        std::fs::write(dir.path().join("shard.tar"), b"")?;
+        let member = dir.path().join("shard.tar#dir/a.tar#b.wav");
+        match Member::parse(&member) {
+            Some(Member::Tar { shard, name }) => {
+                assert_eq!(
+                    (shard, name),
+                    (dir.path().join("shard.tar").as_path(), "dir/a.tar#b.wav")
+                )
+            }
+            m => panic!("unexpected member {m:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn large_extended_headers_are_rejected() -> Result<()> {
+        let dir = TempDir::new("archive-long-name")?;
+        let shard = dir.path().join("shard.tar");
+        let mut header = [0u8; TAR_BLOCK_SIZE as usize];
+        header[..9].copy_from_slice(b"long_name");
+        // A 2 MiB long name, the size is encoded in octal.
+        header[124..136].copy_from_slice(b"00010000000\0");
+        header[156] = b'L';
+        std::fs::write(&shard, header)?;
+        let err = index_tar(&shard).unwrap_err();
+        assert!(err.to_string().contains("too large"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn members_use_the_archive_modification_time() -> Result<()> {
+        let dir = TempDir::new("archive-modified")?;
+        let archive = dir.path().join("shard.tar");
+        std::fs::write(&archive, b"")?;
+        let member = dir.path().join("shard.tar#member.wav");
+        assert_eq!(modified(&member)?, std::fs::metadata(&archive)?.modified()?);
+        Ok(())
+    }
+}
@@ -1,12 +1,16 @@
 use anyhow::{Context, Result};
 
-use symphonia::core::audio::Signal;
+use numpy::ndarray::{Array2, ArrayView2};
+
+use symphonia::core::audio::{AudioBufferRef, Signal};
 use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::conv::FromSample;
 use symphonia::core::errors::Error;
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::MediaSourceStream;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
+use symphonia::core::sample::{i24, u24};
 use symphonia::core::units::{Time, TimeBase};
 
 pub struct FileReader {
@@ -18,45 +22,317 @@ pub struct FileReader {
     time_base: TimeBase,
     sample_rate: u32,
     channels: usize,
+    codec: symphonia::core::codecs::CodecType,
+    bits_per_sample: Option<u32>,
+    container: &'static str,
+    metadata: Metadata,
+    tracks: Vec<TrackInfo>,
+    // The index of the next sample that the decoder would produce, this is used to avoid seeking
+    // when consecutive segments are decoded. None when the position is unknown.
+    position: Option<u64>,
+    // The last decoded packet and the number of frames already consumed from it, when the
+    // previous decode call stopped in the middle of a packet.
+    carry: Option<(AudioBufferRef<'static>, usize)>,
+    seek_mode: SeekMode,
+    // The index of the first sample returned by the last decode call.
+    last_start_sample: u64,
+    num_seeks: usize,
 }
 
-fn conv<T>(
-    pcm_data: &mut [Vec<f32>],
-    data: std::borrow::Cow<symphonia::core::audio::AudioBuffer<T>>,
-) where
-    T: symphonia::core::sample::Sample,
-    f32: symphonia::core::conv::FromSample<T>,
+/// A description of one of the tracks available in a file.
+#[derive(Debug, Clone)]
+pub struct TrackInfo {
+    pub codec: &'static str,
+    pub channels: Option<usize>,
+    pub sample_rate: Option<u32>,
+    pub language: Option<String>,
+}
+
+/// The track to decode in files that contain multiple tracks.
+#[derive(Debug, Clone)]
+pub enum TrackSelector {
+    /// The index of the track in the list returned by [`FileReader::tracks`].
+    Index(usize),
+    /// The first decodeable track with this language, e.g. "eng".
+    Language(String),
+}
+
+fn codec_name(codec: symphonia::core::codecs::CodecType) -> &'static str {
+    symphonia::default::get_codecs().get_codec(codec).map_or("unknown", |d| d.short_name)
+}
+
+/// Tags extracted from the file, e.g. ID3 tags, vorbis comments, RIFF INFO chunks, or MP4 atoms.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub language: Option<String>,
+    pub comments: Vec<String>,
+    /// Whether the file contains an embedded picture, e.g. a cover.
+    pub has_cover: bool,
+}
+
+impl Metadata {
+    // The first value found for each tag is kept, so tags coming from the container take
+    // precedence over the ones read before (e.g. an ID3v2 tag prepended to the stream).
+    fn update(&mut self, rev: &symphonia::core::meta::MetadataRevision) {
+        use symphonia::core::meta::StandardTagKey as K;
+
+        for tag in rev.tags().iter() {
+            let value = tag.value.to_string();
+            let field = match tag.std_key {
+                Some(K::TrackTitle) => &mut self.title,
+                Some(K::Artist) => &mut self.artist,
+                Some(K::AlbumArtist) if self.artist.is_none() => &mut self.artist,
+                Some(K::Album) => &mut self.album,
+                Some(K::Language) => &mut self.language,
+                Some(K::Comment) => {
+                    if !self.comments.contains(&value) {
+                        self.comments.push(value)
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            if field.is_none() {
+                *field = Some(value)
+            }
+        }
+        self.has_cover |= !rev.visuals().is_empty();
+    }
+}
+
+/// The sample types that can be produced when decoding.
+pub trait OutputSample:
+    Copy
+    + Default
+    + FromSample<u8>
+    + FromSample<u16>
+    + FromSample<u24>
+    + FromSample<u32>
+    + FromSample<i8>
+    + FromSample<i16>
+    + FromSample<i24>
+    + FromSample<i32>
+    + FromSample<f32>
+    + FromSample<f64>
 {
-    use symphonia::core::conv::FromSample;
-    for (channel_index, pcm_data) in pcm_data.iter_mut().enumerate() {
-        pcm_data.extend(data.chan(channel_index).iter().map(|v| f32::from_sample(*v)))
+}
+
+impl OutputSample for i16 {}
+impl OutputSample for i32 {}
+impl OutputSample for f32 {}
+impl OutputSample for f64 {}
+
+/// The sample type used for the decoded pcm data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dtype {
+    Int16,
+    Int32,
+    #[default]
+    Float32,
+    Float64,
+}
+
+impl std::str::FromStr for Dtype {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "int16" => Ok(Self::Int16),
+            "int32" => Ok(Self::Int32),
+            "float32" => Ok(Self::Float32),
+            "float64" => Ok(Self::Float64),
+            _ => {
+                anyhow::bail!("unsupported dtype '{s}', expected int16, int32, float32 or float64")
+            }
+        }
+    }
+}
+
+/// Decoded pcm data with shape `(channels, samples)`, the variant depends on the requested dtype.
+pub enum Pcm {
+    Int16(Array2<i16>),
+    Int32(Array2<i32>),
+    Float32(Array2<f32>),
+    Float64(Array2<f64>),
+}
+
+impl Pcm {
+    pub fn from_f32(data: Array2<f32>, dtype: Dtype) -> Self {
+        match dtype {
+            Dtype::Int16 => Self::Int16(data.mapv(i16::from_sample)),
+            Dtype::Int32 => Self::Int32(data.mapv(i32::from_sample)),
+            Dtype::Float32 => Self::Float32(data),
+            Dtype::Float64 => Self::Float64(data.mapv(f64::from_sample)),
+        }
     }
 }
 
-fn conv_s<T>(
-    pcm_data: &mut [Vec<f32>],
-    data: std::borrow::Cow<symphonia::core::audio::AudioBuffer<T>>,
+/// The memory backing a `PlanarBuf`, either an owned vector that can be reallocated when more
+/// space is needed or a fixed size slice provided by the caller.
+trait Storage<T>: AsMut<[T]> + Sized {
+    fn alloc(&self, len: usize) -> Result<Self>;
+}
+
+impl<T: Copy + Default> Storage<T> for Vec<T> {
+    fn alloc(&self, len: usize) -> Result<Self> {
+        Ok(vec![T::default(); len])
+    }
+}
+
+impl<T> Storage<T> for &mut [T] {
+    fn alloc(&self, len: usize) -> Result<Self> {
+        anyhow::bail!("output buffer is too small, {} < {len}", self.len())
+    }
+}
+
+/// Planar pcm data stored in a single buffer, channel `c` uses `data[c * stride..][..len]`. The
+/// decoded samples are written directly at their final location so that the buffer can be handed
+/// over to numpy without any copy.
+struct PlanarBuf<T, S = Vec<T>> {
+    data: S,
+    channels: usize,
+    stride: usize,
+    len: usize,
+    _phantom: std::marker::PhantomData<T>,
+}
+
+impl<T: Copy + Default> PlanarBuf<T> {
+    fn new(channels: usize, capacity: usize) -> Self {
+        let data = vec![T::default(); channels * capacity];
+        Self::from_storage(data, channels, capacity)
+    }
+
+    fn into_array(self) -> Array2<T> {
+        let Self { mut data, channels, stride, len, _phantom } = self;
+        if stride != len {
+            for c in 1..channels {
+                data.copy_within(c * stride..c * stride + len, c * len)
+            }
+            data.truncate(channels * len);
+            data.shrink_to_fit();
+        }
+        // The buffer contains exactly channels * len elements at this point.
+        Array2::from_shape_vec((channels, len), data).expect("consistent planar buffer")
+    }
+}
+
+impl<T: Copy + Default, S: Storage<T>> PlanarBuf<T, S> {
+    fn from_storage(data: S, channels: usize, stride: usize) -> Self {
+        Self { data, channels, stride, len: 0, _phantom: std::marker::PhantomData }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Ensures that `n` more samples can be written for each channel.
+    fn reserve(&mut self, n: usize) -> Result<()> {
+        if self.len + n <= self.stride {
+            return Ok(());
+        }
+        let stride = usize::max(self.len + n, 2 * self.stride);
+        let mut data = self.data.alloc(self.channels * stride)?;
+        for c in 0..self.channels {
+            data.as_mut()[c * stride..][..self.len]
+                .copy_from_slice(&self.data.as_mut()[c * self.stride..][..self.len]);
+        }
+        self.data = data;
+        self.stride = stride;
+        Ok(())
+    }
+
+    /// The location of the next `n` samples for channel `c`, `advance` has to be called once all
+    /// the channels have been written.
+    fn next_mut(&mut self, c: usize, n: usize) -> &mut [T] {
+        &mut self.data.as_mut()[c * self.stride + self.len..][..n]
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.len += n
+    }
+
+    /// Pads all the channels with zeros up to `len`.
+    fn pad(&mut self, len: usize) -> Result<()> {
+        if len > self.len {
+            let n = len - self.len;
+            self.reserve(n)?;
+            for c in 0..self.channels {
+                self.next_mut(c, n).fill(T::default())
+            }
+            self.advance(n)
+        }
+        Ok(())
+    }
+}
+
+fn conv_s<T, O, S>(
+    pcm_data: &mut PlanarBuf<O, S>,
+    data: &symphonia::core::audio::AudioBuffer<T>,
     to_skip: usize,
     samples_to_read: usize,
-) -> usize
+) -> Result<usize>
 where
     T: symphonia::core::sample::Sample,
-    f32: symphonia::core::conv::FromSample<T>,
+    O: FromSample<T> + Copy + Default,
+    S: Storage<O>,
 {
-    use symphonia::core::conv::FromSample;
-    let mut remaining_to_skip = 0;
-    for (channel_index, pcm_data) in pcm_data.iter_mut().enumerate() {
-        let data = data.chan(channel_index);
-        if to_skip < data.len() {
-            let data = &data[to_skip..];
-            let missing_samples = samples_to_read.saturating_sub(pcm_data.len());
-            let data = &data[..usize::min(data.len(), missing_samples)];
-            pcm_data.extend(data.iter().map(|v| f32::from_sample(*v)));
-        } else {
-            remaining_to_skip = to_skip - data.len()
+    let frames = data.frames();
+    if to_skip >= frames {
+        return Ok(to_skip - frames);
+    }
+    let missing_samples = samples_to_read.saturating_sub(pcm_data.len());
+    let n = usize::min(frames - to_skip, missing_samples);
+    pcm_data.reserve(n)?;
+    for channel_index in 0..pcm_data.channels {
+        let src = &data.chan(channel_index)[to_skip..to_skip + n];
+        let dst = pcm_data.next_mut(channel_index, n);
+        for (dst, src) in dst.iter_mut().zip(src) {
+            *dst = O::from_sample(*src)
         }
     }
-    remaining_to_skip
+    pcm_data.advance(n);
+    Ok(0)
+}
+
+/// Converts the frames of `data` after `to_skip` and appends them to `pcm_data`, returns the number
+/// of frames that remain to be skipped.
+fn conv_ref<O: OutputSample, S: Storage<O>>(
+    pcm_data: &mut PlanarBuf<O, S>,
+    data: &AudioBufferRef,
+    to_skip: usize,
+    samples_to_read: usize,
+) -> Result<usize> {
+    match data {
+        AudioBufferRef::F32(data) => conv_s(pcm_data, data, to_skip, samples_to_read),
+        AudioBufferRef::U8(data) => conv_s(pcm_data, data, to_skip, samples_to_read),
+        AudioBufferRef::U16(data) => conv_s(pcm_data, data, to_skip, samples_to_read),
+        AudioBufferRef::U24(data) => conv_s(pcm_data, data, to_skip, samples_to_read),
+        AudioBufferRef::U32(data) => conv_s(pcm_data, data, to_skip, samples_to_read),
+        AudioBufferRef::S8(data) => conv_s(pcm_data, data, to_skip, samples_to_read),
+        AudioBufferRef::S16(data) => conv_s(pcm_data, data, to_skip, samples_to_read),
+        AudioBufferRef::S24(data) => conv_s(pcm_data, data, to_skip, samples_to_read),
+        AudioBufferRef::S32(data) => conv_s(pcm_data, data, to_skip, samples_to_read),
+        AudioBufferRef::F64(data) => conv_s(pcm_data, data, to_skip, samples_to_read),
+    }
+}
+
+fn into_owned(data: AudioBufferRef) -> AudioBufferRef<'static> {
+    use std::borrow::Cow;
+    match data {
+        AudioBufferRef::F32(data) => AudioBufferRef::F32(Cow::Owned(data.into_owned())),
+        AudioBufferRef::U8(data) => AudioBufferRef::U8(Cow::Owned(data.into_owned())),
+        AudioBufferRef::U16(data) => AudioBufferRef::U16(Cow::Owned(data.into_owned())),
+        AudioBufferRef::U24(data) => AudioBufferRef::U24(Cow::Owned(data.into_owned())),
+        AudioBufferRef::U32(data) => AudioBufferRef::U32(Cow::Owned(data.into_owned())),
+        AudioBufferRef::S8(data) => AudioBufferRef::S8(Cow::Owned(data.into_owned())),
+        AudioBufferRef::S16(data) => AudioBufferRef::S16(Cow::Owned(data.into_owned())),
+        AudioBufferRef::S24(data) => AudioBufferRef::S24(Cow::Owned(data.into_owned())),
+        AudioBufferRef::S32(data) => AudioBufferRef::S32(Cow::Owned(data.into_owned())),
+        AudioBufferRef::F64(data) => AudioBufferRef::F64(Cow::Owned(data.into_owned())),
+    }
 }
 
 pub trait IntoTime {
@@ -76,43 +352,246 @@ impl IntoTime for f64 {
     }
 }
 
+// Guess the container format from the first few bytes of the stream, skipping a potential
+// ID3v2 tag. The reader position is restored to the start of the stream afterwards.
+fn sniff_container<R: std::io::Read + std::io::Seek>(r: &mut R) -> std::io::Result<&'static str> {
+    use std::io::SeekFrom;
+
+    let mut header = [0u8; 12];
+    let read_header = |r: &mut R, header: &mut [u8; 12]| -> std::io::Result<()> {
+        *header = [0u8; 12];
+        let mut len = 0;
+        while len < header.len() {
+            match r.read(&mut header[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        Ok(())
+    };
+    read_header(r, &mut header)?;
+    if &header[..3] == b"ID3" {
+        // The tag size is stored as a 28 bits "syncsafe" integer, a footer may follow.
+        let size = header[6..10].iter().fold(0u64, |acc, &v| (acc << 7) | (v & 0x7f) as u64);
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        r.seek(SeekFrom::Start(10 + size + footer))?;
+        read_header(r, &mut header)?;
+    }
+    r.seek(SeekFrom::Start(0))?;
+    let container = match header {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E'] => "wav",
+        [b'R', b'F', b'6', b'4', _, _, _, _, b'W', b'A', b'V', b'E'] => "rf64",
+        [b'B', b'W', b'6', b'4', _, _, _, _, b'W', b'A', b'V', b'E'] => "rf64",
+        [b'F', b'O', b'R', b'M', _, _, _, _, b'A', b'I', b'F', _] => "aiff",
+        [b'f', b'L', b'a', b'C', ..] => "flac",
+        [b'O', b'g', b'g', b'S', ..] => "ogg",
+        [b'c', b'a', b'f', b'f', ..] => "caf",
+        [0x1a, 0x45, 0xdf, 0xa3, ..] => "mkv",
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => "mp4",
+        // ADTS and MPEG audio frames share the same sync word, ADTS uses layer 0.
+        [0xff, b, ..] if b & 0xf6 == 0xf0 => "adts",
+        [0xff, b, ..] if b & 0xe0 == 0xe0 => "mp3",
+        _ => "unknown",
+    };
+    Ok(container)
+}
+
+/// Options used when opening a file.
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    /// When set, the duration is always computed by scanning the packets of the audio track
+    /// rather than using the number of frames declared in the container headers. This does not
+    /// require decoding the audio but reads through the whole file.
+    pub exact_duration: bool,
+    /// The track to decode, by default the first track with a known codec is used.
+    pub track: Option<TrackSelector>,
+    /// When set, the file is read as headerless pcm data using this format.
+    pub raw: Option<crate::pcm::PcmFormat>,
+    /// How to seek to the start of the decoded segments, see `SeekMode`.
+    pub seek_mode: SeekMode,
+}
+
+/// How decoding positions itself at the requested start time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SeekMode {
+    /// Start decoding at the position reached by the container seek, which may be slightly off
+    /// the requested time but avoids decoding or scanning from an earlier position.
+    Coarse,
+    /// Start decoding exactly at the requested time.
+    #[default]
+    Accurate,
+}
+
+impl std::str::FromStr for SeekMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "coarse" => Ok(Self::Coarse),
+            "accurate" => Ok(Self::Accurate),
+            _ => anyhow::bail!("unsupported seek_mode '{s}', expected coarse or accurate"),
+        }
+    }
+}
+
+// The number of channels of a track, some containers only set the channel layout.
+fn track_channels(codec_params: &symphonia::core::codecs::CodecParameters) -> Option<usize> {
+    use symphonia::core::audio::Layout;
+    match (codec_params.channels, codec_params.channel_layout) {
+        (Some(c), _) => Some(c.count()),
+        (None, Some(Layout::Mono)) => Some(1),
+        (None, Some(Layout::Stereo)) => Some(2),
+        (None, _) => None,
+    }
+}
+
+// Scan the packets for the given track, without decoding them, and return the timestamp of the
+// end of the last packet.
+fn scan_end_ts(
+    format: &mut dyn symphonia::core::formats::FormatReader,
+    track_id: u32,
+) -> Result<u64> {
+    let mut end_ts = 0;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(ioerr)) if ioerr.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(err) => Err(err)?,
+        };
+        while !format.metadata().is_latest() {
+            format.metadata().pop();
+        }
+        if packet.track_id() != track_id {
+            continue;
+        }
+        end_ts = u64::max(end_ts, packet.ts() + packet.dur());
+    }
+    Ok(end_ts)
+}
+
 impl FileReader {
     pub fn new<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Self::new_with_options(path, &OpenOptions::default())
+    }
+
+    /// Opens an audio file, the path can also refer to a member of an uncompressed tar file using
+    /// the `shard.tar#member.flac` syntax, or to a member of a zip file using the
+    /// `archive.zip!/dir/member.wav` syntax.
+    pub fn new_with_options<P: AsRef<std::path::Path>>(
+        path: P,
+        opts: &OpenOptions,
+    ) -> Result<Self> {
         let path = path.as_ref();
-        let src = std::fs::File::open(path)?;
-        let mss = MediaSourceStream::new(Box::new(src), Default::default());
-        let mut hint = Hint::new();
-        if let Some(extension) = path.extension().and_then(|v| v.to_str()) {
-            hint.with_extension(extension);
-        }
+        let src = crate::archive::open(path)?;
+        let extension = match crate::archive::Member::parse(path) {
+            Some(member) => std::path::Path::new(member.name()).extension(),
+            None => path.extension(),
+        };
+        Self::from_source(src, extension.and_then(|v| v.to_str()), opts)
+    }
 
-        // Use the default options for metadata and format readers.
-        let meta_opts: MetadataOptions = Default::default();
-        let fmt_opts: FormatOptions = Default::default();
+    /// Reads audio from an in-memory buffer, `extension` is an optional hint on the format of the
+    /// data, e.g. "mp3".
+    pub fn from_bytes(data: Vec<u8>, extension: Option<&str>, opts: &OpenOptions) -> Result<Self> {
+        Self::from_source(Box::new(std::io::Cursor::new(data)), extension, opts)
+    }
+
+    /// Reads audio from any seekable source.
+    pub fn from_source(
+        mut src: Box<dyn MediaSource>,
+        extension: Option<&str>,
+        opts: &OpenOptions,
+    ) -> Result<Self> {
+        let container = match opts.raw {
+            None => sniff_container(&mut src)?,
+            Some(_) => "raw",
+        };
+        let mss = MediaSourceStream::new(src, Default::default());
+        let (mut format, metadata) = if let Some(raw) = opts.raw.as_ref() {
+            let format = crate::pcm::PcmReader::new(mss, raw, 0, None)?;
+            (Box::new(format) as Box<dyn FormatReader>, Metadata::default())
+        } else if container == "rf64" {
+            // RF64 files are not supported by symphonia so a custom reader is used.
+            let format = crate::pcm::PcmReader::try_new(mss, &Default::default())?;
+            (Box::new(format) as Box<dyn FormatReader>, Metadata::default())
+        } else {
+            let mut hint = Hint::new();
+            if let Some(extension) = extension {
+                hint.with_extension(extension);
+            }
 
-        // Probe the media source.
-        let probed = symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)?;
+            // Use the default options for metadata and format readers.
+            let meta_opts: MetadataOptions = Default::default();
+            let fmt_opts: FormatOptions = Default::default();
 
-        // Get the instantiated format reader.
-        let format = probed.format;
+            // Probe the media source.
+            let mut probed =
+                symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)?;
 
-        // Find the first audio track with a known (decodeable) codec.
-        let track = format
+            // Collect the metadata that appear before the container and in the container itself.
+            let mut metadata = Metadata::default();
+            if let Some(rev) = probed.format.metadata().current() {
+                metadata.update(rev)
+            }
+            if let Some(rev) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+                metadata.update(rev)
+            }
+            (probed.format, metadata)
+        };
+
+        let tracks = format
             .tracks()
             .iter()
-            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-            .context("no useable codec")?;
-        let time_base = track.codec_params.time_base.context("no time-base")?;
-        let sample_rate = track.codec_params.sample_rate.context("no sample-rate")?;
-        let n_frames = track.codec_params.n_frames.context("no n-frames")?;
-        let start_ts = track.codec_params.start_ts;
+            .map(|t| TrackInfo {
+                codec: codec_name(t.codec_params.codec),
+                channels: track_channels(&t.codec_params),
+                sample_rate: t.codec_params.sample_rate,
+                language: t.language.clone(),
+            })
+            .collect::<Vec<_>>();
+        let track = match opts.track.as_ref() {
+            // Find the first audio track with a known (decodeable) codec.
+            None => format
+                .tracks()
+                .iter()
+                .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+                .context("no useable codec")?,
+            Some(TrackSelector::Index(index)) => {
+                let track = format.tracks().get(*index).with_context(|| {
+                    format!("no track with index {index}, {} tracks available", tracks.len())
+                })?;
+                if track.codec_params.codec == CODEC_TYPE_NULL {
+                    anyhow::bail!("no useable codec for track {index}")
+                }
+                track
+            }
+            Some(TrackSelector::Language(language)) => format
+                .tracks()
+                .iter()
+                .find(|t| {
+                    t.codec_params.codec != CODEC_TYPE_NULL
+                        && t.language.as_ref().is_some_and(|l| l.eq_ignore_ascii_case(language))
+                })
+                .with_context(|| format!("no useable track with language {language}"))?,
+        };
+        let track_id = track.id;
+        let codec_params = track.codec_params.clone();
+        let time_base = codec_params.time_base.context("no time-base")?;
+        let sample_rate = codec_params.sample_rate.context("no sample-rate")?;
+        let start_ts = codec_params.start_ts;
+        let n_frames = match codec_params.n_frames {
+            Some(n_frames) if !opts.exact_duration => n_frames,
+            // Some containers do not declare the number of frames, e.g. mp3 files without a Xing
+            // header or raw aac streams, in this case the packets timestamps are used.
+            _ => scan_end_ts(format.as_mut(), track_id)?.saturating_sub(start_ts),
+        };
         let duration = time_base.calc_time(n_frames);
-        let channels = match track.codec_params.channels {
-            Some(c) => c.count(),
-            None => match track.codec_params.channel_layout {
+        let channels = match track_channels(&codec_params) {
+            Some(c) => c,
+            None => match codec_params.channel_layout {
                 None => anyhow::bail!("no channel"),
-                Some(symphonia::core::audio::Layout::Mono) => 1,
-                Some(symphonia::core::audio::Layout::Stereo) => 2,
                 Some(l) => anyhow::bail!("unsupported layout {l:?}"),
             },
         };
@@ -121,11 +600,30 @@ impl FileReader {
         let dec_opts: DecoderOptions = Default::default();
 
         // Create a decoder for the track.
-        let decoder = symphonia::default::get_codecs().make(&track.codec_params, &dec_opts)?;
+        let decoder = symphonia::default::get_codecs().make(&codec_params, &dec_opts)?;
 
-        // Store the track identifier, it will be used to filter packets.
-        let track_id = track.id;
-        Ok(Self { track_id, decoder, format, time_base, start_ts, duration, sample_rate, channels })
+        let codec = codec_params.codec;
+        let bits_per_sample = codec_params.bits_per_sample;
+        Ok(Self {
+            track_id,
+            decoder,
+            format,
+            time_base,
+            start_ts,
+            duration,
+            sample_rate,
+            channels,
+            codec,
+            bits_per_sample,
+            container,
+            metadata,
+            tracks,
+            position: None,
+            carry: None,
+            seek_mode: opts.seek_mode,
+            last_start_sample: 0,
+            num_seeks: 0,
+        })
     }
 
     pub fn duration_sec(&self) -> f64 {
@@ -137,22 +635,199 @@ impl FileReader {
         start_time: I1,
         duration: I2,
         pad_with_zeros: bool,
-    ) -> Result<(Vec<Vec<f32>>, usize)> {
-        let start_time = start_time.into_time();
-        let duration = duration.into_time();
-        let start_ts = self.time_base.calc_timestamp(start_time);
-        let samples_to_read = self.time_base.calc_timestamp(duration) as usize;
-        let mut pcm_data = vec![Vec::with_capacity(samples_to_read); self.channels];
-        // Somehow using Time rather than TimeStamp in the seek below doesn't seem to have much
-        // effects.
-        let seeked_to = self.format.seek(
-            symphonia::core::formats::SeekMode::Accurate,
-            symphonia::core::formats::SeekTo::TimeStamp { ts: start_ts, track_id: self.track_id },
-        )?;
-        self.decoder.reset();
-        let mut to_skip = start_ts.saturating_sub(seeked_to.actual_ts) as usize;
+    ) -> Result<(Array2<f32>, usize)> {
+        self.decode_as(start_time, duration, pad_with_zeros)
+    }
+
+    /// Similar to `decode` but returns the data using the given dtype.
+    pub fn decode_dtype<I1: IntoTime, I2: IntoTime>(
+        &mut self,
+        start_time: I1,
+        duration: I2,
+        pad_with_zeros: bool,
+        dtype: Dtype,
+    ) -> Result<(Pcm, usize)> {
+        let start_sample = self.time_to_samples(start_time.into_time());
+        let samples_to_read = self.time_to_samples(duration.into_time()) as usize;
+        self.decode_range_dtype(start_sample, samples_to_read, pad_with_zeros, dtype)
+    }
+
+    /// Decodes `num_samples` samples per channel starting from sample `start_sample`. Unlike
+    /// `decode`, no rounding is involved so the returned data has exactly `num_samples` samples
+    /// per channel, unless the end of the stream is reached first and `pad_with_zeros` is not set.
+    pub fn decode_samples(
+        &mut self,
+        start_sample: u64,
+        num_samples: usize,
+        pad_with_zeros: bool,
+    ) -> Result<(Array2<f32>, usize)> {
+        self.decode_range(start_sample, num_samples, pad_with_zeros)
+    }
+
+    /// Similar to `decode_samples` but returns the data using the given dtype.
+    pub fn decode_samples_dtype(
+        &mut self,
+        start_sample: u64,
+        num_samples: usize,
+        pad_with_zeros: bool,
+        dtype: Dtype,
+    ) -> Result<(Pcm, usize)> {
+        self.decode_range_dtype(start_sample, num_samples, pad_with_zeros, dtype)
+    }
+
+    fn decode_range_dtype(
+        &mut self,
+        start_sample: u64,
+        samples_to_read: usize,
+        pad_with_zeros: bool,
+        dtype: Dtype,
+    ) -> Result<(Pcm, usize)> {
+        let (pcm, unpadded_len) = match dtype {
+            Dtype::Int16 => {
+                let (d, l) = self.decode_range(start_sample, samples_to_read, pad_with_zeros)?;
+                (Pcm::Int16(d), l)
+            }
+            Dtype::Int32 => {
+                let (d, l) = self.decode_range(start_sample, samples_to_read, pad_with_zeros)?;
+                (Pcm::Int32(d), l)
+            }
+            Dtype::Float32 => {
+                let (d, l) = self.decode_range(start_sample, samples_to_read, pad_with_zeros)?;
+                (Pcm::Float32(d), l)
+            }
+            Dtype::Float64 => {
+                let (d, l) = self.decode_range(start_sample, samples_to_read, pad_with_zeros)?;
+                (Pcm::Float64(d), l)
+            }
+        };
+        Ok((pcm, unpadded_len))
+    }
+
+    /// Decodes the data, the samples are converted to `T` without going through f32 so integer
+    /// samples are kept exact when possible.
+    pub fn decode_as<T: OutputSample, I1: IntoTime, I2: IntoTime>(
+        &mut self,
+        start_time: I1,
+        duration: I2,
+        pad_with_zeros: bool,
+    ) -> Result<(Array2<T>, usize)> {
+        let start_sample = self.time_to_samples(start_time.into_time());
+        let samples_to_read = self.time_to_samples(duration.into_time()) as usize;
+        self.decode_range(start_sample, samples_to_read, pad_with_zeros)
+    }
+
+    fn decode_range<T: OutputSample>(
+        &mut self,
+        start_sample: u64,
+        samples_to_read: usize,
+        pad_with_zeros: bool,
+    ) -> Result<(Array2<T>, usize)> {
+        // The buffer gets extended if the duration from the headers turns out to be too short.
+        let capacity = if pad_with_zeros {
+            samples_to_read
+        } else {
+            usize::min(samples_to_read, self.num_frames().saturating_sub(start_sample) as usize)
+        };
+        let mut pcm_data = PlanarBuf::new(self.channels, capacity);
+        self.decode_to(start_sample, samples_to_read, &mut pcm_data)?;
+        let unpaded_len = pcm_data.len();
+        if pad_with_zeros {
+            pcm_data.pad(samples_to_read)?
+        }
+        Ok((pcm_data.into_array(), unpaded_len))
+    }
+
+    /// Converts a time into a number of samples per channel, regardless of the time base.
+    fn time_to_samples(&self, time: Time) -> u64 {
+        TimeBase::new(1, self.sample_rate).calc_timestamp(time)
+    }
 
-        while pcm_data[0].len() < samples_to_read {
+    /// Converts a sample index into a timestamp using the time base of the track, integers are
+    /// used rather than `Time` to avoid rounding errors.
+    fn sample_to_ts(&self, sample: u64) -> u64 {
+        let (numer, denom) = (self.time_base.numer as u128, self.time_base.denom as u128);
+        (sample as u128 * denom / (numer * self.sample_rate as u128)) as u64
+    }
+
+    /// Converts a timestamp using the time base of the track into a sample index.
+    fn ts_to_sample(&self, ts: u64) -> u64 {
+        let (numer, denom) = (self.time_base.numer as u128, self.time_base.denom as u128);
+        (ts as u128 * numer * self.sample_rate as u128 / denom) as u64
+    }
+
+    /// Decodes the data starting at `start_time` into `out`, a buffer holding the samples for each
+    /// channel one after the other. `out.len() / channels` samples are decoded for each channel
+    /// and the number of samples actually written per channel is returned, this is smaller than
+    /// requested when reaching the end of the stream.
+    pub fn decode_into<T: OutputSample, I: IntoTime>(
+        &mut self,
+        start_time: I,
+        out: &mut [T],
+    ) -> Result<usize> {
+        if self.channels == 0 || !out.len().is_multiple_of(self.channels) {
+            anyhow::bail!("output length {} is not a multiple of {}", out.len(), self.channels)
+        }
+        let start_sample = self.time_to_samples(start_time.into_time());
+        let samples_to_read = out.len() / self.channels;
+        let mut pcm_data = PlanarBuf::from_storage(out, self.channels, samples_to_read);
+        self.decode_to(start_sample, samples_to_read, &mut pcm_data)?;
+        Ok(pcm_data.len())
+    }
+
+    /// Decodes from sample `start_sample` into `pcm_data`. When `start_sample` is at or slightly
+    /// after the end of the previous call, the decoding resumes from there without seeking,
+    /// starting with the leftover samples of the last decoded packet.
+    fn decode_to<T: OutputSample, S: Storage<T>>(
+        &mut self,
+        start_sample: u64,
+        samples_to_read: usize,
+        pcm_data: &mut PlanarBuf<T, S>,
+    ) -> Result<()> {
+        // Skipping up to a second of audio is cheaper than seeking and priming the decoder.
+        let max_skip = self.sample_rate as u64;
+        let (mut position, mut to_skip) = match self.position.take() {
+            Some(position) if start_sample >= position && start_sample - position <= max_skip => {
+                (position, (start_sample - position) as usize)
+            }
+            _ => {
+                self.carry = None;
+                self.num_seeks += 1;
+                let mode = match self.seek_mode {
+                    SeekMode::Coarse => symphonia::core::formats::SeekMode::Coarse,
+                    SeekMode::Accurate => symphonia::core::formats::SeekMode::Accurate,
+                };
+                // Somehow using Time rather than TimeStamp in the seek below doesn't seem to have
+                // much effects.
+                let seeked_to = self.format.seek(
+                    mode,
+                    symphonia::core::formats::SeekTo::TimeStamp {
+                        ts: self.sample_to_ts(start_sample),
+                        track_id: self.track_id,
+                    },
+                )?;
+                self.decoder.reset();
+                let position = self.ts_to_sample(seeked_to.actual_ts);
+                let to_skip = match self.seek_mode {
+                    SeekMode::Coarse => 0,
+                    SeekMode::Accurate => start_sample.saturating_sub(position) as usize,
+                };
+                (position, to_skip)
+            }
+        };
+        self.last_start_sample = position + to_skip as u64;
+
+        if let Some((data, consumed)) = self.carry.take() {
+            let prev_len = pcm_data.len();
+            let skipped = usize::min(to_skip, data.frames() - consumed);
+            to_skip = conv_ref(pcm_data, &data, consumed + to_skip, samples_to_read)?;
+            let newly_consumed = skipped + pcm_data.len() - prev_len;
+            position += newly_consumed as u64;
+            if consumed + newly_consumed < data.frames() {
+                self.carry = Some((data, consumed + newly_consumed))
+            }
+        }
+
+        while pcm_data.len() < samples_to_read {
             // Get the next packet from the media format.
             let packet = match self.format.next_packet() {
                 Ok(packet) => packet,
@@ -170,50 +845,51 @@ impl FileReader {
 
             // Decode the packet into audio samples.
             let decoded = self.decoder.decode(&packet)?;
-            to_skip = match decoded {
-                symphonia::core::audio::AudioBufferRef::F32(data) => {
-                    conv_s(&mut pcm_data, data, to_skip, samples_to_read)
-                }
-                symphonia::core::audio::AudioBufferRef::U8(data) => {
-                    conv_s(&mut pcm_data, data, to_skip, samples_to_read)
-                }
-                symphonia::core::audio::AudioBufferRef::U16(data) => {
-                    conv_s(&mut pcm_data, data, to_skip, samples_to_read)
-                }
-                symphonia::core::audio::AudioBufferRef::U24(data) => {
-                    conv_s(&mut pcm_data, data, to_skip, samples_to_read)
-                }
-                symphonia::core::audio::AudioBufferRef::U32(data) => {
-                    conv_s(&mut pcm_data, data, to_skip, samples_to_read)
-                }
-                symphonia::core::audio::AudioBufferRef::S8(data) => {
-                    conv_s(&mut pcm_data, data, to_skip, samples_to_read)
-                }
-                symphonia::core::audio::AudioBufferRef::S16(data) => {
-                    conv_s(&mut pcm_data, data, to_skip, samples_to_read)
-                }
-                symphonia::core::audio::AudioBufferRef::S24(data) => {
-                    conv_s(&mut pcm_data, data, to_skip, samples_to_read)
-                }
-                symphonia::core::audio::AudioBufferRef::S32(data) => {
-                    conv_s(&mut pcm_data, data, to_skip, samples_to_read)
-                }
-                symphonia::core::audio::AudioBufferRef::F64(data) => {
-                    conv_s(&mut pcm_data, data, to_skip, samples_to_read)
-                }
-            };
-        }
-        let unpaded_len = if pcm_data.is_empty() { 0 } else { pcm_data[0].len() };
-        if pad_with_zeros && unpaded_len < samples_to_read {
-            for pcm_data in pcm_data.iter_mut() {
-                pcm_data.resize(samples_to_read, 0f32)
+            let prev_len = pcm_data.len();
+            let skipped = usize::min(to_skip, decoded.frames());
+            to_skip = conv_ref(pcm_data, &decoded, to_skip, samples_to_read)?;
+            let consumed = skipped + pcm_data.len() - prev_len;
+            position += consumed as u64;
+            if consumed < decoded.frames() {
+                self.carry = Some((into_owned(decoded), consumed))
             }
         }
-        Ok((pcm_data, unpaded_len))
+        self.position = Some(position);
+        Ok(())
+    }
+
+    /// Sets how the decode functions seek to the requested start time.
+    pub fn set_seek_mode(&mut self, seek_mode: SeekMode) {
+        self.seek_mode = seek_mode
+    }
+
+    /// The start time in seconds of the data returned by the last decode call. With coarse
+    /// seeking, this can differ from the requested start time.
+    pub fn last_start_sec(&self) -> f64 {
+        self.last_start_sample as f64 / self.sample_rate as f64
     }
 
-    pub fn decode_all(&mut self) -> Result<Vec<Vec<f32>>> {
-        let mut pcm_data = vec![vec![]; self.channels];
+    /// The number of times the decode functions had to seek, segments that were decoded by
+    /// resuming from the end of the previous call are not counted.
+    pub fn num_seeks(&self) -> usize {
+        self.num_seeks
+    }
+
+    /// Decodes the whole stream using the given dtype.
+    pub fn decode_all_dtype(&mut self, dtype: Dtype) -> Result<Pcm> {
+        let pcm = match dtype {
+            Dtype::Int16 => Pcm::Int16(self.decode_all_as()?),
+            Dtype::Int32 => Pcm::Int32(self.decode_all_as()?),
+            Dtype::Float32 => Pcm::Float32(self.decode_all_as()?),
+            Dtype::Float64 => Pcm::Float64(self.decode_all_as()?),
+        };
+        Ok(pcm)
+    }
+
+    pub fn decode_all_as<T: OutputSample>(&mut self) -> Result<Array2<T>> {
+        let mut pcm_data = PlanarBuf::new(self.channels, self.num_frames() as usize);
+        self.position = None;
+        self.carry = None;
         self.format.seek(
             symphonia::core::formats::SeekMode::Accurate,
             symphonia::core::formats::SeekTo::TimeStamp {
@@ -246,24 +922,14 @@ impl FileReader {
 
             // Decode the packet into audio samples.
             let decoded = self.decoder.decode(&packet)?;
-            match decoded {
-                symphonia::core::audio::AudioBufferRef::F32(data) => {
-                    for (channel_index, pcm_data) in pcm_data.iter_mut().enumerate() {
-                        pcm_data.extend_from_slice(data.chan(channel_index))
-                    }
-                }
-                symphonia::core::audio::AudioBufferRef::U8(data) => conv(&mut pcm_data, data),
-                symphonia::core::audio::AudioBufferRef::U16(data) => conv(&mut pcm_data, data),
-                symphonia::core::audio::AudioBufferRef::U24(data) => conv(&mut pcm_data, data),
-                symphonia::core::audio::AudioBufferRef::U32(data) => conv(&mut pcm_data, data),
-                symphonia::core::audio::AudioBufferRef::S8(data) => conv(&mut pcm_data, data),
-                symphonia::core::audio::AudioBufferRef::S16(data) => conv(&mut pcm_data, data),
-                symphonia::core::audio::AudioBufferRef::S24(data) => conv(&mut pcm_data, data),
-                symphonia::core::audio::AudioBufferRef::S32(data) => conv(&mut pcm_data, data),
-                symphonia::core::audio::AudioBufferRef::F64(data) => conv(&mut pcm_data, data),
-            };
+            conv_ref(&mut pcm_data, &decoded, 0, usize::MAX)?;
         }
-        Ok(pcm_data)
+        Ok(pcm_data.into_array())
+    }
+
+    /// The number of frames in the stream as derived from its duration.
+    fn num_frames(&self) -> u64 {
+        self.time_to_samples(self.duration)
     }
 
     pub fn sample_rate(&self) -> u32 {
@@ -273,6 +939,30 @@ impl FileReader {
     pub fn channels(&self) -> usize {
         self.channels
     }
+
+    /// The short name of the codec used by the decoded track, e.g. "mp3" or "pcm_s16le".
+    pub fn codec_name(&self) -> &'static str {
+        codec_name(self.codec)
+    }
+
+    /// The number of bits per sample, only available for some codecs, e.g. pcm or flac.
+    pub fn bits_per_sample(&self) -> Option<u32> {
+        self.bits_per_sample
+    }
+
+    /// The container format as guessed from the first bytes of the file, e.g. "wav" or "ogg".
+    pub fn container(&self) -> &'static str {
+        self.container
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// All the tracks available in the file, including the ones that cannot be decoded.
+    pub fn tracks(&self) -> &[TrackInfo] {
+        &self.tracks
+    }
 }
 
 pub fn resample(pcm_in: &[f32], sr_in: usize, sr_out: usize) -> anyhow::Result<Vec<f32>> {
@@ -308,15 +998,263 @@ pub fn resample(pcm_in: &[f32], sr_in: usize, sr_out: usize) -> anyhow::Result<V
 }
 
 pub fn resample2(
-    pcm_in: &[Vec<f32>],
+    pcm_in: ArrayView2<f32>,
     sr_in: usize,
     sr_out: usize,
-) -> anyhow::Result<Vec<Vec<f32>>> {
-    pcm_in
-        .iter()
-        .map(|data| {
-            let data = resample(data, sr_in, sr_out)?;
-            Ok::<_, anyhow::Error>(data)
-        })
-        .collect::<Result<Vec<_>, _>>()
+) -> anyhow::Result<Array2<f32>> {
+    let channels = pcm_in.nrows();
+    let mut pcm_out = vec![];
+    let mut len = 0;
+    for data in pcm_in.rows() {
+        let data = match data.as_slice() {
+            Some(data) => resample(data, sr_in, sr_out)?,
+            None => resample(&data.to_vec(), sr_in, sr_out)?,
+        };
+        len = data.len();
+        pcm_out.extend_from_slice(&data)
+    }
+    Ok(Array2::from_shape_vec((channels, len), pcm_out)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use numpy::ndarray::s;
+
+    // Encodes an ebml element, the size always uses 8 bytes.
+    fn element(id: u32, data: &[u8]) -> Vec<u8> {
+        let mut out: Vec<u8> = id.to_be_bytes().into_iter().skip_while(|&b| b == 0).collect();
+        out.push(0x01);
+        out.extend_from_slice(&(data.len() as u64).to_be_bytes()[1..]);
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn uint(id: u32, v: u64) -> Vec<u8> {
+        element(id, &v.to_be_bytes())
+    }
+
+    /// A mono flac track in a matroska container, with one block every 72ms and a cluster every
+    /// 720ms. Matroska uses timestamps in milliseconds rather than in samples. The segment
+    /// duration is only written when `with_duration` is set.
+    fn flac_mkv(pcm: &[f32], sample_rate: u32, with_duration: bool) -> Result<Vec<u8>> {
+        flac_mkv_tracks(&[(pcm, None)], sample_rate, with_duration)
+    }
+
+    /// Similar to `flac_mkv` with one track per `(pcm, language)` pair, all the tracks should have
+    /// the same length and their blocks are interleaved.
+    fn flac_mkv_tracks(
+        tracks: &[(&[f32], Option<&str>)],
+        sample_rate: u32,
+        with_duration: bool,
+    ) -> Result<Vec<u8>> {
+        const BLOCK_LEN: usize = 1152;
+        let block_ms = (BLOCK_LEN * 1000 / sample_rate as usize) as u64;
+        let mut track_entries = vec![];
+        let mut frames = vec![];
+        for (track_idx, (pcm, language)) in tracks.iter().enumerate() {
+            let mut codec_private = vec![];
+            let mut track_frames = vec![];
+            for block in pcm.chunks(BLOCK_LEN) {
+                let mut stream = vec![];
+                crate::flac::write(&mut stream, &[block], sample_rate, 16, 0)?;
+                // Skip the metadata blocks to only keep the encoded frame.
+                let mut pos = 4;
+                loop {
+                    let len =
+                        u32::from_be_bytes([0, stream[pos + 1], stream[pos + 2], stream[pos + 3]]);
+                    let is_last = stream[pos] & 0x80 != 0;
+                    pos += 4 + len as usize;
+                    if is_last {
+                        break;
+                    }
+                }
+                if codec_private.is_empty() {
+                    codec_private = stream[..pos].to_vec()
+                }
+                track_frames.push(stream[pos..].to_vec())
+            }
+            let audio = [element(0xB5, &(sample_rate as f64).to_be_bytes()), uint(0x9F, 1)];
+            let mut track = vec![
+                uint(0xD7, track_idx as u64 + 1),
+                uint(0x73C5, track_idx as u64 + 1),
+                uint(0x83, 2),
+                element(0x86, b"A_FLAC"),
+                element(0x63A2, &codec_private),
+                uint(0x23E383, block_ms * 1_000_000),
+                element(0xE1, &audio.concat()),
+            ];
+            if let Some(language) = language {
+                track.push(element(0x22B59C, language.as_bytes()))
+            }
+            track_entries.push(element(0xAE, &track.concat()));
+            frames.push(track_frames)
+        }
+        let duration_ms = tracks[0].0.len() as f64 * 1000. / sample_rate as f64;
+
+        let mut info = vec![uint(0x2AD7B1, 1_000_000)];
+        if with_duration {
+            info.push(element(0x4489, &duration_ms.to_be_bytes()))
+        }
+        let tracks = element(0x1654AE6B, &track_entries.concat());
+        let info = element(0x1549A966, &info.concat());
+        let n_frames = frames[0].len();
+        let clusters: Vec<Vec<u8>> = (0..n_frames)
+            .step_by(10)
+            .map(|first_frame| {
+                let mut cluster = uint(0xE7, first_frame as u64 * block_ms);
+                for frame_idx in first_frame..usize::min(first_frame + 10, n_frames) {
+                    let rel_ts = ((frame_idx - first_frame) as u64 * block_ms) as i16;
+                    for (track_idx, frames) in frames.iter().enumerate() {
+                        let mut block = vec![0x81 + track_idx as u8];
+                        block.extend_from_slice(&rel_ts.to_be_bytes());
+                        block.push(0x80);
+                        block.extend_from_slice(&frames[frame_idx]);
+                        cluster.extend(element(0xA3, &block))
+                    }
+                }
+                element(0x1F43B675, &cluster)
+            })
+            .collect();
+
+        // The cues point at each cluster, relative to the start of the segment data. The elements
+        // have a fixed size so the length of the cues does not depend on the positions.
+        let cues = |mut pos: u64| {
+            let mut points = vec![];
+            for (cluster_idx, cluster) in clusters.iter().enumerate() {
+                let positions = [uint(0xF7, 1), uint(0xF1, pos)].concat();
+                let ts = cluster_idx as u64 * 10 * block_ms;
+                points.extend(element(0xBB, &[uint(0xB3, ts), element(0xB7, &positions)].concat()));
+                pos += cluster.len() as u64;
+            }
+            element(0x1C53BB6B, &points)
+        };
+        let cues = cues((info.len() + tracks.len() + cues(0).len()) as u64);
+        let segment = [info, tracks, cues, clusters.concat()].concat();
+        Ok([element(0x1A45DFA3, &element(0x4282, b"matroska")), element(0x18538067, &segment)]
+            .concat())
+    }
+
+    #[test]
+    fn time_base_differs_from_sample_rate() -> Result<()> {
+        let sample_rate = 16000;
+        let pcm: Vec<f32> = (0..5 * sample_rate).map(|i| ((i as f32) * 0.01).sin() * 0.5).collect();
+        let mkv = flac_mkv(&pcm, sample_rate as u32, true)?;
+        let mut reader = FileReader::from_bytes(mkv, Some("mkv"), &Default::default())?;
+        assert_eq!(reader.time_base, TimeBase::new(1_000_000, 1_000_000_000));
+        assert_eq!((reader.sample_rate(), reader.duration_sec()), (16000, 5.0));
+        let all: Array2<f32> = reader.decode_all_as()?;
+        assert_eq!(all.dim(), (1, pcm.len()));
+
+        // Consecutive segments resume from the previous position without seeking.
+        for start in (1000..20000usize).step_by(3000) {
+            let (data, len) = reader.decode_samples(start as u64, 3000, false)?;
+            assert_eq!(len, 3000);
+            assert_eq!(data, all.slice(s![.., start..start + 3000]));
+        }
+        assert_eq!(reader.num_seeks(), 1);
+        // Skipping less than a second of audio does not seek either.
+        let (data, _) = reader.decode_samples(30000, 1000, false)?;
+        assert_eq!(data, all.slice(s![.., 30000..31000]));
+        assert_eq!(reader.num_seeks(), 1);
+        assert_eq!(reader.last_start_sec(), 30000. / 16000.);
+        // Jumping backward or further ahead seeks.
+        for start in [5000usize, 60000] {
+            let (data, _) = reader.decode_samples(start as u64, 1000, false)?;
+            assert_eq!(data, all.slice(s![.., start..start + 1000]));
+        }
+        assert_eq!(reader.num_seeks(), 3);
+
+        // Durations are converted using the sample rate rather than the time base.
+        let (data, len) = reader.decode(2.5, 1.0, false)?;
+        assert_eq!(len, 16000);
+        assert_eq!(data, all.slice(s![.., 40000..56000]));
+        let (data, len) = reader.decode(4.5, 1.0, true)?;
+        assert_eq!((data.dim(), len), ((1, 16000), 8000));
+        Ok(())
+    }
+
+    #[test]
+    fn duration_from_packet_timestamps() -> Result<()> {
+        // 50 blocks of 1152 samples, i.e. 3.6s at 16kHz.
+        let pcm: Vec<f32> = (0..50 * 1152).map(|i| ((i as f32) * 0.02).sin() * 0.5).collect();
+        let mkv = flac_mkv(&pcm, 16000, false)?;
+        let mut reader = FileReader::from_bytes(mkv.clone(), Some("mkv"), &Default::default())?;
+        assert_eq!(reader.duration_sec(), 3.6);
+        let all: Array2<f32> = reader.decode_all_as()?;
+        assert_eq!(all.dim(), (1, pcm.len()));
+
+        // The exact duration ignores the one declared in the headers. The last block is shortened
+        // by 63ms so that the two differ, the packet still lasts for the default 72ms.
+        let mkv = flac_mkv(&pcm[..pcm.len() - 1008], 16000, true)?;
+        let reader = FileReader::from_bytes(mkv.clone(), Some("mkv"), &Default::default())?;
+        assert_eq!(reader.duration_sec(), 3.537);
+        let opts = OpenOptions { exact_duration: true, ..Default::default() };
+        let reader = FileReader::from_bytes(mkv, Some("mkv"), &opts)?;
+        assert_eq!(reader.duration_sec(), 3.6);
+        Ok(())
+    }
+
+    #[test]
+    fn track_selection() -> Result<()> {
+        let eng: Vec<f32> = (0..20 * 1152).map(|i| ((i as f32) * 0.01).sin() * 0.5).collect();
+        let fra: Vec<f32> = (0..20 * 1152).map(|i| ((i as f32) * 0.03).cos() * 0.25).collect();
+        let mkv = flac_mkv_tracks(&[(&eng, Some("eng")), (&fra, Some("fra"))], 16000, true)?;
+        let open = |track| {
+            let opts = OpenOptions { track, ..Default::default() };
+            FileReader::from_bytes(mkv.clone(), Some("mkv"), &opts)
+        };
+
+        let mut reader = open(None)?;
+        let tracks: Vec<_> =
+            reader.tracks().iter().map(|t| (t.codec, t.channels, t.language.as_deref())).collect();
+        assert_eq!(tracks, [("flac", Some(1), Some("eng")), ("flac", Some(1), Some("fra"))]);
+        let expected: Array2<f32> = reader.decode_all_as()?;
+        assert_eq!(expected.dim(), (1, eng.len()));
+        for track in [TrackSelector::Index(0), TrackSelector::Language("ENG".to_string())] {
+            assert_eq!(open(Some(track))?.decode_all_as::<f32>()?, expected);
+        }
+
+        let mut reader = open(Some(TrackSelector::Index(1)))?;
+        let expected: Array2<f32> = reader.decode_all_as()?;
+        assert_ne!(expected, open(None)?.decode_all_as::<f32>()?);
+        let mut reader = open(Some(TrackSelector::Language("fra".to_string())))?;
+        assert_eq!(reader.decode_all_as::<f32>()?, expected);
+        // Seeking uses the timestamps of the selected track.
+        let (data, _) = reader.decode_samples(15000, 1000, false)?;
+        assert_eq!(data, expected.slice(s![.., 15000..16000]));
+
+        assert!(open(Some(TrackSelector::Index(2))).is_err());
+        assert!(open(Some(TrackSelector::Language("deu".to_string()))).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn coarse_seeks() -> Result<()> {
+        let pcm: Vec<f32> = (0..5 * 16000).map(|i| ((i as f32) * 0.01).sin() * 0.5).collect();
+        let mkv = flac_mkv(&pcm, 16000, true)?;
+        let mut reader = FileReader::from_bytes(mkv.clone(), Some("mkv"), &Default::default())?;
+        let all: Array2<f32> = reader.decode_all_as()?;
+
+        // Accurate seeks start exactly at the requested sample.
+        let (data, _) = reader.decode_samples(40000, 1000, false)?;
+        assert_eq!(data, all.slice(s![.., 40000..41000]));
+        assert_eq!(reader.last_start_sec(), 2.5);
+
+        // Coarse seeks start at the beginning of the block containing the requested sample, i.e.
+        // block 34 of 1152 samples.
+        let opts = OpenOptions { seek_mode: SeekMode::Coarse, ..Default::default() };
+        let mut reader = FileReader::from_bytes(mkv, Some("mkv"), &opts)?;
+        let (data, len) = reader.decode_samples(40000, 1000, false)?;
+        assert_eq!(reader.last_start_sec(), 39168. / 16000.);
+        assert_eq!(len, 1000);
+        assert_eq!(data, all.slice(s![.., 39168..40168]));
+
+        // The seek mode can be changed on an existing reader.
+        reader.set_seek_mode(SeekMode::Accurate);
+        let (data, _) = reader.decode_samples(20000, 1000, false)?;
+        assert_eq!(data, all.slice(s![.., 20000..21000]));
+        assert_eq!(reader.last_start_sec(), 1.25);
+        Ok(())
+    }
 }
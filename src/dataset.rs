@@ -1,16 +1,40 @@
-use crate::{audio, par_map, py_bail, PyRes};
+use crate::{archive, audio, packed, par_map, pcm, pcm_cache, py_bail, PyRes};
 use pyo3::prelude::*;
 use rand::{Rng, SeedableRng};
 use std::sync::Arc;
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-struct PathWithDuration {
-    path: String,
-    duration: f64,
+pub struct PathWithDuration {
+    pub path: String,
+    pub duration: f64,
 }
 
 type Paths = Arc<Vec<PathWithDuration>>;
 
+// The duration is optional in jsonl files, manifests generated by `scan_audio` use a null duration
+// for the files that could not be read.
+#[derive(Debug, Clone, serde::Deserialize)]
+struct JsonlEntry {
+    path: String,
+    duration: Option<f64>,
+}
+
+/// The format of headerless pcm files, provided as a dictionary with `dtype`, `channels`, and
+/// `sample_rate` keys, see `read_raw`.
+#[derive(FromPyObject)]
+#[pyo3(from_item_all)]
+pub struct RawFormat {
+    dtype: String,
+    channels: usize,
+    sample_rate: u32,
+}
+
+impl RawFormat {
+    fn into_pcm_format(self) -> PyResult<pcm::PcmFormat> {
+        pcm::PcmFormat::raw(&self.dtype, self.channels, self.sample_rate).w()
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 enum OnError {
     Raise,
@@ -22,13 +46,37 @@ struct Sample {
     sample_index: u64,
     file_index: usize,
     start_time: f64,
+    actual_start_time: f64,
     sample_rate: usize,
     unpadded_len: usize,
-    data: anyhow::Result<Vec<Vec<f32>>>,
+    data: anyhow::Result<audio::Pcm>,
     gen_duration: f64,
 }
 
 impl Sample {
+    fn new(
+        sample_index: u64,
+        file_index: usize,
+        start_time: f64,
+        segment: anyhow::Result<Segment>,
+        gen_duration: f64,
+    ) -> Self {
+        let (data, unpadded_len, sample_rate, actual_start_time) = match segment {
+            Ok(s) => (Ok(s.data), s.unpadded_len, s.sample_rate, s.start_time),
+            Err(err) => (Err(err), 0, 0, 0.),
+        };
+        Self {
+            sample_index,
+            file_index,
+            start_time,
+            actual_start_time,
+            sample_rate,
+            unpadded_len,
+            data,
+            gen_duration,
+        }
+    }
+
     fn into_dict(
         self,
         py: Python<'_>,
@@ -54,13 +102,11 @@ impl Sample {
         dict.set_item("file_index", self.file_index)?;
         dict.set_item("path", path)?;
         dict.set_item("start_time_sec", self.start_time)?;
+        dict.set_item("actual_start_time_sec", self.actual_start_time)?;
         dict.set_item("sample_rate", self.sample_rate)?;
         dict.set_item("unpadded_len", self.unpadded_len)?;
         dict.set_item("gen_duration_sec", self.gen_duration)?;
-        dict.set_item::<_, Py<PyAny>>(
-            "data",
-            numpy::PyArray2::from_vec2(py, &data)?.into_any().unbind(),
-        )?;
+        dict.set_item("data", crate::pcm_to_py(py, data)?)?;
         Ok(Some(dict.into_any().unbind()))
     }
 }
@@ -91,14 +137,31 @@ pub struct DatasetReader {
     pad_last_segment: bool,
     sample_rate: Option<usize>,
     channel_len_per_thread: usize,
+    ordered: bool,
     f: Option<Arc<Py<PyAny>>>,
+    raw: Option<pcm::PcmFormat>,
+    dtype: audio::Dtype,
+    seek_mode: audio::SeekMode,
+    cache_dir: Option<std::path::PathBuf>,
+    packed: Option<Arc<packed::Packed>>,
 }
 
 #[pymethods]
 impl DatasetReader {
     #[allow(clippy::too_many_arguments)]
     /// Creates a reader object on a list of pairs `(filename, duration_in_seconds)`.
-    #[pyo3(signature = (paths, *, duration_sec, channel_len_per_thread=1, pad_last_segment=false, on_error=None, sample_rate=None, num_threads=None, f=None))]
+    /// When `raw` is set, the files are read as headerless pcm data, `raw` is a dictionary with
+    /// `dtype`, `channels`, and `sample_rate` keys, see `read_raw`.
+    /// `dtype` sets the type of the returned arrays, one of "int16", "int32", "float32", or
+    /// "float64".
+    /// `seek_mode` can be "accurate" or "coarse", with coarse seeking the segments may start
+    /// slightly off the sampled start time, the actual start time is returned in the
+    /// `actual_start_time_sec` field.
+    /// When `cache_dir` is set, each file is decoded in full the first time it is read and the
+    /// decoded pcm data is stored in this directory, later segments from the same file, including
+    /// in subsequent epochs, are read from a memory map of this cache rather than decoded again.
+    /// The data is stored using `dtype` so that it is read back without any conversion.
+    #[pyo3(signature = (paths, *, duration_sec, channel_len_per_thread=1, pad_last_segment=false, on_error=None, sample_rate=None, num_threads=None, f=None, raw=None, dtype="float32", seek_mode="accurate", cache_dir=None))]
     #[new]
     fn new(
         paths: Vec<(String, f64)>,
@@ -109,6 +172,10 @@ impl DatasetReader {
         sample_rate: Option<usize>,
         num_threads: Option<usize>,
         f: Option<Py<PyAny>>,
+        raw: Option<RawFormat>,
+        dtype: &str,
+        seek_mode: &str,
+        cache_dir: Option<std::path::PathBuf>,
     ) -> PyResult<Self> {
         let on_error = match on_error {
             Some("raise") => OnError::Raise,
@@ -135,11 +202,76 @@ impl DatasetReader {
             sample_rate,
             pad_last_segment,
             channel_len_per_thread,
+            ordered: true,
+            f: f.map(Arc::new),
+            raw: raw.map(|raw| raw.into_pcm_format()).transpose()?,
+            dtype: dtype.parse().w()?,
+            seek_mode: seek_mode.parse().w()?,
+            cache_dir,
+            packed: None,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    /// Creates a reader object on the pcm shards written by `pack` in `packed_dir`. The segments
+    /// are read from memory maps of the shards without any decoding, the data has the sample rate
+    /// that was used when packing. `dtype` sets the type of the returned arrays.
+    #[staticmethod]
+    #[pyo3(signature = (packed_dir, *, duration_sec, channel_len_per_thread=1, pad_last_segment=false, on_error=None, num_threads=None, f=None, dtype="float32"))]
+    fn from_packed(
+        packed_dir: std::path::PathBuf,
+        duration_sec: f64,
+        channel_len_per_thread: usize,
+        pad_last_segment: bool,
+        on_error: Option<&str>,
+        num_threads: Option<usize>,
+        f: Option<Py<PyAny>>,
+        dtype: &str,
+    ) -> PyResult<Self> {
+        let on_error = match on_error {
+            Some("raise") => OnError::Raise,
+            Some("log") | None => OnError::Log,
+            Some("ignore") => OnError::Ignore,
+            Some(on_error) => py_bail!("unknown on_error '{on_error}'"),
+        };
+        let packed = packed::Packed::open(&packed_dir).w_f(&packed_dir)?;
+        let paths: Vec<PathWithDuration> = packed
+            .entries()
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| PathWithDuration {
+                path: entry.path.clone(),
+                duration: packed.duration_sec(index),
+            })
+            .collect();
+        Ok(Self {
+            paths: Arc::new(paths),
+            duration_sec,
+            iter_order: IterOrder::Sequential,
+            seed: 1337,
+            skip: 0,
+            on_error,
+            num_threads: num_threads.unwrap_or_else(rayon::current_num_threads),
+            step_by: 1,
+            sample_rate: None,
+            pad_last_segment,
+            channel_len_per_thread,
+            ordered: true,
             f: f.map(Arc::new),
+            raw: None,
+            dtype: dtype.parse().w()?,
+            seek_mode: audio::SeekMode::default(),
+            cache_dir: None,
+            packed: Some(Arc::new(packed)),
         })
     }
 
     /// Sequential reading.
+    ///
+    /// Each worker thread handles blocks of 4 consecutive segments so that they are decoded from
+    /// the same open file without seeking. The channel length per thread is raised to at least 4
+    /// in this mode, so that all the threads are kept busy while the results of a block are
+    /// consumed.
     #[pyo3(signature = (*, skip=0, step_by=1))]
     fn seq(&self, skip: u64, step_by: u64) -> Self {
         Self {
@@ -154,7 +286,13 @@ impl DatasetReader {
             sample_rate: self.sample_rate,
             pad_last_segment: self.pad_last_segment,
             channel_len_per_thread: self.channel_len_per_thread,
+            ordered: self.ordered,
             f: self.f.clone(),
+            raw: self.raw,
+            dtype: self.dtype,
+            seek_mode: self.seek_mode,
+            cache_dir: self.cache_dir.clone(),
+            packed: self.packed.clone(),
         }
     }
 
@@ -178,7 +316,13 @@ impl DatasetReader {
             sample_rate: self.sample_rate,
             pad_last_segment: self.pad_last_segment,
             channel_len_per_thread: self.channel_len_per_thread,
+            ordered: self.ordered,
             f: self.f.clone(),
+            raw: self.raw,
+            dtype: self.dtype,
+            seek_mode: self.seek_mode,
+            cache_dir: self.cache_dir.clone(),
+            packed: self.packed.clone(),
         }
     }
 
@@ -196,6 +340,16 @@ impl DatasetReader {
         s
     }
 
+    /// When `ordered` is false, the worker threads pick the next segment to process from a shared
+    /// queue and the samples are returned as soon as they are ready rather than in order, so that a
+    /// slow segment does not stall the iteration while other threads have results available.
+    #[pyo3(signature = (ordered))]
+    fn ordered(&self, ordered: bool) -> Self {
+        let mut s = self.clone();
+        s.ordered = ordered;
+        s
+    }
+
     #[pyo3(signature = (on_error))]
     fn on_error(&self, on_error: &str) -> PyResult<Self> {
         let on_error = match on_error {
@@ -226,8 +380,10 @@ impl DatasetReader {
                     self.num_threads,
                     self.pad_last_segment,
                     self.channel_len_per_thread,
+                    self.ordered,
                     self.sample_rate,
                     self.f.clone(),
+                    self.source_options(),
                 )?;
                 Ok(iter.into_pyobject(py).w()?.into_any().unbind())
             }
@@ -242,8 +398,10 @@ impl DatasetReader {
                     self.num_threads,
                     self.pad_last_segment,
                     self.channel_len_per_thread,
+                    self.ordered,
                     self.sample_rate,
                     self.f.clone(),
+                    self.source_options(),
                 )?;
                 Ok(iter.into_pyobject(py).w()?.into_any().unbind())
             }
@@ -258,8 +416,10 @@ impl DatasetReader {
                     self.num_threads,
                     self.pad_last_segment,
                     self.channel_len_per_thread,
+                    self.ordered,
                     self.sample_rate,
                     self.f.clone(),
+                    self.source_options(),
                 )?;
                 Ok(iter.into_pyobject(py).w()?.into_any().unbind())
             }
@@ -267,11 +427,32 @@ impl DatasetReader {
     }
 }
 
+impl DatasetReader {
+    fn source_options(&self) -> SourceOptions {
+        let open_options =
+            audio::OpenOptions { raw: self.raw, seek_mode: self.seek_mode, ..Default::default() };
+        SourceOptions {
+            open_options,
+            dtype: self.dtype,
+            cache_dir: self.cache_dir.clone(),
+            packed: self.packed.clone(),
+        }
+    }
+}
+
 /// Creates a reader object from a jsonl file.
+///
+/// Each line should contain a `path` and a `duration` field, lines with a null duration are
+/// skipped. Paths can refer to members of uncompressed tar files, e.g. WebDataset shards, using
+/// the `shard.tar#member.flac` syntax, and to members of zip files using the
+/// `archive.zip!/dir/member.wav` syntax. When `raw` is set, the files are read as headerless pcm
+/// data, see `DatasetReader`. `dtype` sets the type of the returned arrays, `seek_mode` how
+/// segments are seeked to, and `cache_dir` where decoded pcm data gets cached, see
+/// `DatasetReader`.
 #[allow(clippy::too_many_arguments)]
-#[pyfunction(signature = (jsonl, *, duration_sec, channel_len_per_thread=1, pad_last_segment=false, on_error=None, sample_rate=None, num_threads=None, f=None))]
+#[pyfunction(signature = (jsonl, *, duration_sec, channel_len_per_thread=1, pad_last_segment=false, on_error=None, sample_rate=None, num_threads=None, f=None, raw=None, dtype="float32", seek_mode="accurate", cache_dir=None))]
 pub fn dataset_jsonl(
-    jsonl: String,
+    jsonl: std::path::PathBuf,
     duration_sec: f64,
     channel_len_per_thread: usize,
     pad_last_segment: bool,
@@ -279,29 +460,18 @@ pub fn dataset_jsonl(
     sample_rate: Option<usize>,
     num_threads: Option<usize>,
     f: Option<Py<PyAny>>,
+    raw: Option<RawFormat>,
+    dtype: &str,
+    seek_mode: &str,
+    cache_dir: Option<std::path::PathBuf>,
 ) -> PyResult<DatasetReader> {
-    use std::io::BufRead;
-
     let on_error = match on_error {
         Some("raise") => OnError::Raise,
         Some("log") | None => OnError::Log,
         Some("ignore") => OnError::Ignore,
         Some(on_error) => py_bail!("unknown on_error '{on_error}'"),
     };
-    let jsonl = std::path::PathBuf::from(&jsonl).canonicalize()?;
-    let file = std::io::BufReader::new(std::fs::File::open(&jsonl)?);
-    let jsonl_dir = jsonl.parent().unwrap_or_else(|| std::path::Path::new("."));
-    let mut paths = vec![];
-    for line in file.lines() {
-        let line = line?;
-        let path: PathWithDuration = serde_json::from_str(line.as_str()).w()?;
-        let p = std::path::PathBuf::from(path.path);
-        let p = if p.is_absolute() { p } else { jsonl_dir.to_path_buf().join(p) };
-        paths.push(PathWithDuration {
-            path: p.to_string_lossy().to_string(),
-            duration: path.duration,
-        });
-    }
+    let paths = read_jsonl(&jsonl).w_f(&jsonl)?;
     Ok(DatasetReader {
         paths: Arc::new(paths),
         duration_sec,
@@ -314,10 +484,39 @@ pub fn dataset_jsonl(
         pad_last_segment,
         sample_rate,
         channel_len_per_thread,
+        ordered: true,
         f: f.map(Arc::new),
+        raw: raw.map(|raw| raw.into_pcm_format()).transpose()?,
+        dtype: dtype.parse().w()?,
+        seek_mode: seek_mode.parse().w()?,
+        cache_dir,
+        packed: None,
     })
 }
 
+/// Reads the paths and durations from a jsonl manifest, relative paths are resolved from the
+/// directory containing the manifest.
+pub fn read_jsonl<P: AsRef<std::path::Path>>(jsonl: P) -> anyhow::Result<Vec<PathWithDuration>> {
+    use std::io::BufRead;
+
+    let jsonl = jsonl.as_ref().canonicalize()?;
+    let file = std::io::BufReader::new(std::fs::File::open(&jsonl)?);
+    let jsonl_dir = jsonl.parent().unwrap_or_else(|| std::path::Path::new("."));
+    let mut paths = vec![];
+    for line in file.lines() {
+        let line = line?;
+        let entry: JsonlEntry = serde_json::from_str(line.as_str())?;
+        let duration = match entry.duration {
+            None => continue,
+            Some(duration) => duration,
+        };
+        let p = std::path::PathBuf::from(entry.path);
+        let p = if p.is_absolute() { p } else { jsonl_dir.to_path_buf().join(p) };
+        paths.push(PathWithDuration { path: p.to_string_lossy().to_string(), duration });
+    }
+    Ok(paths)
+}
+
 #[allow(unused)]
 #[pyclass]
 pub struct DatasetIter {
@@ -364,6 +563,256 @@ impl RngWithStep {
     }
 }
 
+/// The number of open readers kept by each worker thread.
+const READER_CACHE_SIZE: usize = 8;
+
+/// When iterating sequentially, each worker thread decodes this number of consecutive segments
+/// so that they can be read from the same open reader without seeking.
+const SEQUENTIAL_BLOCK_LEN: usize = 4;
+
+/// Where the segments of a file are read from, either the audio file itself, the decoded pcm
+/// cache, or the packed shards.
+enum Source {
+    Reader(Box<audio::FileReader>),
+    Cached(pcm_cache::CachedPcm),
+    Packed(Arc<packed::Packed>, usize),
+}
+
+/// The options used by the worker threads to open the files.
+#[derive(Clone)]
+struct SourceOptions {
+    open_options: audio::OpenOptions,
+    dtype: audio::Dtype,
+    cache_dir: Option<std::path::PathBuf>,
+    packed: Option<Arc<packed::Packed>>,
+}
+
+impl SourceOptions {
+    fn open(&self, file_index: usize, path: &str) -> anyhow::Result<Source> {
+        if let Some(packed) = self.packed.as_ref() {
+            return Ok(Source::Packed(packed.clone(), file_index));
+        }
+        if archive::file_len(path)? == 0 {
+            anyhow::bail!("empty file")
+        }
+        match self.cache_dir.as_ref() {
+            None => {
+                let reader = audio::FileReader::new_with_options(path, &self.open_options)?;
+                Ok(Source::Reader(Box::new(reader)))
+            }
+            Some(cache_dir) => {
+                let storage = pcm_cache::Storage::for_dtype(self.dtype);
+                let pcm = pcm_cache::CachedPcm::open_or_build(
+                    cache_dir,
+                    path,
+                    &self.open_options,
+                    storage,
+                )?;
+                Ok(Source::Cached(pcm))
+            }
+        }
+    }
+}
+
+impl Source {
+    fn sample_rate(&self) -> u32 {
+        match self {
+            Self::Reader(r) => r.sample_rate(),
+            Self::Cached(c) => c.sample_rate(),
+            Self::Packed(p, _) => p.sample_rate(),
+        }
+    }
+
+    fn duration_sec(&self) -> f64 {
+        match self {
+            Self::Reader(r) => r.duration_sec(),
+            Self::Cached(c) => c.duration_sec(),
+            Self::Packed(p, index) => p.duration_sec(*index),
+        }
+    }
+
+    /// Returns the decoded data, its unpadded length, and the actual start time.
+    fn decode_samples(
+        &mut self,
+        start_sample: u64,
+        num_samples: usize,
+        pad_with_zeros: bool,
+    ) -> anyhow::Result<(numpy::ndarray::Array2<f32>, usize, f64)> {
+        match self {
+            Self::Reader(r) => {
+                let (data, unpadded_len) =
+                    r.decode_samples(start_sample, num_samples, pad_with_zeros)?;
+                Ok((data, unpadded_len, r.last_start_sec()))
+            }
+            Self::Cached(_) | Self::Packed(_, _) => {
+                let (data, unpadded_len) =
+                    self.pcm().read(start_sample, num_samples, pad_with_zeros);
+                Ok((data, unpadded_len, start_sample as f64 / self.sample_rate() as f64))
+            }
+        }
+    }
+
+    fn decode_samples_dtype(
+        &mut self,
+        start_sample: u64,
+        num_samples: usize,
+        pad_with_zeros: bool,
+        dtype: audio::Dtype,
+    ) -> anyhow::Result<(audio::Pcm, usize, f64)> {
+        match self {
+            Self::Reader(r) => {
+                let (data, unpadded_len) =
+                    r.decode_samples_dtype(start_sample, num_samples, pad_with_zeros, dtype)?;
+                Ok((data, unpadded_len, r.last_start_sec()))
+            }
+            Self::Cached(_) | Self::Packed(_, _) => {
+                let (data, unpadded_len) =
+                    self.pcm().read_dtype(start_sample, num_samples, pad_with_zeros, dtype);
+                Ok((data, unpadded_len, start_sample as f64 / self.sample_rate() as f64))
+            }
+        }
+    }
+
+    /// The already decoded pcm data, this should not be called on file readers.
+    fn pcm(&self) -> pcm_cache::PlanarPcm<'_> {
+        match self {
+            Self::Reader(_) => unreachable!("no pcm data for file readers"),
+            Self::Cached(c) => c.pcm(),
+            Self::Packed(p, index) => p.pcm(*index),
+        }
+    }
+}
+
+/// A small LRU cache of open readers keyed by file index. Each worker thread has its own cache so
+/// that segments from the same file do not re-open and re-probe it, consecutive segments are then
+/// decoded without seeking.
+struct ReaderCache {
+    readers: std::collections::VecDeque<(usize, Source)>,
+}
+
+impl ReaderCache {
+    fn new() -> Self {
+        Self { readers: std::collections::VecDeque::with_capacity(READER_CACHE_SIZE) }
+    }
+
+    /// Removes the reader for `file_index` from the cache, or opens it if it is not cached. The
+    /// reader should be given back via `put` once done.
+    fn take<F>(&mut self, file_index: usize, open: F) -> anyhow::Result<Source>
+    where
+        F: FnOnce() -> anyhow::Result<Source>,
+    {
+        match self.readers.iter().position(|(index, _)| *index == file_index) {
+            Some(pos) => Ok(self.readers.remove(pos).expect("valid position").1),
+            None => open(),
+        }
+    }
+
+    fn put(&mut self, file_index: usize, reader: Source) {
+        self.readers.push_front((file_index, reader));
+        self.readers.truncate(READER_CACHE_SIZE);
+    }
+
+    /// Decodes a segment of `path` using the cached reader for this file, if any.
+    #[allow(clippy::too_many_arguments)]
+    fn decode_segment(
+        &mut self,
+        source_options: &SourceOptions,
+        file_index: usize,
+        path: &str,
+        start: SegmentStart,
+        duration_sec: f64,
+        pad_last_segment: bool,
+        target_sample_rate: Option<usize>,
+    ) -> anyhow::Result<Segment> {
+        let mut reader = self.take(file_index, || source_options.open(file_index, path))?;
+        let segment = decode_segment(
+            &mut reader,
+            start,
+            duration_sec,
+            pad_last_segment,
+            target_sample_rate,
+            source_options.dtype,
+        );
+        self.put(file_index, reader);
+        segment
+    }
+}
+
+/// Where a segment starts in its file.
+#[derive(Debug, Clone, Copy)]
+enum SegmentStart {
+    /// A start time in seconds.
+    Time(f64),
+    /// The index of the segment when the file is split in consecutive segments. The start sample
+    /// is a multiple of the segment length so that consecutive segments are exactly contiguous,
+    /// rounding the start times could otherwise overlap them by a sample and force a seek.
+    Index(u32),
+}
+
+/// Splits each file in consecutive segments of `duration_sec`, returns the file and segment
+/// indexes.
+fn file_segments(paths: &Paths, duration_sec: f64, pad_last_segment: bool) -> Vec<(u32, u32)> {
+    let mut segments = Vec::new();
+    for (path_index, path_with_d) in paths.iter().enumerate() {
+        let mut segment_index = 0u32;
+        while (segment_index + 1) as f64 * duration_sec < path_with_d.duration {
+            segments.push((path_index as u32, segment_index));
+            segment_index += 1;
+        }
+        if pad_last_segment && (segment_index as f64 * duration_sec) < path_with_d.duration {
+            segments.push((path_index as u32, segment_index));
+        }
+    }
+    segments
+}
+
+/// A decoded segment, `start_time` is the time actually reached when seeking.
+struct Segment {
+    data: audio::Pcm,
+    unpadded_len: usize,
+    sample_rate: usize,
+    start_time: f64,
+}
+
+/// Decodes a segment and resamples it to `target_sample_rate` when needed.
+/// The segment boundaries are converted to sample indexes so that all the segments decoded from a
+/// file have the exact same number of samples.
+fn decode_segment(
+    reader: &mut Source,
+    start: SegmentStart,
+    duration_sec: f64,
+    pad_last_segment: bool,
+    target_sample_rate: Option<usize>,
+    dtype: audio::Dtype,
+) -> anyhow::Result<Segment> {
+    let sample_rate = reader.sample_rate() as usize;
+    let num_samples = (duration_sec * sample_rate as f64).round() as usize;
+    let start_sample = match start {
+        SegmentStart::Time(start_time) => (start_time * sample_rate as f64).round() as u64,
+        SegmentStart::Index(index) => index as u64 * num_samples as u64,
+    };
+    match target_sample_rate {
+        Some(target_sample_rate) if target_sample_rate != sample_rate => {
+            let (data, unpadded_len, start_time) =
+                reader.decode_samples(start_sample, num_samples, pad_last_segment)?;
+            let is_unpadded = unpadded_len == data.ncols();
+            let data = audio::resample2(data.view(), sample_rate, target_sample_rate)?;
+            let unpadded_len = if is_unpadded {
+                data.ncols()
+            } else {
+                unpadded_len * target_sample_rate / sample_rate
+            };
+            let data = audio::Pcm::from_f32(data, dtype);
+            Ok(Segment { data, unpadded_len, sample_rate: target_sample_rate, start_time })
+        }
+        _ => {
+            let (data, unpadded_len, start_time) =
+                reader.decode_samples_dtype(start_sample, num_samples, pad_last_segment, dtype)?;
+            Ok(Segment { data, unpadded_len, sample_rate, start_time })
+        }
+    }
+}
+
 impl DatasetIter {
     #[allow(clippy::too_many_arguments)]
     fn new_random(
@@ -376,8 +825,10 @@ impl DatasetIter {
         num_threads: usize,
         pad_last_segment: bool,
         channel_len_per_thread: usize,
+        ordered: bool,
         target_sample_rate: Option<usize>,
         f: Option<Arc<Py<PyAny>>>,
+        source_options: SourceOptions,
     ) -> PyResult<Self> {
         let sum_durations: f64 = paths.iter().map(|p| p.duration).sum();
         if sum_durations < 1e-5 {
@@ -399,12 +850,13 @@ impl DatasetIter {
                 None,
                 num_threads,
                 channel_len_per_thread,
+                ordered,
                 move |thread_idx| {
                     let mut rng = rng.clone();
                     rng.skip(step_by * thread_idx as u64);
-                    rng
+                    (rng, ReaderCache::new())
                 },
-                move |rng| {
+                move |(rng, cache)| {
                     let now = std::time::Instant::now();
                     let (sample_index, file_index, start_time_1) = rng.next();
                     // [partition_point] returns the first element for which the predicate is
@@ -412,76 +864,44 @@ impl DatasetIter {
                     let file_index = cumulative_prs.partition_point(|&v| v < file_index);
                     let file_index = usize::min(file_index, cumulative_prs.len());
 
-                    let (data, start_time, sample_rate) = 'data: {
-                        let metadata = match std::fs::metadata(&paths[file_index].path) {
-                            Ok(md) => md,
-                            Err(err) => break 'data (Err(err.into()), 0., 0),
-                        };
-                        if metadata.len() == 0 {
-                            break 'data (Err(anyhow::Error::msg("empty file")), 0., 0);
-                        }
-                        let mut reader = match audio::FileReader::new(&paths[file_index].path) {
+                    let (segment, start_time) = 'data: {
+                        let path = &paths[file_index].path;
+                        let reader =
+                            cache.take(file_index, || source_options.open(file_index, path));
+                        let mut reader = match reader {
                             Ok(reader) => reader,
-                            Err(err) => break 'data (Err(err), 0., 0),
+                            Err(err) => break 'data (Err(err), 0.),
                         };
                         let left_in_reader = reader.duration_sec();
                         if left_in_reader <= duration_sec {
-                            let err = Err(anyhow::format_err!(
-                                "file is too small {}",
-                                reader.duration_sec()
-                            ));
-                            break 'data (err, 0., 0);
+                            let err =
+                                Err(anyhow::format_err!("file is too small {left_in_reader}"));
+                            cache.put(file_index, reader);
+                            break 'data (err, 0.);
                         }
                         let start_time = if pad_last_segment {
                             start_time_1 * left_in_reader
                         } else {
                             start_time_1 * (left_in_reader - duration_sec)
                         };
-                        let (data, unpadded_len) =
-                            match reader.decode(start_time, duration_sec, pad_last_segment) {
-                                Ok(data) => data,
-                                Err(err) => break 'data (Err(err), 0., 0),
-                            };
-                        let sample_rate = reader.sample_rate() as usize;
-                        match target_sample_rate {
-                            None => (Ok((data, unpadded_len)), start_time, sample_rate),
-                            Some(target_sample_rate) => {
-                                if target_sample_rate != sample_rate {
-                                    let is_unpadded = unpadded_len == data[0].len();
-                                    let data =
-                                        audio::resample2(&data, sample_rate, target_sample_rate);
-                                    match data {
-                                        Ok(data) => {
-                                            let unpadded_len = if is_unpadded {
-                                                data[0].len()
-                                            } else {
-                                                unpadded_len * target_sample_rate / sample_rate
-                                            };
-                                            (
-                                                Ok((data, unpadded_len)),
-                                                start_time,
-                                                target_sample_rate,
-                                            )
-                                        }
-                                        Err(err) => break 'data (Err(err), 0., 0),
-                                    }
-                                } else {
-                                    (Ok((data, unpadded_len)), start_time, sample_rate)
-                                }
-                            }
-                        }
+                        let segment = decode_segment(
+                            &mut reader,
+                            SegmentStart::Time(start_time),
+                            duration_sec,
+                            pad_last_segment,
+                            target_sample_rate,
+                            source_options.dtype,
+                        );
+                        cache.put(file_index, reader);
+                        (segment, start_time)
                     };
-                    let unpadded_len = data.as_ref().map_or(0, |d| d.1);
-                    let data = data.map(|d| d.0);
-                    let sample = Sample {
+                    let sample = Sample::new(
                         sample_index,
                         file_index,
                         start_time,
-                        sample_rate,
-                        data,
-                        unpadded_len,
-                        gen_duration: now.elapsed().as_secs_f64(),
-                    };
+                        segment,
+                        now.elapsed().as_secs_f64(),
+                    );
                     match f.as_ref() {
                         None => SampleOrObject::Sample(sample),
                         Some(f) => {
@@ -513,30 +933,15 @@ impl DatasetIter {
         num_threads: usize,
         pad_last_segment: bool,
         channel_len_per_thread: usize,
+        ordered: bool,
         target_sample_rate: Option<usize>,
         f: Option<Arc<Py<PyAny>>>,
+        source_options: SourceOptions,
     ) -> PyResult<Self> {
         use rand::seq::SliceRandom;
 
         // For a million hours of audio with duration set to 30s, this would contain 120m elements.
-        let mut segments: Vec<_> = paths
-            .iter()
-            .enumerate()
-            .map(|(path_index, path_with_d)| {
-                let mut segments = Vec::new();
-                let mut start_ts = 0f64;
-                while start_ts + duration_sec < path_with_d.duration {
-                    segments.push((path_index as u32, start_ts as f32));
-                    start_ts += duration_sec;
-                }
-                if pad_last_segment && start_ts < path_with_d.duration {
-                    segments.push((path_index as u32, start_ts as f32));
-                }
-                Ok(segments)
-            })
-            .collect::<anyhow::Result<Vec<_>>>()
-            .w()?
-            .concat();
+        let mut segments = file_segments(paths, duration_sec, pad_last_segment);
         if let Some(seed) = seed {
             let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
             segments.shuffle(&mut rng);
@@ -549,60 +954,41 @@ impl DatasetIter {
         } else {
             segments
         };
+        // Consecutive segments of a file are only worth keeping on the same thread when they
+        // are not shuffled, the channel length is raised as documented in `seq`.
+        let (block_len, channel_len) = match seed {
+            None => (SEQUENTIAL_BLOCK_LEN, channel_len_per_thread.max(SEQUENTIAL_BLOCK_LEN)),
+            Some(_) => (1, channel_len_per_thread),
+        };
         let pm = {
             let paths = paths.clone();
             par_map::par_map(
                 segments,
                 num_threads,
-                channel_len_per_thread,
-                move |segment_index, (file_index, start_time)| {
+                channel_len,
+                ordered,
+                block_len,
+                |_thread_idx| ReaderCache::new(),
+                move |cache, segment_index, &(file_index, index_in_file)| {
                     let now = std::time::Instant::now();
-                    let file_index = *file_index as usize;
-                    let start_time = *start_time as f64;
-                    let (data, sample_rate, unpadded_len) = 'sample: {
-                        let mut reader = match audio::FileReader::new(&paths[file_index].path) {
-                            Ok(reader) => reader,
-                            Err(err) => break 'sample (Err(err), 0, 0),
-                        };
-                        let (data, unpadded_len) =
-                            match reader.decode(start_time, duration_sec, pad_last_segment) {
-                                Ok(data) => data,
-                                Err(err) => break 'sample (Err(err), 0, 0),
-                            };
-                        let sample_rate = reader.sample_rate() as usize;
-                        match target_sample_rate {
-                            None => (Ok(data), sample_rate, unpadded_len),
-                            Some(target_sample_rate) => {
-                                if target_sample_rate != sample_rate {
-                                    let is_unpadded = unpadded_len == data[0].len();
-                                    let data =
-                                        audio::resample2(&data, sample_rate, target_sample_rate);
-                                    match data {
-                                        Ok(data) => {
-                                            let unpadded_len = if is_unpadded {
-                                                data[0].len()
-                                            } else {
-                                                unpadded_len * target_sample_rate / sample_rate
-                                            };
-                                            (Ok(data), target_sample_rate, unpadded_len)
-                                        }
-                                        Err(err) => break 'sample (Err(err), 0, 0),
-                                    }
-                                } else {
-                                    (Ok(data), sample_rate, unpadded_len)
-                                }
-                            }
-                        }
-                    };
-                    let sample = Sample {
-                        sample_index: segment_index as u64 * step_by + skip,
+                    let file_index = file_index as usize;
+                    let start_time = index_in_file as f64 * duration_sec;
+                    let segment = cache.decode_segment(
+                        &source_options,
+                        file_index,
+                        &paths[file_index].path,
+                        SegmentStart::Index(index_in_file),
+                        duration_sec,
+                        pad_last_segment,
+                        target_sample_rate,
+                    );
+                    let sample = Sample::new(
+                        segment_index as u64 * step_by + skip,
                         file_index,
                         start_time,
-                        sample_rate,
-                        data,
-                        unpadded_len,
-                        gen_duration: now.elapsed().as_secs_f64(),
-                    };
+                        segment,
+                        now.elapsed().as_secs_f64(),
+                    );
                     match f.as_ref() {
                         None => SampleOrObject::Sample(sample),
                         Some(f) => {
@@ -630,6 +1016,26 @@ impl DatasetIter {
         self.pm.buffered_lens()
     }
 
+    /// Stops the worker threads and waits for them to exit, the iteration ends after this.
+    fn close(&mut self, py: Python) {
+        py.detach(|| self.pm.close())
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
+    fn __exit__(
+        &mut self,
+        py: Python,
+        _exc_type: Option<Bound<'_, PyAny>>,
+        _exc_value: Option<Bound<'_, PyAny>>,
+        _traceback: Option<Bound<'_, PyAny>>,
+    ) {
+        self.close(py)
+    }
+
     fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
         slf
     }
@@ -638,7 +1044,7 @@ impl DatasetIter {
         loop {
             let sample = py.detach(|| self.pm.next());
             let sample = match sample {
-                Some(sample) => sample,
+                Some(sample) => sample.w()?,
                 None => return Ok(None),
             };
             let sample = match sample {
@@ -659,3 +1065,90 @@ impl DatasetIter {
         }
     }
 }
+
+impl Drop for DatasetIter {
+    fn drop(&mut self) {
+        // The GIL has to be released while joining the threads as the workers may need it to
+        // call `f`. When the interpreter is shutting down, the threads are only signaled.
+        let closed = Python::try_attach(|py| py.detach(|| self.pm.close()));
+        if closed.is_none() {
+            self.pm.detach()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn sequential_segments_reuse_readers() -> anyhow::Result<()> {
+        let dir = TempDir::new("dataset")?;
+        let path = dir.path().join("ramp.wav");
+        let samples: Vec<f32> = (0..16000 * 10 + 3200).map(|i| (i % 1000) as f32 / 2000.).collect();
+        let mut file = std::fs::File::create(&path)?;
+        crate::wav::write_mono(&mut file, &samples, 16000, &Default::default())?;
+        drop(file);
+        let path = path.to_string_lossy().to_string();
+        let expected: numpy::ndarray::Array2<f32> =
+            audio::FileReader::new(&path)?.decode_all_as()?;
+        let paths: Paths = Arc::new(vec![PathWithDuration { path, duration: 10.2 }]);
+        let segments = file_segments(&paths, 0.5, false);
+        assert_eq!(segments.len(), 20);
+
+        let source_options = Arc::new(SourceOptions {
+            open_options: Default::default(),
+            dtype: audio::Dtype::Float32,
+            cache_dir: None,
+            packed: None,
+        });
+        // Returns the total number of seeks over all the worker threads.
+        let run = |block_len: usize| -> anyhow::Result<usize> {
+            let paths = paths.clone();
+            let source_options = source_options.clone();
+            let pm = par_map::par_map(
+                segments.clone(),
+                4,
+                block_len,
+                true,
+                block_len,
+                |thread_idx| (thread_idx, ReaderCache::new()),
+                move |(thread_idx, cache), _, &(file_index, index_in_file)| {
+                    let segment = cache.decode_segment(
+                        &source_options,
+                        file_index as usize,
+                        &paths[file_index as usize].path,
+                        SegmentStart::Index(index_in_file),
+                        0.5,
+                        false,
+                        None,
+                    );
+                    let num_seeks = match cache.readers.front() {
+                        Some((_, Source::Reader(r))) => r.num_seeks(),
+                        _ => 0,
+                    };
+                    (*thread_idx, segment, num_seeks)
+                },
+            );
+            let mut seeks_per_thread = [0; 4];
+            for (index, res) in pm.enumerate() {
+                let (thread_idx, segment, num_seeks) = res?;
+                let data = match segment?.data {
+                    audio::Pcm::Float32(data) => data,
+                    _ => anyhow::bail!("unexpected dtype"),
+                };
+                let start = index * 8000;
+                assert_eq!(data, expected.slice(numpy::ndarray::s![.., start..start + 8000]));
+                seeks_per_thread[thread_idx] = usize::max(seeks_per_thread[thread_idx], num_seeks);
+            }
+            Ok(seeks_per_thread.iter().sum())
+        };
+        // Each thread seeks once per block and then decodes the following segments of the block
+        // from where the previous one ended.
+        assert_eq!(run(SEQUENTIAL_BLOCK_LEN)?, 20 / SEQUENTIAL_BLOCK_LEN);
+        // Without blocks, the segments decoded by a thread are 2s apart which requires seeking.
+        assert_eq!(run(1)?, 20);
+        Ok(())
+    }
+}
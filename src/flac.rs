@@ -0,0 +1,453 @@
+// A simple flac encoder using fixed predictors and rice coded residuals.
+// https://www.rfc-editor.org/rfc/rfc9639.html
+use anyhow::Result;
+use md5::Digest;
+
+const MAX_CHANNELS: usize = 8;
+const MAX_FIXED_ORDER: usize = 4;
+// One seek point is added every SEEK_POINT_INTERVAL_SEC seconds, as done by the reference encoder.
+const SEEK_POINT_INTERVAL_SEC: u64 = 10;
+
+const fn crc8_table() -> [u8; 256] {
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u8;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const fn crc16_table() -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC8_TABLE: [u8; 256] = crc8_table();
+static CRC16_TABLE: [u16; 256] = crc16_table();
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &b| CRC8_TABLE[(crc ^ b) as usize])
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &b| (crc << 8) ^ CRC16_TABLE[((crc >> 8) as u8 ^ b) as usize])
+}
+
+/// The parameters derived from the compression level, loosely following the reference encoder.
+#[derive(Debug, Clone, Copy)]
+struct Params {
+    block_size: usize,
+    max_partition_order: u32,
+    stereo_decorrelation: bool,
+}
+
+impl Params {
+    fn new(compression_level: u32) -> Result<Self> {
+        let params = match compression_level {
+            0 => Self { block_size: 1152, max_partition_order: 3, stereo_decorrelation: false },
+            1 | 2 => Self { block_size: 1152, max_partition_order: 3, stereo_decorrelation: true },
+            3..=5 => Self { block_size: 4096, max_partition_order: 5, stereo_decorrelation: true },
+            6..=8 => Self { block_size: 4096, max_partition_order: 8, stereo_decorrelation: true },
+            _ => anyhow::bail!("unsupported compression level {compression_level}, expected 0-8"),
+        };
+        Ok(params)
+    }
+}
+
+struct BitWriter {
+    data: Vec<u8>,
+    acc: u64,
+    n_bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self { data: Vec::new(), acc: 0, n_bits: 0 }
+    }
+
+    fn write(&mut self, v: u64, n_bits: u32) {
+        if n_bits == 0 {
+            return;
+        }
+        let v = if n_bits == 64 { v } else { v & ((1 << n_bits) - 1) };
+        if self.n_bits + n_bits > 64 {
+            // Split the write so that the accumulator never overflows.
+            let hi = n_bits - 32;
+            self.write(v >> 32, hi);
+            self.write(v & 0xFFFF_FFFF, 32);
+            return;
+        }
+        self.acc = if n_bits == 64 { v } else { (self.acc << n_bits) | v };
+        self.n_bits += n_bits;
+        while self.n_bits >= 8 {
+            self.n_bits -= 8;
+            self.data.push((self.acc >> self.n_bits) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, v: i64, n_bits: u32) {
+        self.write(v as u64, n_bits)
+    }
+
+    fn write_unary(&mut self, q: u64) {
+        let mut q = q;
+        while q >= 32 {
+            self.write(0, 32);
+            q -= 32;
+        }
+        self.write(1, q as u32 + 1);
+    }
+
+    fn align(&mut self) {
+        if self.n_bits > 0 {
+            self.write(0, 8 - self.n_bits)
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// The encoding chosen for a subframe together with its size in bits.
+enum Subframe {
+    Constant(i64),
+    Verbatim,
+    Fixed { order: usize, partition_order: u32, rice_params: Vec<u32> },
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn fixed_residuals(samples: &[i64], order: usize) -> Vec<i64> {
+    let s = samples;
+    (order..s.len())
+        .map(|i| match order {
+            0 => s[i],
+            1 => s[i] - s[i - 1],
+            2 => s[i] - 2 * s[i - 1] + s[i - 2],
+            3 => s[i] - 3 * s[i - 1] + 3 * s[i - 2] - s[i - 3],
+            _ => s[i] - 4 * s[i - 1] + 6 * s[i - 2] - 4 * s[i - 3] + s[i - 4],
+        })
+        .collect()
+}
+
+// Returns the best rice parameter for a partition together with the resulting size in bits.
+fn best_rice_param(residuals: &[i64]) -> (u32, u64) {
+    let n = residuals.len() as u64;
+    let sum: u64 = residuals.iter().map(|&r| zigzag(r)).sum();
+    let cost = |k: u32| n * (k as u64 + 1) + residuals.iter().map(|&r| zigzag(r) >> k).sum::<u64>();
+    let estimate = if n == 0 || sum <= n { 0 } else { (sum / n).ilog2().min(30) };
+    let mut best = (estimate, cost(estimate));
+    for k in [estimate.saturating_sub(1), (estimate + 1).min(30)] {
+        let c = cost(k);
+        if c < best.1 {
+            best = (k, c)
+        }
+    }
+    best
+}
+
+// Returns the partition order, the rice parameters and the size in bits of the residual section.
+fn best_partitioning(
+    residuals: &[i64],
+    block_size: usize,
+    order: usize,
+    max_partition_order: u32,
+) -> (u32, Vec<u32>, u64) {
+    let mut best: Option<(u32, Vec<u32>, u64)> = None;
+    for partition_order in 0..=max_partition_order {
+        let n_partitions = 1usize << partition_order;
+        if !block_size.is_multiple_of(n_partitions) || block_size / n_partitions <= order {
+            break;
+        }
+        let partition_len = block_size / n_partitions;
+        let mut params = Vec::with_capacity(n_partitions);
+        // coding method and partition order
+        let mut bits = 2 + 4;
+        let mut start = 0;
+        for p in 0..n_partitions {
+            let len = if p == 0 { partition_len - order } else { partition_len };
+            let (k, cost) = best_rice_param(&residuals[start..start + len]);
+            params.push(k);
+            bits += 5 + cost;
+            start += len;
+        }
+        if best.as_ref().is_none_or(|b| bits < b.2) {
+            best = Some((partition_order, params, bits))
+        }
+    }
+    // Partition order 0 is always valid as block_size > order.
+    best.unwrap()
+}
+
+fn choose_subframe(samples: &[i64], bps: u32, max_partition_order: u32) -> (Subframe, u64) {
+    let header_bits = 8;
+    if samples.iter().all(|&s| s == samples[0]) {
+        return (Subframe::Constant(samples[0]), header_bits + bps as u64);
+    }
+    let mut best = (Subframe::Verbatim, header_bits + bps as u64 * samples.len() as u64);
+    for order in 0..=MAX_FIXED_ORDER.min(samples.len() - 1) {
+        let residuals = fixed_residuals(samples, order);
+        let (partition_order, rice_params, residual_bits) =
+            best_partitioning(&residuals, samples.len(), order, max_partition_order);
+        let bits = header_bits + bps as u64 * order as u64 + residual_bits;
+        if bits < best.1 {
+            best = (Subframe::Fixed { order, partition_order, rice_params }, bits)
+        }
+    }
+    best
+}
+
+fn write_subframe(bw: &mut BitWriter, samples: &[i64], bps: u32, subframe: &Subframe) {
+    match subframe {
+        Subframe::Constant(v) => {
+            bw.write(0b0000_0000, 8);
+            bw.write_signed(*v, bps);
+        }
+        Subframe::Verbatim => {
+            bw.write(0b0000_0010, 8);
+            for &s in samples.iter() {
+                bw.write_signed(s, bps)
+            }
+        }
+        Subframe::Fixed { order, partition_order, rice_params } => {
+            bw.write((0b001000 | *order as u64) << 1, 8);
+            for &s in samples[..*order].iter() {
+                bw.write_signed(s, bps)
+            }
+            let residuals = fixed_residuals(samples, *order);
+            // Use the 5 bits rice parameters only when necessary.
+            let wide = rice_params.iter().any(|&k| k >= 15);
+            bw.write(if wide { 1 } else { 0 }, 2);
+            bw.write(*partition_order as u64, 4);
+            let partition_len = samples.len() >> partition_order;
+            let mut start = 0;
+            for (p, &k) in rice_params.iter().enumerate() {
+                let len = if p == 0 { partition_len - order } else { partition_len };
+                bw.write(k as u64, if wide { 5 } else { 4 });
+                for &r in residuals[start..start + len].iter() {
+                    let u = zigzag(r);
+                    bw.write_unary(u >> k);
+                    bw.write(u, k);
+                }
+                start += len;
+            }
+        }
+    }
+}
+
+fn write_utf8_number(bw: &mut BitWriter, v: u64) {
+    let n_bytes = match v {
+        0..0x80 => {
+            bw.write(v, 8);
+            return;
+        }
+        0x80..0x800 => 2,
+        0x800..0x10000 => 3,
+        0x10000..0x200000 => 4,
+        0x200000..0x4000000 => 5,
+        _ => 6,
+    };
+    let prefix = (0xFF00u64 >> n_bytes) & 0xFF;
+    bw.write(prefix | (v >> (6 * (n_bytes - 1))), 8);
+    for i in (0..n_bytes - 1).rev() {
+        bw.write(0x80 | ((v >> (6 * i)) & 0x3F), 8);
+    }
+}
+
+fn encode_frame(channels: &[Vec<i64>], frame_idx: u64, bps: u32, params: &Params) -> Vec<u8> {
+    let block_size = channels[0].len();
+    let mut bw = BitWriter::new();
+
+    // Pick the channel assignment.
+    let max_po = params.max_partition_order;
+    let (assignment, subframes) = if channels.len() == 2 && params.stereo_decorrelation {
+        let (l, r) = (&channels[0], &channels[1]);
+        let side = l.iter().zip(r.iter()).map(|(l, r)| l - r).collect::<Vec<_>>();
+        let mid = l.iter().zip(r.iter()).map(|(l, r)| (l + r) >> 1).collect::<Vec<_>>();
+        let (sl, bl) = choose_subframe(l, bps, max_po);
+        let (sr, br) = choose_subframe(r, bps, max_po);
+        let (ss, bs) = choose_subframe(&side, bps + 1, max_po);
+        let (sm, bm) = choose_subframe(&mid, bps, max_po);
+        let costs = [bl + br, bl + bs, bs + br, bm + bs];
+        let best = (0..4).min_by_key(|&i| costs[i]).unwrap_or(0);
+        let (l, r) = (l.clone(), r.clone());
+        match best {
+            0 => (0b0001, vec![(l, bps, sl), (r, bps, sr)]),
+            1 => (0b1000, vec![(l, bps, sl), (side, bps + 1, ss)]),
+            2 => (0b1001, vec![(side, bps + 1, ss), (r, bps, sr)]),
+            _ => (0b1010, vec![(mid, bps, sm), (side, bps + 1, ss)]),
+        }
+    } else {
+        let subframes = channels
+            .iter()
+            .map(|c| {
+                let (s, _) = choose_subframe(c, bps, max_po);
+                (c.clone(), bps, s)
+            })
+            .collect::<Vec<_>>();
+        (channels.len() as u64 - 1, subframes)
+    };
+
+    // Frame header
+    bw.write(0b1111_1111_1111_1000, 16);
+    let block_size_code = match block_size {
+        1152 => 0b0011,
+        4096 => 0b1100,
+        1..=256 => 0b0110,
+        _ => 0b0111,
+    };
+    // The sample rate is read from the STREAMINFO block.
+    bw.write(block_size_code, 4);
+    bw.write(0b0000, 4);
+    bw.write(assignment, 4);
+    let bps_code = if bps == 16 { 0b100 } else { 0b110 };
+    bw.write(bps_code, 3);
+    bw.write(0, 1);
+    // With a fixed block size, the frame number is written rather than the sample number.
+    write_utf8_number(&mut bw, frame_idx);
+    match block_size_code {
+        0b0110 => bw.write(block_size as u64 - 1, 8),
+        0b0111 => bw.write(block_size as u64 - 1, 16),
+        _ => {}
+    }
+    let crc = crc8(bw.bytes());
+    bw.write(crc as u64, 8);
+
+    for (samples, bps, subframe) in subframes.iter() {
+        write_subframe(&mut bw, samples, *bps, subframe)
+    }
+    bw.align();
+    let crc = crc16(bw.bytes());
+    bw.write(crc as u64, 16);
+    bw.data
+}
+
+/// Encodes some pcm data as a flac stream, `pcm` contains one slice per channel and all the
+/// slices must have the same, non-zero, length. The samples are expected to be between -1 and 1
+/// and are quantized to `bits_per_sample` which can either be 16 or 24.
+/// The `compression_level` goes from 0 (fastest) to 8 (smallest output).
+pub fn write<W: std::io::Write>(
+    w: &mut W,
+    pcm: &[&[f32]],
+    sample_rate: u32,
+    bits_per_sample: u32,
+    compression_level: u32,
+) -> Result<()> {
+    let params = Params::new(compression_level)?;
+    let n_channels = pcm.len();
+    if n_channels == 0 || n_channels > MAX_CHANNELS {
+        anyhow::bail!("flac supports between 1 and {MAX_CHANNELS} channels, got {n_channels}")
+    }
+    if bits_per_sample != 16 && bits_per_sample != 24 {
+        anyhow::bail!("unsupported bits per sample {bits_per_sample}, expected 16 or 24")
+    }
+    if sample_rate == 0 || sample_rate >= 1 << 20 {
+        anyhow::bail!("unsupported sample rate {sample_rate}")
+    }
+    let n_samples = pcm[0].len();
+    if pcm.iter().any(|c| c.len() != n_samples) {
+        anyhow::bail!("all channels must have the same number of samples")
+    }
+    // Decoders such as symphonia cannot probe a stream that does not contain any frame.
+    if n_samples == 0 {
+        anyhow::bail!("cannot encode an empty flac stream")
+    }
+    if n_samples as u64 >= 1 << 36 {
+        anyhow::bail!("too many samples for a flac stream {n_samples}")
+    }
+
+    let max_value = ((1i64 << (bits_per_sample - 1)) - 1) as f32;
+    let quantize = |v: f32| (v.clamp(-1.0, 1.0) * max_value) as i64;
+
+    // The MD5 signature is computed on the interleaved little endian samples.
+    let mut md5 = md5::Md5::new();
+    let bytes_per_sample = bits_per_sample as usize / 8;
+    let mut buf = Vec::with_capacity(n_channels * bytes_per_sample * params.block_size);
+
+    let mut frames = Vec::new();
+    let mut seek_points = Vec::new();
+    let mut next_seek_sample = 0u64;
+    let mut offset = 0u64;
+    for (frame_idx, start) in (0..n_samples).step_by(params.block_size).enumerate() {
+        let end = usize::min(start + params.block_size, n_samples);
+        let channels = pcm
+            .iter()
+            .map(|c| c[start..end].iter().map(|&v| quantize(v)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        buf.clear();
+        for i in 0..end - start {
+            for c in channels.iter() {
+                buf.extend_from_slice(&c[i].to_le_bytes()[..bytes_per_sample])
+            }
+        }
+        md5.update(&buf);
+
+        let frame = encode_frame(&channels, frame_idx as u64, bits_per_sample, &params);
+        if start as u64 >= next_seek_sample {
+            seek_points.push((start as u64, offset, (end - start) as u16));
+            next_seek_sample = start as u64 + SEEK_POINT_INTERVAL_SEC * sample_rate as u64;
+        }
+        offset += frame.len() as u64;
+        frames.push(frame);
+    }
+
+    let min_frame_size = frames.iter().map(|f| f.len()).min().unwrap_or(0) as u64;
+    let max_frame_size = frames.iter().map(|f| f.len()).max().unwrap_or(0) as u64;
+    let block_size = params.block_size as u64;
+
+    w.write_all(b"fLaC")?;
+
+    // STREAMINFO
+    let mut bw = BitWriter::new();
+    bw.write(0, 1); // not the last metadata block
+    bw.write(0, 7); // STREAMINFO
+    bw.write(34, 24);
+    bw.write(block_size, 16);
+    bw.write(block_size, 16);
+    bw.write(min_frame_size, 24);
+    bw.write(max_frame_size, 24);
+    bw.write(sample_rate as u64, 20);
+    bw.write(n_channels as u64 - 1, 3);
+    bw.write(bits_per_sample as u64 - 1, 5);
+    bw.write(n_samples as u64, 36);
+    w.write_all(bw.bytes())?;
+    w.write_all(&md5.finalize())?;
+
+    // SEEKTABLE
+    let mut bw = BitWriter::new();
+    bw.write(1, 1); // last metadata block
+    bw.write(3, 7); // SEEKTABLE
+    bw.write(18 * seek_points.len() as u64, 24);
+    for (sample, offset, n_samples) in seek_points.iter() {
+        bw.write(*sample, 64);
+        bw.write(*offset, 64);
+        bw.write(*n_samples as u64, 16);
+    }
+    w.write_all(bw.bytes())?;
+
+    for frame in frames.iter() {
+        w.write_all(frame)?
+    }
+    Ok(())
+}
@@ -1,8 +1,17 @@
 #![allow(clippy::useless_conversion)]
+mod archive;
 mod audio;
 mod dataset;
+mod flac;
+mod manifest;
 mod opus;
+mod packed;
 mod par_map;
+mod pcm;
+mod pcm_cache;
+mod pyfile;
+#[cfg(test)]
+mod test_utils;
 mod wav;
 
 use pyo3::prelude::*;
@@ -40,6 +49,58 @@ macro_rules! py_bail {
     };
 }
 
+/// A track can be selected either by its index or by its language.
+#[derive(FromPyObject)]
+enum Track {
+    Index(usize),
+    Language(String),
+}
+
+impl From<Track> for audio::TrackSelector {
+    fn from(value: Track) -> Self {
+        match value {
+            Track::Index(index) => Self::Index(index),
+            Track::Language(language) => Self::Language(language),
+        }
+    }
+}
+
+/// Opens an audio reader from a path, some bytes, or a python file-like object supporting `read`
+/// and `seek`. Also returns the path, or a placeholder name, to be used in error messages.
+fn open_reader(
+    source: &Bound<'_, PyAny>,
+    format_hint: Option<&str>,
+    opts: &audio::OpenOptions,
+) -> PyResult<(audio::FileReader, std::path::PathBuf)> {
+    let (src, path) = open_source(source)?;
+    // Paths use the extension of the file, or of the archive member, as a format hint.
+    let extension = match archive::Member::parse(&path) {
+        Some(member) => std::path::Path::new(member.name()).extension(),
+        None => path.extension(),
+    };
+    let format_hint = format_hint.or(extension.and_then(|v| v.to_str()));
+    let reader = audio::FileReader::from_source(src, format_hint, opts).w_f(&path)?;
+    Ok((reader, path))
+}
+
+/// Opens the data from `source`, either some bytes, a python file-like object that supports
+/// `read` and `seek`, or a path which can refer to an archive member.
+fn open_source(
+    source: &Bound<'_, PyAny>,
+) -> PyResult<(Box<dyn symphonia::core::io::MediaSource>, std::path::PathBuf)> {
+    if let Ok(bytes) = source.cast::<pyo3::types::PyBytes>() {
+        let src = std::io::Cursor::new(bytes.as_bytes().to_vec());
+        Ok((Box::new(src), std::path::PathBuf::from("<bytes>")))
+    } else if pyfile::PyFileLike::is_readable(source) {
+        let src = pyfile::PyFileLike::new(source.clone().unbind());
+        Ok((Box::new(src), std::path::PathBuf::from("<file-like>")))
+    } else {
+        let path: std::path::PathBuf = source.extract()?;
+        let src = archive::open(&path).w_f(&path)?;
+        Ok((src, path))
+    }
+}
+
 #[pyclass]
 struct FileReader {
     inner: audio::FileReader,
@@ -48,10 +109,42 @@ struct FileReader {
 
 #[pymethods]
 impl FileReader {
+    /// Opens an audio file. When `exact_duration` is set, the duration is computed by scanning
+    /// the whole file rather than relying on the frame count declared in its headers. This scan
+    /// is also used when the headers do not contain the frame count.
+    /// `track` selects the track to decode for files with multiple tracks, it can either be an
+    /// index in the `tracks` list or a language such as "eng".
+    /// `path` can also be a python file-like object that supports `read` and `seek`.
     #[new]
-    fn new(path: std::path::PathBuf) -> PyResult<Self> {
-        let inner = audio::FileReader::new(&path).w_f(&path)?;
-        Ok(Self { inner, path: path.to_path_buf() })
+    #[pyo3(signature = (path, *, exact_duration=false, track=None))]
+    fn new(path: &Bound<'_, PyAny>, exact_duration: bool, track: Option<Track>) -> PyResult<Self> {
+        let opts = audio::OpenOptions {
+            exact_duration,
+            track: track.map(|t| t.into()),
+            ..Default::default()
+        };
+        let (inner, path) = open_reader(path, None, &opts)?;
+        Ok(Self { inner, path })
+    }
+
+    /// Creates a reader on some in-memory encoded audio data. `format_hint` is an optional file
+    /// extension describing the format of the data, e.g. "mp3".
+    #[staticmethod]
+    #[pyo3(signature = (data, *, format_hint=None, exact_duration=false, track=None))]
+    fn from_bytes(
+        data: Vec<u8>,
+        format_hint: Option<&str>,
+        exact_duration: bool,
+        track: Option<Track>,
+    ) -> PyResult<Self> {
+        let opts = audio::OpenOptions {
+            exact_duration,
+            track: track.map(|t| t.into()),
+            ..Default::default()
+        };
+        let path = std::path::PathBuf::from("<bytes>");
+        let inner = audio::FileReader::from_bytes(data, format_hint, &opts).w_f(&path)?;
+        Ok(Self { inner, path })
     }
 
     fn __str__(&self) -> String {
@@ -76,15 +169,90 @@ impl FileReader {
         self.inner.channels()
     }
 
+    /// The start time in seconds of the data returned by the last decode call, this can differ
+    /// from the requested start time when using coarse seeking.
+    #[getter]
+    fn last_start_sec(&self) -> f64 {
+        self.inner.last_start_sec()
+    }
+
+    /// The number of times the decode calls had to seek, decoding a segment that starts at or
+    /// shortly after the end of the previous one resumes from there without seeking.
+    #[getter]
+    fn num_seeks(&self) -> usize {
+        self.inner.num_seeks()
+    }
+
+    /// The list of tracks available in the file. Each track is described by a dictionary with the
+    /// following keys: codec, channels, sample_rate, and language.
+    #[getter]
+    fn tracks<'a>(&self, py: Python<'a>) -> PyResult<Vec<Bound<'a, pyo3::types::PyDict>>> {
+        self.inner
+            .tracks()
+            .iter()
+            .map(|t| {
+                let dict = pyo3::types::PyDict::new(py);
+                dict.set_item("codec", t.codec)?;
+                dict.set_item("channels", t.channels)?;
+                dict.set_item("sample_rate", t.sample_rate)?;
+                dict.set_item("language", &t.language)?;
+                Ok(dict)
+            })
+            .collect()
+    }
+
+    /// The tags found in the file as a dictionary with the following keys: title, artist, album,
+    /// language, comments, and has_cover.
+    #[getter]
+    fn metadata<'a>(&self, py: Python<'a>) -> PyResult<Bound<'a, pyo3::types::PyDict>> {
+        metadata_dict(py, self.inner.metadata())
+    }
+
     /// Decodes the audio data from `start_sec` to `start_sec + duration_sec` and return the PCM
     /// data as a two dimensional numpy array. The first dimension is the channel, the second one
     /// is time.
     /// If the end of the file is reached, the decoding stops and the already decoded data is
     /// returned.
-    fn decode(&mut self, start_sec: f64, duration_sec: f64, py: Python) -> PyResult<Py<PyAny>> {
+    /// `dtype` can be "int16", "int32", "float32", or "float64", integer samples are not
+    /// converted through float values.
+    /// `seek_mode` can be "accurate" or "coarse", the latter avoids decoding or scanning from an
+    /// earlier position but the data may start slightly off `start_sec`, see `last_start_sec`.
+    #[pyo3(signature = (start_sec, duration_sec, *, dtype="float32", seek_mode="accurate"))]
+    fn decode(
+        &mut self,
+        start_sec: f64,
+        duration_sec: f64,
+        dtype: &str,
+        seek_mode: &str,
+        py: Python,
+    ) -> PyResult<Py<PyAny>> {
+        let dtype = dtype.parse::<audio::Dtype>().w()?;
+        self.inner.set_seek_mode(seek_mode.parse().w()?);
         let (data, _unpadded_len) =
-            self.inner.decode(start_sec, duration_sec, false).w_f(&self.path)?;
-        Ok(numpy::PyArray2::from_vec2(py, &data)?.into_any().unbind())
+            self.inner.decode_dtype(start_sec, duration_sec, false, dtype).w_f(&self.path)?;
+        Ok(pcm_to_py(py, data)?.unbind())
+    }
+
+    /// Decodes `num_samples` samples per channel starting from sample `start_sample` and return
+    /// the PCM data as a two dimensional numpy array. Positions are expressed in samples at the
+    /// file sample rate so the returned array has exactly `num_samples` columns, unless the end of
+    /// the file is reached in which case the already decoded data is returned.
+    #[pyo3(signature = (start_sample, num_samples, *, dtype="float32", seek_mode="accurate"))]
+    fn decode_samples(
+        &mut self,
+        start_sample: u64,
+        num_samples: usize,
+        dtype: &str,
+        seek_mode: &str,
+        py: Python,
+    ) -> PyResult<Py<PyAny>> {
+        let dtype = dtype.parse::<audio::Dtype>().w()?;
+        self.inner.set_seek_mode(seek_mode.parse().w()?);
+        let (data, _unpadded_len) = self
+            .inner
+            .decode_samples_dtype(start_sample, num_samples, false, dtype)
+            .w_f(&self.path)?;
+        Ok(pcm_to_py(py, data)?.unbind())
     }
 
     /// Decodes the audio data from `start_sec` to `start_sec + duration_sec` and return the PCM
@@ -92,36 +260,189 @@ impl FileReader {
     /// is time.
     /// If the end of the file is reached, the array is padded with zeros so that its length is
     /// still matching `duration_sec`.
+    #[pyo3(signature = (start_sec, duration_sec, *, dtype="float32", seek_mode="accurate"))]
     fn decode_with_padding<'a>(
         &mut self,
         start_sec: f64,
         duration_sec: f64,
+        dtype: &str,
+        seek_mode: &str,
         py: Python<'a>,
     ) -> PyResult<(Bound<'a, PyAny>, usize)> {
+        let dtype = dtype.parse::<audio::Dtype>().w()?;
+        self.inner.set_seek_mode(seek_mode.parse().w()?);
         let (data, unpadded_len) =
-            self.inner.decode(start_sec, duration_sec, true).w_f(&self.path)?;
-        let data = numpy::PyArray2::from_vec2(py, &data)?.into_any();
-        Ok((data, unpadded_len))
+            self.inner.decode_dtype(start_sec, duration_sec, true, dtype).w_f(&self.path)?;
+        Ok((pcm_to_py(py, data)?, unpadded_len))
+    }
+
+    /// Decodes the audio data starting at `start_sec` into `out`, a preallocated and writable
+    /// float32 array with shape `(channels, samples)` using a C-contiguous layout. The number of
+    /// samples written per channel is returned, this is smaller than the array length when the
+    /// end of the file is reached in which case the remaining values are left untouched.
+    #[pyo3(signature = (out, start_sec))]
+    fn decode_into(
+        &mut self,
+        mut out: numpy::PyReadwriteArray2<f32>,
+        start_sec: f64,
+    ) -> PyResult<usize> {
+        let (channels, _len) = out.as_array().dim();
+        if channels != self.inner.channels() {
+            py_bail!("expected {} channels in out, got {channels}", self.inner.channels())
+        }
+        let out = match out.as_slice_mut() {
+            Ok(out) => out,
+            Err(_) => py_bail!("out is not a C-contiguous array"),
+        };
+        self.inner.decode_into(start_sec, out).w_f(&self.path)
+    }
+
+    /// Returns an iterator over windows of `chunk_sec` seconds, each window starting `hop_sec`
+    /// seconds after the previous one, `hop_sec` defaults to `chunk_sec`. The file is decoded
+    /// sequentially a single time, overlapping samples are reused rather than decoded again. The
+    /// last window is shorter when the end of the file is reached.
+    #[pyo3(signature = (chunk_sec, hop_sec=None))]
+    fn chunks(
+        slf: Bound<'_, Self>,
+        chunk_sec: f64,
+        hop_sec: Option<f64>,
+    ) -> PyResult<FileReaderChunks> {
+        let sample_rate = slf.borrow().inner.sample_rate() as f64;
+        let chunk_len = (chunk_sec * sample_rate).round() as usize;
+        let hop_len = hop_sec.map_or(chunk_len, |hop_sec| (hop_sec * sample_rate).round() as usize);
+        if chunk_len == 0 || hop_len == 0 {
+            py_bail!("chunk_sec and hop_sec should be at least one sample long")
+        }
+        Ok(FileReaderChunks {
+            reader: slf.unbind(),
+            chunk_len,
+            hop_len,
+            next_start: 0,
+            overlap: None,
+            done: false,
+        })
     }
 
     /// Decodes the audio data for the whole file and return it as a two dimensional numpy array.
-    fn decode_all<'a>(&mut self, py: Python<'a>) -> PyResult<Bound<'a, PyAny>> {
-        let data = self.inner.decode_all().w_f(&self.path)?;
-        Ok(numpy::PyArray2::from_vec2(py, &data)?.into_any())
+    #[pyo3(signature = (*, dtype="float32"))]
+    fn decode_all<'a>(&mut self, dtype: &str, py: Python<'a>) -> PyResult<Bound<'a, PyAny>> {
+        let dtype = dtype.parse::<audio::Dtype>().w()?;
+        let data = self.inner.decode_all_dtype(dtype).w_f(&self.path)?;
+        pcm_to_py(py, data)
+    }
+}
+
+/// An iterator over fixed size windows of an audio file, see `FileReader.chunks`.
+#[pyclass]
+struct FileReaderChunks {
+    reader: Py<FileReader>,
+    chunk_len: usize,
+    hop_len: usize,
+    next_start: u64,
+    // The samples of the previous window that are also part of the next one.
+    overlap: Option<numpy::ndarray::Array2<f32>>,
+    done: bool,
+}
+
+#[pymethods]
+impl FileReaderChunks {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python) -> PyResult<Option<Py<PyAny>>> {
+        use numpy::ndarray::{s, Axis};
+
+        if self.done {
+            return Ok(None);
+        }
+        let mut reader = self.reader.bind(py).try_borrow_mut()?;
+        let reader = &mut *reader;
+        let overlap = self.overlap.take();
+        let overlap_len = overlap.as_ref().map_or(0, |o| o.ncols());
+        let to_read = self.chunk_len - overlap_len;
+        let (data, _unpadded_len) = reader
+            .inner
+            .decode_samples(self.next_start + overlap_len as u64, to_read, false)
+            .w_f(&reader.path)?;
+        if data.ncols() < to_read {
+            self.done = true;
+            if data.ncols() == 0 {
+                return Ok(None);
+            }
+        }
+        let data = match overlap {
+            None => data,
+            Some(overlap) => numpy::ndarray::concatenate(Axis(1), &[overlap.view(), data.view()])
+                .w_f(&reader.path)?,
+        };
+        if self.hop_len < self.chunk_len && !self.done {
+            self.overlap = Some(data.slice(s![.., self.hop_len..]).to_owned())
+        }
+        self.next_start += self.hop_len as u64;
+        Ok(Some(numpy::PyArray2::from_owned_array(py, data).into_any().unbind()))
     }
 }
 
+/// Converts some decoded pcm data to a two dimensional numpy array with the matching dtype, the
+/// numpy array takes ownership of the buffer so no copy is involved.
+fn pcm_to_py(py: Python<'_>, data: audio::Pcm) -> PyResult<Bound<'_, PyAny>> {
+    let data = match data {
+        audio::Pcm::Int16(data) => numpy::PyArray2::from_owned_array(py, data).into_any(),
+        audio::Pcm::Int32(data) => numpy::PyArray2::from_owned_array(py, data).into_any(),
+        audio::Pcm::Float32(data) => numpy::PyArray2::from_owned_array(py, data).into_any(),
+        audio::Pcm::Float64(data) => numpy::PyArray2::from_owned_array(py, data).into_any(),
+    };
+    Ok(data)
+}
+
+fn metadata_dict<'a>(
+    py: Python<'a>,
+    metadata: &audio::Metadata,
+) -> PyResult<Bound<'a, pyo3::types::PyDict>> {
+    let dict = pyo3::types::PyDict::new(py);
+    dict.set_item("title", &metadata.title)?;
+    dict.set_item("artist", &metadata.artist)?;
+    dict.set_item("album", &metadata.album)?;
+    dict.set_item("language", &metadata.language)?;
+    dict.set_item("comments", &metadata.comments)?;
+    dict.set_item("has_cover", metadata.has_cover)?;
+    Ok(dict)
+}
+
+/// Reads the tags of an audio file, e.g. ID3 tags, vorbis comments, RIFF INFO chunks or MP4
+/// metadata.
+///
+/// This returns a dictionary with the following keys: title, artist, album, language, comments,
+/// and has_cover. Missing tags are set to None.
+/// As for `read`, the input can also be an archive member path, some bytes, or a python file-like
+/// object that supports `read` and `seek`, `format_hint` then specifies the file extension.
+#[pyfunction]
+#[pyo3(signature = (filename, *, format_hint=None))]
+fn read_metadata<'a>(
+    filename: &Bound<'_, PyAny>,
+    format_hint: Option<&str>,
+    py: Python<'a>,
+) -> PyResult<Bound<'a, pyo3::types::PyDict>> {
+    let (reader, _filename) = open_reader(filename, format_hint, &Default::default())?;
+    metadata_dict(py, reader.metadata())
+}
+
 /// Returns the durations for the audio files passed as input.
 ///
 /// The input argument is a list of filenames. For each of these files, the duration in seconds is
 /// returned as a float, None is returned if the files cannot be open or properly read.
+/// When `exact_duration` is set, the durations are computed by scanning the audio packets rather
+/// than using the frame counts from the file headers.
 #[pyfunction]
-fn durations(filenames: Vec<std::path::PathBuf>) -> Vec<Option<f64>> {
+#[pyo3(signature = (filenames, *, exact_duration=false))]
+fn durations(filenames: Vec<std::path::PathBuf>, exact_duration: bool) -> Vec<Option<f64>> {
     use rayon::prelude::*;
+    let opts = audio::OpenOptions { exact_duration, ..Default::default() };
     filenames
         .par_iter()
         .map(|filename| {
-            let mut reader = audio::FileReader::new(filename).ok()?;
+            let mut reader = audio::FileReader::new_with_options(filename, &opts).ok()?;
             // Try to read a small portion of the file to check that it works.
             let (_data, _unpadded_len) = reader.decode(0., 0.1, false).ok()?;
             Some(reader.duration_sec())
@@ -133,65 +454,109 @@ fn durations(filenames: Vec<std::path::PathBuf>) -> Vec<Option<f64>> {
 ///
 /// The input argument is a filename. Its content is decoded the audio data for the whole file and
 /// return it as a two dimensional numpy array as well as the sample rate.
+/// Rather than a filename, the input can also be some bytes or a python file-like object that
+/// supports `read` and `seek`, in which case `format_hint` can be used to specify the file
+/// extension of the underlying data.
+/// For files with multiple tracks, `track` can be used to select the track to decode either via
+/// its index or its language.
+/// `dtype` can be "int16", "int32", "float32", or "float64". Integer samples are returned without
+/// going through float values unless some resampling is required.
 #[pyfunction]
-#[pyo3(signature = (filename, start_sec=None, duration_sec=None, sample_rate=None))]
+#[pyo3(signature = (filename, start_sec=None, duration_sec=None, sample_rate=None, *, format_hint=None, track=None, dtype="float32"))]
 fn read(
-    filename: std::path::PathBuf,
+    filename: &Bound<'_, PyAny>,
     start_sec: Option<f64>,
     duration_sec: Option<f64>,
     sample_rate: Option<u32>,
+    format_hint: Option<&str>,
+    track: Option<Track>,
+    dtype: &str,
 ) -> PyResult<(Py<PyAny>, u32)> {
-    let mut reader = audio::FileReader::new(&filename).w_f(&filename)?;
+    let dtype = dtype.parse::<audio::Dtype>().w()?;
+    let opts = audio::OpenOptions { track: track.map(|t| t.into()), ..Default::default() };
+    let (mut reader, filename) = open_reader(filename, format_hint, &opts)?;
+    let in_sr = reader.sample_rate();
+    let (decode_dtype, out_sr) = match sample_rate {
+        // Resampling operates on f32 values, the conversion to dtype happens afterwards.
+        Some(out_sr) if out_sr != in_sr => (audio::Dtype::Float32, out_sr),
+        _ => (dtype, in_sr),
+    };
     let data = match (start_sec, duration_sec) {
         (Some(start_sec), Some(duration_sec)) => {
-            reader.decode(start_sec, duration_sec, false).w_f(&filename)?.0
+            reader.decode_dtype(start_sec, duration_sec, false, decode_dtype).w_f(&filename)?.0
         }
-        (Some(start_sec), None) => reader.decode(start_sec, 1e9, false).w_f(&filename)?.0,
-        (None, Some(duration_sec)) => reader.decode(0., duration_sec, false).w_f(&filename)?.0,
-        (None, None) => reader.decode_all().w_f(&filename)?,
-    };
-    let (data, sample_rate) = match sample_rate {
-        Some(out_sr) => {
-            let in_sr = reader.sample_rate();
-            if in_sr != out_sr {
-                let data =
-                    audio::resample2(&data, in_sr as usize, out_sr as usize).w_f(&filename)?;
-                (data, out_sr)
-            } else {
-                (data, in_sr)
-            }
+        (Some(start_sec), None) => {
+            reader.decode_dtype(start_sec, 1e9, false, decode_dtype).w_f(&filename)?.0
         }
-        None => {
-            let sample_rate = reader.sample_rate();
-            (data, sample_rate)
+        (None, Some(duration_sec)) => {
+            reader.decode_dtype(0., duration_sec, false, decode_dtype).w_f(&filename)?.0
         }
+        (None, None) => reader.decode_all_dtype(decode_dtype).w_f(&filename)?,
     };
-    let data = Python::attach(|py| {
-        Ok::<_, PyErr>(numpy::PyArray2::from_vec2(py, &data)?.into_any().unbind())
-    })
-    .w_f(&filename)?;
-    Ok((data, sample_rate))
+    let data = match data {
+        audio::Pcm::Float32(data) if out_sr != in_sr => {
+            let data =
+                audio::resample2(data.view(), in_sr as usize, out_sr as usize).w_f(&filename)?;
+            audio::Pcm::from_f32(data, dtype)
+        }
+        data => data,
+    };
+    let data = Python::attach(|py| Ok::<_, PyErr>(pcm_to_py(py, data)?.unbind())).w_f(&filename)?;
+    Ok((data, out_sr))
 }
 
-/// Writes an audio file using the wav format based on pcm data from a numpy array.
-///
-/// This only supports a single channel at the moment so the input array data is expected to have a
-/// single dimension.
-#[pyfunction]
-#[pyo3(signature = (filename, data, sample_rate))]
-fn write_wav(
-    filename: std::path::PathBuf,
+/// Opens a writer on a path or on a python file-like object supporting `write`. Also returns the
+/// path, or a placeholder name, to be used in error messages.
+fn open_writer(
+    dest: &Bound<'_, PyAny>,
+) -> PyResult<(Box<dyn std::io::Write + Send>, std::path::PathBuf)> {
+    if pyfile::PyFileLike::is_writable(dest) {
+        let path = std::path::PathBuf::from("<file-like>");
+        let w = pyfile::PyFileLike::new(dest.clone().unbind());
+        Ok((Box::new(std::io::BufWriter::new(w)), path))
+    } else {
+        let path: std::path::PathBuf = dest.extract()?;
+        let w = std::fs::File::create(&path).w_f(&path)?;
+        Ok((Box::new(std::io::BufWriter::new(w)), path))
+    }
+}
+
+trait WriteSeek: std::io::Write + std::io::Seek + Send + Sync {}
+
+impl<T: std::io::Write + std::io::Seek + Send + Sync> WriteSeek for T {}
+
+/// Same as `open_writer` for the writers that need to seek back, file-like objects must then
+/// support `seek` in addition to `write`.
+fn open_seekable_writer(
+    dest: &Bound<'_, PyAny>,
+) -> PyResult<(std::io::BufWriter<Box<dyn WriteSeek>>, std::path::PathBuf)> {
+    if pyfile::PyFileLike::is_writable(dest) {
+        if !dest.hasattr("seek")? {
+            py_bail!("the file-like object does not support seek")
+        }
+        let path = std::path::PathBuf::from("<file-like>");
+        let w = pyfile::PyFileLike::new(dest.clone().unbind());
+        Ok((std::io::BufWriter::new(Box::new(w)), path))
+    } else {
+        let path: std::path::PathBuf = dest.extract()?;
+        let w = std::fs::File::create(&path).w_f(&path)?;
+        Ok((std::io::BufWriter::new(Box::new(w)), path))
+    }
+}
+
+fn write_wav_to<W: std::io::Write>(
+    w: &mut W,
     data: numpy::PyReadonlyArrayDyn<f32>,
     sample_rate: u32,
+    metadata: &wav::Metadata,
+    filename: &std::path::Path,
 ) -> PyResult<()> {
-    let w = std::fs::File::create(&filename).w_f(&filename)?;
-    let mut w = std::io::BufWriter::new(w);
     let data = data.as_array();
     match data.ndim() {
         1 => {
             let data = data.into_dimensionality::<numpy::Ix1>().w()?;
             let data = to_cow(&data);
-            wav::write_mono(&mut w, &data, sample_rate).w_f(&filename)?;
+            wav::write_mono(w, &data, sample_rate, metadata).w_f(filename)?;
         }
         2 => {
             let data = data.into_dimensionality::<numpy::Ix2>().w()?;
@@ -199,7 +564,7 @@ fn write_wav(
                 [1, l] => {
                     let data = data.into_shape_with_order((*l,)).w()?;
                     let data = to_cow(&data);
-                    wav::write_mono(&mut w, &data, sample_rate).w_f(&filename)?;
+                    wav::write_mono(w, &data, sample_rate, metadata).w_f(filename)?;
                 }
                 [2, l] => {
                     let data = data.into_shape_with_order((2 * *l,)).w()?;
@@ -210,59 +575,343 @@ fn write_wav(
                         .zip(pcm2.iter())
                         .flat_map(|(s1, s2)| [*s1, *s2])
                         .collect::<Vec<_>>();
-                    wav::write_stereo(&mut w, &data, sample_rate).w_f(&filename)?
+                    wav::write_stereo(w, &data, sample_rate, metadata).w_f(filename)?
                 }
                 _ => py_bail!("expected one or two channels, got shape {:?}", data.shape()),
             }
         }
         _ => py_bail!("expected one or two dimensions, got shape {:?}", data.shape()),
     }
+    w.flush().w_f(filename)?;
     Ok(())
 }
 
-/// Writes an opus file containing the input pcm data.
+fn wav_metadata_from_py(
+    bext: Option<&Bound<'_, pyo3::types::PyDict>>,
+    markers: Option<Vec<Bound<'_, pyo3::types::PyDict>>>,
+) -> PyResult<wav::Metadata> {
+    fn get<'py, T: FromPyObjectOwned<'py>>(
+        dict: &Bound<'py, pyo3::types::PyDict>,
+        key: &str,
+    ) -> PyResult<Option<T>> {
+        match dict.get_item(key)? {
+            None => Ok(None),
+            Some(v) if v.is_none() => Ok(None),
+            Some(v) => Ok(Some(v.extract().map_err(Into::into)?)),
+        }
+    }
+
+    let bext = match bext {
+        None => None,
+        Some(bext) => Some(wav::Bext {
+            description: get(bext, "description")?.unwrap_or_default(),
+            originator: get(bext, "originator")?.unwrap_or_default(),
+            originator_reference: get(bext, "originator_reference")?.unwrap_or_default(),
+            origination_date: get(bext, "origination_date")?.unwrap_or_default(),
+            origination_time: get(bext, "origination_time")?.unwrap_or_default(),
+            time_reference: get(bext, "time_reference")?.unwrap_or_default(),
+            coding_history: get(bext, "coding_history")?.unwrap_or_default(),
+        }),
+    };
+    let mut wav_markers = vec![];
+    for marker in markers.unwrap_or_default().iter() {
+        let position: u64 = match get(marker, "position")? {
+            None => py_bail!("missing position for marker {marker}"),
+            Some(position) => position,
+        };
+        // The cue chunk stores the positions on 32 bits.
+        let position = match u32::try_from(position) {
+            Ok(position) => position,
+            Err(_) => py_bail!("marker position {position} is larger than {}", u32::MAX),
+        };
+        wav_markers.push(wav::Marker { position, label: get(marker, "label")? })
+    }
+    Ok(wav::Metadata { bext, markers: wav_markers })
+}
+
+/// Writes an audio file using the wav format based on pcm data from a numpy array.
 ///
-/// Opus content is always encoded at 48kHz so the pcm data is resampled if sample_rate is
-/// different from 48000.
+/// This only supports a single channel at the moment so the input array data is expected to have a
+/// single dimension. The RF64 format is used for files larger than 4GiB.
+/// `filename` can also be a python file-like object that supports `write`.
+/// The optional `bext` dictionary is written as broadcast wave metadata, its keys are
+/// description, originator, originator_reference, origination_date, origination_time,
+/// time_reference, and coding_history. `markers` is a list of dictionaries with a `position` key,
+/// in samples, and an optional `label` key, these are written in the `cue ` and `LIST` chunks.
 #[pyfunction]
-#[pyo3(signature = (filename, data, sample_rate))]
-fn write_opus(
-    filename: std::path::PathBuf,
+#[pyo3(signature = (filename, data, sample_rate, *, bext=None, markers=None))]
+fn write_wav(
+    filename: &Bound<'_, PyAny>,
     data: numpy::PyReadonlyArrayDyn<f32>,
     sample_rate: u32,
+    bext: Option<Bound<'_, pyo3::types::PyDict>>,
+    markers: Option<Vec<Bound<'_, pyo3::types::PyDict>>>,
 ) -> PyResult<()> {
-    let write_mono = |mut w: std::io::BufWriter<std::fs::File>,
-                      data: numpy::ndarray::ArrayView1<f32>| {
-        let data = to_cow(&data);
-        opus::write_ogg_mono(&mut w, &data, sample_rate).w_f(&filename)
+    let metadata = wav_metadata_from_py(bext.as_ref(), markers)?;
+    let (mut w, filename) = open_writer(filename)?;
+    write_wav_to(&mut w, data, sample_rate, &metadata, &filename)
+}
+
+/// Encodes some pcm data using the wav format and returns the resulting bytes, see `write_wav`.
+#[pyfunction]
+#[pyo3(signature = (data, sample_rate, *, bext=None, markers=None))]
+fn write_wav_bytes(
+    data: numpy::PyReadonlyArrayDyn<f32>,
+    sample_rate: u32,
+    bext: Option<Bound<'_, pyo3::types::PyDict>>,
+    markers: Option<Vec<Bound<'_, pyo3::types::PyDict>>>,
+    py: Python,
+) -> PyResult<Py<pyo3::types::PyBytes>> {
+    let metadata = wav_metadata_from_py(bext.as_ref(), markers)?;
+    let mut w = vec![];
+    write_wav_to(&mut w, data, sample_rate, &metadata, std::path::Path::new("<bytes>"))?;
+    Ok(pyo3::types::PyBytes::new(py, &w).unbind())
+}
+
+/// Reads the broadcast wave metadata and the markers of a wav file.
+///
+/// This returns a dictionary with a `bext` key, None if the file has no `bext` chunk, and a
+/// `markers` key containing a list of dictionaries with `position` and `label` keys, see
+/// `write_wav`.
+/// As for `read`, the input can also be an archive member path, some bytes, or a python file-like
+/// object that supports `read` and `seek`.
+#[pyfunction]
+#[pyo3(signature = (filename))]
+fn read_wav_metadata<'a>(
+    filename: &Bound<'_, PyAny>,
+    py: Python<'a>,
+) -> PyResult<Bound<'a, pyo3::types::PyDict>> {
+    let (src, filename) = open_source(filename)?;
+    let metadata = wav::read_metadata(&mut std::io::BufReader::new(src)).w_f(&filename)?;
+    let dict = pyo3::types::PyDict::new(py);
+    let bext = match metadata.bext {
+        None => None,
+        Some(bext) => {
+            let d = pyo3::types::PyDict::new(py);
+            d.set_item("description", bext.description)?;
+            d.set_item("originator", bext.originator)?;
+            d.set_item("originator_reference", bext.originator_reference)?;
+            d.set_item("origination_date", bext.origination_date)?;
+            d.set_item("origination_time", bext.origination_time)?;
+            d.set_item("time_reference", bext.time_reference)?;
+            d.set_item("coding_history", bext.coding_history)?;
+            Some(d)
+        }
     };
+    dict.set_item("bext", bext)?;
+    let markers = pyo3::types::PyList::empty(py);
+    for marker in metadata.markers.into_iter() {
+        let d = pyo3::types::PyDict::new(py);
+        d.set_item("position", marker.position)?;
+        d.set_item("label", marker.label)?;
+        markers.append(d)?;
+    }
+    dict.set_item("markers", markers)?;
+    Ok(dict)
+}
 
-    let w = std::fs::File::create(&filename).w_f(&filename)?;
-    let mut w = std::io::BufWriter::new(w);
+fn write_opus_to<W: std::io::Write>(
+    w: &mut W,
+    data: numpy::PyReadonlyArrayDyn<f32>,
+    sample_rate: u32,
+    comments: &[(String, String)],
+    filename: &std::path::Path,
+) -> PyResult<()> {
     let data = data.as_array();
     match data.ndim() {
         1 => {
             let data = data.into_dimensionality::<numpy::Ix1>().w()?;
-            write_mono(w, data)?
+            let data = to_cow(&data);
+            opus::write_ogg_mono(w, &data, sample_rate, comments).w_f(filename)?
         }
         2 => {
             let data = data.into_dimensionality::<numpy::Ix2>().w()?;
             match data.shape() {
                 [1, l] => {
                     let data = data.into_shape_with_order((*l,)).w()?;
-                    write_mono(w, data)?
+                    let data = to_cow(&data);
+                    opus::write_ogg_mono(w, &data, sample_rate, comments).w_f(filename)?
                 }
                 [2, l] => {
                     let data = data.into_shape_with_order((*l * 2,)).w()?;
                     let data = to_cow(&data);
                     let (pcm1, pcm2) = (&data[..*l], &data[*l..]);
-                    opus::write_ogg_stereo(&mut w, pcm1, pcm2, sample_rate).w_f(&filename)?
+                    opus::write_ogg_stereo(w, pcm1, pcm2, sample_rate, comments).w_f(filename)?
                 }
                 _ => py_bail!("expected one or two channels, got shape {:?}", data.shape()),
             }
         }
         _ => py_bail!("expected one or two dimensions, got shape {:?}", data.shape()),
     }
+    w.flush().w_f(filename)?;
+    Ok(())
+}
+
+/// Writes an opus file containing the input pcm data.
+///
+/// Opus content is always encoded at 48kHz so the pcm data is resampled if sample_rate is
+/// different from 48000.
+/// The optional `tags` dictionary is written as vorbis comments in the OpusTags header, e.g.
+/// `{"SPEAKER": "alice", "LICENSE": "cc-by"}`.
+/// `filename` can also be a python file-like object that supports `write`.
+#[pyfunction]
+#[pyo3(signature = (filename, data, sample_rate, *, tags=None))]
+fn write_opus(
+    filename: &Bound<'_, PyAny>,
+    data: numpy::PyReadonlyArrayDyn<f32>,
+    sample_rate: u32,
+    tags: Option<Bound<'_, pyo3::types::PyDict>>,
+) -> PyResult<()> {
+    let comments = comments_from_dict(tags.as_ref())?;
+    let (mut w, filename) = open_writer(filename)?;
+    write_opus_to(&mut w, data, sample_rate, &comments, &filename)
+}
+
+/// Encodes some pcm data as an ogg/opus stream and returns the resulting bytes, see `write_opus`.
+#[pyfunction]
+#[pyo3(signature = (data, sample_rate, *, tags=None))]
+fn write_opus_bytes(
+    data: numpy::PyReadonlyArrayDyn<f32>,
+    sample_rate: u32,
+    tags: Option<Bound<'_, pyo3::types::PyDict>>,
+    py: Python,
+) -> PyResult<Py<pyo3::types::PyBytes>> {
+    let comments = comments_from_dict(tags.as_ref())?;
+    let mut w = vec![];
+    write_opus_to(&mut w, data, sample_rate, &comments, std::path::Path::new("<bytes>"))?;
+    Ok(pyo3::types::PyBytes::new(py, &w).unbind())
+}
+
+/// Writes an audio file using the flac format based on pcm data from a numpy array.
+///
+/// The input array can either have a single dimension or have shape `(channels, samples)` with up
+/// to 8 channels. The samples are quantized to `bits_per_sample`, either 16 or 24, and
+/// `compression_level` goes from 0 (fastest) to 8 (smallest output). The array must contain at
+/// least one sample.
+/// `filename` can also be a python file-like object that supports `write`.
+#[pyfunction]
+#[pyo3(signature = (filename, data, sample_rate, *, bits_per_sample=16, compression_level=5))]
+fn write_flac(
+    filename: &Bound<'_, PyAny>,
+    data: numpy::PyReadonlyArrayDyn<f32>,
+    sample_rate: u32,
+    bits_per_sample: u32,
+    compression_level: u32,
+    py: Python,
+) -> PyResult<()> {
+    let data = data.as_array();
+    let data = match data.ndim() {
+        1 => {
+            let data = data.into_dimensionality::<numpy::Ix1>().w()?;
+            vec![data.to_vec()]
+        }
+        2 => {
+            let data = data.into_dimensionality::<numpy::Ix2>().w()?;
+            data.outer_iter().map(|c| c.to_vec()).collect::<Vec<_>>()
+        }
+        _ => py_bail!("expected one or two dimensions, got shape {:?}", data.shape()),
+    };
+    let pcm = data.iter().map(|c| c.as_slice()).collect::<Vec<_>>();
+    let mut buf = vec![];
+    py.detach(|| flac::write(&mut buf, &pcm, sample_rate, bits_per_sample, compression_level))
+        .w()?;
+    // The output is only created once the encoding has succeeded.
+    let (mut w, filename) = open_writer(filename)?;
+    w.write_all(&buf).w_f(&filename)?;
+    w.flush().w_f(&filename)?;
+    Ok(())
+}
+
+/// Reads headerless pcm data, interleaved by channel, from a file or from some bytes.
+///
+/// `dtype` describes the samples and their endianness, e.g. "s16le", "s24le", "s32be", "f32le",
+/// "f64le", or "u8". `offset` and `count` are expressed in samples per channel, by default all
+/// the data after `offset` is read. The returned array has shape `(channels, samples)`.
+#[pyfunction]
+#[pyo3(signature = (source, dtype, channels, sample_rate, offset=0, count=None))]
+fn read_raw(
+    source: &Bound<'_, PyAny>,
+    dtype: &str,
+    channels: usize,
+    sample_rate: u32,
+    offset: u64,
+    count: Option<u64>,
+    py: Python,
+) -> PyResult<Py<PyAny>> {
+    use std::io::{Read, Seek};
+
+    let format = pcm::PcmFormat::raw(dtype, channels, sample_rate).w()?;
+    let frame_len = format.frame_len();
+    let start = match offset.checked_mul(frame_len) {
+        Some(start) => start,
+        None => py_bail!("offset {offset} is too large"),
+    };
+    let len = match count {
+        None => None,
+        Some(count) => match count.checked_mul(frame_len) {
+            Some(len) => Some(len),
+            None => py_bail!("count {count} is too large"),
+        },
+    };
+    let data = if let Ok(bytes) = source.cast::<pyo3::types::PyBytes>() {
+        let bytes = bytes.as_bytes();
+        let start = u64::min(start, bytes.len() as u64);
+        let end = match len {
+            None => bytes.len() as u64,
+            Some(len) => match start.checked_add(len) {
+                Some(end) => u64::min(end, bytes.len() as u64),
+                None => py_bail!("range of {len} bytes from {start} is too large"),
+            },
+        };
+        if start > end || end > bytes.len() as u64 {
+            py_bail!("invalid range {start}..{end} for {} bytes", bytes.len())
+        }
+        format.decode(&bytes[start as usize..end as usize])
+    } else {
+        let path: std::path::PathBuf = source.extract()?;
+        let mut src = archive::open(&path).w_f(&path)?;
+        src.seek(std::io::SeekFrom::Start(start)).w_f(&path)?;
+        let mut buf = vec![];
+        match len {
+            None => src.read_to_end(&mut buf).w_f(&path)?,
+            Some(len) => src.take(len).read_to_end(&mut buf).w_f(&path)?,
+        };
+        format.decode(&buf)
+    };
+    Ok(numpy::PyArray2::from_owned_array(py, data).into_any().unbind())
+}
+
+/// Writes some pcm data as headerless pcm, interleaved by channel, see `read_raw` for the
+/// supported values of `dtype`.
+///
+/// The input array can either have a single dimension or have shape `(channels, samples)`.
+/// `filename` can also be a python file-like object that supports `write`.
+#[pyfunction]
+#[pyo3(signature = (filename, data, dtype))]
+fn write_raw(
+    filename: &Bound<'_, PyAny>,
+    data: numpy::PyReadonlyArrayDyn<f32>,
+    dtype: &str,
+) -> PyResult<()> {
+    let data = data.as_array();
+    let data = match data.ndim() {
+        1 => {
+            let data = data.into_dimensionality::<numpy::Ix1>().w()?;
+            vec![data.to_vec()]
+        }
+        2 => {
+            let data = data.into_dimensionality::<numpy::Ix2>().w()?;
+            data.outer_iter().map(|c| c.to_vec()).collect::<Vec<_>>()
+        }
+        _ => py_bail!("expected one or two dimensions, got shape {:?}", data.shape()),
+    };
+    let pcm = data.iter().map(|c| c.as_slice()).collect::<Vec<_>>();
+    // The sample rate is not stored in raw files.
+    let format = pcm::PcmFormat::raw(dtype, pcm.len(), 1).w()?;
+    let bytes = format.encode(&pcm).w()?;
+    let (mut w, filename) = open_writer(filename)?;
+    w.write_all(&bytes).w_f(&filename)?;
+    w.flush().w_f(&filename)?;
     Ok(())
 }
 
@@ -298,61 +947,106 @@ fn resample(
         }
         2 => {
             let pcm = pcm.into_dimensionality::<numpy::Ix2>().w()?;
-            let (channels, l) = pcm.dim();
-            let pcm = pcm.into_shape_with_order((channels * l,)).w()?;
-            let pcm = to_cow(&pcm)
-                .chunks(l)
-                .map(|pcm| audio::resample(pcm, src_sample_rate, dst_sample_rate))
-                .collect::<anyhow::Result<Vec<_>>>()
-                .w()?;
+            let pcm = audio::resample2(pcm, src_sample_rate, dst_sample_rate).w()?;
             Python::attach(|py| {
-                Ok::<_, PyErr>(numpy::PyArray2::from_vec2(py, &pcm)?.into_any().unbind())
+                Ok::<_, PyErr>(numpy::PyArray2::from_owned_array(py, pcm).into_any().unbind())
             })
         }
         _ => py_bail!("expected one or two dimensions, got shape {:?}", pcm.shape()),
     }
 }
 
+fn comments_from_dict(
+    tags: Option<&Bound<'_, pyo3::types::PyDict>>,
+) -> PyResult<Vec<(String, String)>> {
+    let mut comments = vec![];
+    if let Some(tags) = tags {
+        for (key, value) in tags.iter() {
+            let key: String = key.extract()?;
+            if key.is_empty() || key.contains('=') {
+                py_bail!("invalid tag name '{key}'")
+            }
+            comments.push((key, value.str()?.to_string()))
+        }
+    }
+    Ok(comments)
+}
+
+fn read_opus_result(
+    py: Python,
+    data: numpy::ndarray::Array2<f32>,
+    sample_rate: u32,
+    tags: Option<opus::OpusTags>,
+    with_tags: bool,
+) -> PyResult<Py<PyAny>> {
+    let data = numpy::PyArray2::from_owned_array(py, data).into_any();
+    if with_tags {
+        let dict = pyo3::types::PyDict::new(py);
+        for (key, value) in tags.unwrap_or_default().comments.iter() {
+            dict.set_item(key, value)?;
+        }
+        Ok((data, sample_rate, dict).into_pyobject(py)?.into_any().unbind())
+    } else {
+        Ok((data, sample_rate).into_pyobject(py)?.into_any().unbind())
+    }
+}
+
 /// Reads the whole content of an ogg/opus encoded file.
 ///
 /// This returns a two dimensional array as well as the sample rate. Currently all opus audio is
 /// encoded at 48kHz so this value is always returned.
+/// The pre-skip samples from the OpusHead header and the padding after the last granule
+/// position are removed.
+/// When `with_tags` is set, a dictionary containing the vorbis comments from the OpusTags header
+/// is returned as a third element. If a key appears multiple times, the last value is kept.
 #[pyfunction]
-#[pyo3(signature = (filename))]
-fn read_opus(filename: std::path::PathBuf, py: Python) -> PyResult<(Py<PyAny>, u32)> {
+#[pyo3(signature = (filename, *, with_tags=false))]
+fn read_opus(filename: std::path::PathBuf, with_tags: bool, py: Python) -> PyResult<Py<PyAny>> {
     let file = std::fs::File::open(&filename)?;
     let file = std::io::BufReader::new(file);
-    let (data, sample_rate) = opus::read_ogg(file).w_f(&filename)?;
-    let data = numpy::PyArray2::from_vec2(py, &data)?.into_any().unbind();
-    Ok((data, sample_rate))
+    let (data, sample_rate, tags) = opus::read_ogg(file).w_f(&filename)?;
+    read_opus_result(py, data, sample_rate, tags, with_tags)
 }
 
 /// Reads bytes corresponding to an ogg/opus encoded file.
 ///
 /// This returns a two dimensional array as well as the sample rate. Currently all opus audio is
 /// encoded at 48kHz so this value is always returned.
+/// The pre-skip samples from the OpusHead header and the padding after the last granule
+/// position are removed.
+/// When `with_tags` is set, a dictionary containing the vorbis comments from the OpusTags header
+/// is returned as a third element. If a key appears multiple times, the last value is kept.
 #[pyfunction]
-#[pyo3(signature = (bytes))]
-fn read_opus_bytes(bytes: Vec<u8>, py: Python) -> PyResult<(Py<PyAny>, u32)> {
+#[pyo3(signature = (bytes, *, with_tags=false))]
+fn read_opus_bytes(bytes: Vec<u8>, with_tags: bool, py: Python) -> PyResult<Py<PyAny>> {
     let bytes = std::io::Cursor::new(bytes);
-    let (data, sample_rate) = opus::read_ogg(bytes).w()?;
-    let data = numpy::PyArray2::from_vec2(py, &data)?.into_any().unbind();
-    Ok((data, sample_rate))
+    let (data, sample_rate, tags) = opus::read_ogg(bytes).w()?;
+    read_opus_result(py, data, sample_rate, tags, with_tags)
 }
 
 #[pyclass]
 struct OpusStreamWriter {
     inner: Mutex<kaudio::ogg_opus::Encoder>,
+    header_data: Vec<u8>,
     sample_rate: usize,
     sent_header: bool,
 }
 
 #[pymethods]
 impl OpusStreamWriter {
+    /// Creates a stream writer, the optional `tags` dictionary is written as vorbis comments in
+    /// the OpusTags header.
     #[new]
-    fn new(sample_rate: usize) -> PyResult<Self> {
+    #[pyo3(signature = (sample_rate, *, tags=None))]
+    fn new(sample_rate: usize, tags: Option<Bound<'_, pyo3::types::PyDict>>) -> PyResult<Self> {
+        let comments = comments_from_dict(tags.as_ref())?;
         let inner = kaudio::ogg_opus::Encoder::new(sample_rate).w()?;
-        Ok(Self { inner: Mutex::new(inner), sample_rate, sent_header: false })
+        // The headers are generated here rather than using the encoder ones so as to include the
+        // comments. The encoder only supports mono audio.
+        let mut pw = ogg::PacketWriter::new(Vec::new());
+        opus::write_headers(&mut pw, 1, sample_rate as u32, &comments).w()?;
+        let header_data = pw.into_inner();
+        Ok(Self { inner: Mutex::new(inner), header_data, sample_rate, sent_header: false })
     }
 
     fn __str__(&self) -> String {
@@ -370,16 +1064,191 @@ impl OpusStreamWriter {
             bytes
         } else {
             self.sent_header = true;
-            [inner.header_data(), &bytes].concat()
+            [self.header_data.as_slice(), &bytes].concat()
         };
         Ok(bytes)
     }
 }
 
+/// Converts an array with either a single dimension or with shape `(channels, samples)` to some
+/// pcm data interleaved by channel.
+fn interleave(data: numpy::PyReadonlyArrayDyn<f32>, channels: usize) -> PyResult<Vec<f32>> {
+    let data = data.as_array();
+    match (data.ndim(), data.shape()) {
+        (1, _) if channels == 1 => Ok(data.iter().copied().collect()),
+        (2, [c, _]) if *c == channels => {
+            let data = data.into_dimensionality::<numpy::Ix2>().w()?;
+            Ok(data.t().iter().copied().collect())
+        }
+        _ => py_bail!("expected {channels} channels, got shape {:?}", data.shape()),
+    }
+}
+
+/// Writes a wav file incrementally. The data is buffered and the header gets updated on `flush`
+/// and `close`, so that the file remains readable up to the last flush if the process gets
+/// interrupted. `filename` can also be a python file-like object that supports `write` and
+/// `seek`.
+#[pyclass]
+struct WavWriter {
+    inner: Option<wav::StreamWriter<std::io::BufWriter<Box<dyn WriteSeek>>>>,
+    path: std::path::PathBuf,
+}
+
+#[pymethods]
+impl WavWriter {
+    #[new]
+    #[pyo3(signature = (filename, sample_rate, *, channels=1))]
+    fn new(filename: &Bound<'_, PyAny>, sample_rate: u32, channels: u16) -> PyResult<Self> {
+        let (w, filename) = open_seekable_writer(filename)?;
+        let inner = wav::StreamWriter::new(w, channels, sample_rate).w_f(&filename)?;
+        Ok(Self { inner: Some(inner), path: filename })
+    }
+
+    fn __str__(&self) -> String {
+        format!("WavWriter(path={:?})", self.path)
+    }
+
+    /// Appends some pcm data to the file, the data should have a single dimension for mono audio
+    /// or shape `(channels, samples)`.
+    fn write(&mut self, data: numpy::PyReadonlyArrayDyn<f32>) -> PyResult<()> {
+        let inner = match self.inner.as_mut() {
+            None => py_bail!("{:?}: writer has already been closed", self.path),
+            Some(inner) => inner,
+        };
+        let data = interleave(data, inner.n_channels() as usize)?;
+        inner.write(&data).w_f(&self.path)
+    }
+
+    /// Writes the buffered data and updates the header with the current length.
+    fn flush(&mut self) -> PyResult<()> {
+        match self.inner.as_mut() {
+            None => py_bail!("{:?}: writer has already been closed", self.path),
+            Some(inner) => inner.flush().w_f(&self.path),
+        }
+    }
+
+    /// Finalizes the file, this is a no-op if the writer has already been closed.
+    fn close(&mut self) -> PyResult<()> {
+        if let Some(inner) = self.inner.take() {
+            inner.finalize().w_f(&self.path)?;
+        }
+        Ok(())
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
+    fn __exit__(
+        &mut self,
+        _exc_type: Option<Bound<'_, PyAny>>,
+        _exc_value: Option<Bound<'_, PyAny>>,
+        _traceback: Option<Bound<'_, PyAny>>,
+    ) -> PyResult<()> {
+        self.close()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.take() {
+            let _ = inner.finalize();
+        }
+    }
+}
+
+/// Writes an ogg/opus file incrementally. A page is written for each chunk of data, the pages are
+/// buffered and the file can be decoded up to the last flush if the process gets interrupted. The
+/// end of stream is marked on `close`. `filename` can also be a python file-like object that
+/// supports `write`.
+#[pyclass]
+struct OpusFileWriter {
+    inner: Mutex<Option<opus::StreamEncoder<Box<dyn std::io::Write + Send>>>>,
+    path: std::path::PathBuf,
+}
+
+#[pymethods]
+impl OpusFileWriter {
+    /// The sample rate must be supported natively by opus, i.e. 8000, 12000, 16000, 24000, or
+    /// 48000. The optional `tags` dictionary is written as vorbis comments in the OpusTags header.
+    #[new]
+    #[pyo3(signature = (filename, sample_rate, *, channels=1, tags=None))]
+    fn new(
+        filename: &Bound<'_, PyAny>,
+        sample_rate: u32,
+        channels: usize,
+        tags: Option<Bound<'_, pyo3::types::PyDict>>,
+    ) -> PyResult<Self> {
+        let comments = comments_from_dict(tags.as_ref())?;
+        let (w, filename) = open_writer(filename)?;
+        let inner = opus::StreamEncoder::new(w, sample_rate, channels, &comments).w_f(&filename)?;
+        Ok(Self { inner: Mutex::new(Some(inner)), path: filename })
+    }
+
+    fn __str__(&self) -> String {
+        format!("OpusFileWriter(path={:?})", self.path)
+    }
+
+    /// Appends some pcm data to the file, the data should have a single dimension for mono audio
+    /// or shape `(channels, samples)`. Chunks can have any size.
+    fn write(&mut self, data: numpy::PyReadonlyArrayDyn<f32>) -> PyResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = match inner.as_mut() {
+            None => py_bail!("{:?}: writer has already been closed", self.path),
+            Some(inner) => inner,
+        };
+        let data = interleave(data, inner.channels())?;
+        inner.write(&data).w_f(&self.path)
+    }
+
+    /// Writes the buffered pages, the data that does not fill a complete opus frame yet is only
+    /// encoded by the following writes or on `close`.
+    fn flush(&mut self) -> PyResult<()> {
+        match self.inner.lock().unwrap().as_mut() {
+            None => py_bail!("{:?}: writer has already been closed", self.path),
+            Some(inner) => inner.flush().w_f(&self.path),
+        }
+    }
+
+    /// Encodes the remaining data and marks the end of the stream, this is a no-op if the writer
+    /// has already been closed.
+    fn close(&mut self) -> PyResult<()> {
+        if let Some(inner) = self.inner.lock().unwrap().take() {
+            inner.finalize().w_f(&self.path)?;
+        }
+        Ok(())
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    #[pyo3(signature = (_exc_type=None, _exc_value=None, _traceback=None))]
+    fn __exit__(
+        &mut self,
+        _exc_type: Option<Bound<'_, PyAny>>,
+        _exc_value: Option<Bound<'_, PyAny>>,
+        _traceback: Option<Bound<'_, PyAny>>,
+    ) -> PyResult<()> {
+        self.close()
+    }
+}
+
+impl Drop for OpusFileWriter {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.get_mut().ok().and_then(|inner| inner.take()) {
+            let _ = inner.finalize();
+        }
+    }
+}
+
 #[pyclass]
 struct OpusStreamReader {
     inner: Mutex<kaudio::ogg_opus::Decoder>,
     sample_rate: usize,
+    // Decoded samples that did not fit in the array passed to `append_bytes_into`.
+    pending: Vec<f32>,
 }
 
 #[pymethods]
@@ -388,7 +1257,7 @@ impl OpusStreamReader {
     #[pyo3(signature = (sample_rate, flush_every_n_samples=0))]
     fn new(sample_rate: usize, flush_every_n_samples: usize) -> PyResult<Self> {
         let inner = kaudio::ogg_opus::Decoder::new(sample_rate, flush_every_n_samples).w()?;
-        Ok(Self { inner: Mutex::new(inner), sample_rate })
+        Ok(Self { inner: Mutex::new(inner), sample_rate, pending: vec![] })
     }
 
     fn __str__(&self) -> String {
@@ -398,13 +1267,40 @@ impl OpusStreamReader {
     /// Writes some ogg/opus bytes to the current stream.
     fn append_bytes(&mut self, data: &[u8]) -> PyResult<Py<PyAny>> {
         let mut inner = self.inner.lock().unwrap();
-        let pcm = match inner.decode(data).w()? {
-            None => vec![],
-            Some(pcm) => pcm.to_vec(),
-        };
+        let mut pcm = std::mem::take(&mut self.pending);
+        if let Some(p) = inner.decode(data).w()? {
+            pcm.extend_from_slice(p)
+        }
         let pcm = Python::attach(|py| numpy::PyArray1::from_vec(py, pcm).into_any().unbind());
         Ok(pcm)
     }
+
+    /// Writes some ogg/opus bytes to the current stream and stores the decoded pcm data in `out`,
+    /// a preallocated and writable one dimensional float32 array. The number of samples written
+    /// is returned, samples that do not fit in `out` are kept for the next call.
+    #[pyo3(signature = (data, out))]
+    fn append_bytes_into(
+        &mut self,
+        data: &[u8],
+        mut out: numpy::PyReadwriteArray1<f32>,
+    ) -> PyResult<usize> {
+        let out = match out.as_slice_mut() {
+            Ok(out) => out,
+            Err(_) => py_bail!("out is not a contiguous array"),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(pcm) = inner.decode(data).w()? {
+            if self.pending.is_empty() && pcm.len() <= out.len() {
+                out[..pcm.len()].copy_from_slice(pcm);
+                return Ok(pcm.len());
+            }
+            self.pending.extend_from_slice(pcm)
+        }
+        let n = usize::min(self.pending.len(), out.len());
+        out[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
 }
 
 #[pymodule]
@@ -412,15 +1308,27 @@ fn sphn(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<dataset::DatasetReader>()?;
     m.add_class::<dataset::DatasetIter>()?;
     m.add_class::<FileReader>()?;
+    m.add_class::<FileReaderChunks>()?;
+    m.add_class::<OpusFileWriter>()?;
     m.add_class::<OpusStreamReader>()?;
     m.add_class::<OpusStreamWriter>()?;
+    m.add_class::<WavWriter>()?;
     m.add_function(wrap_pyfunction!(dataset::dataset_jsonl, m)?)?;
     m.add_function(wrap_pyfunction!(durations, m)?)?;
+    m.add_function(wrap_pyfunction!(manifest::scan_audio, m)?)?;
+    m.add_function(wrap_pyfunction!(packed::pack, m)?)?;
     m.add_function(wrap_pyfunction!(read, m)?)?;
+    m.add_function(wrap_pyfunction!(read_metadata, m)?)?;
+    m.add_function(wrap_pyfunction!(read_raw, m)?)?;
+    m.add_function(wrap_pyfunction!(read_wav_metadata, m)?)?;
+    m.add_function(wrap_pyfunction!(write_flac, m)?)?;
+    m.add_function(wrap_pyfunction!(write_raw, m)?)?;
     m.add_function(wrap_pyfunction!(write_wav, m)?)?;
+    m.add_function(wrap_pyfunction!(write_wav_bytes, m)?)?;
     m.add_function(wrap_pyfunction!(read_opus, m)?)?;
     m.add_function(wrap_pyfunction!(read_opus_bytes, m)?)?;
     m.add_function(wrap_pyfunction!(write_opus, m)?)?;
+    m.add_function(wrap_pyfunction!(write_opus_bytes, m)?)?;
     m.add_function(wrap_pyfunction!(resample, m)?)?;
     Ok(())
 }
@@ -0,0 +1,153 @@
+use crate::{archive, audio, PyRes};
+use pyo3::prelude::*;
+
+/// A line of the jsonl manifest, the `path` and `duration` fields are the ones used by
+/// `dataset_jsonl`. When the file cannot be read, `duration` is null and `error` contains the
+/// reason for the failure.
+#[derive(Debug, Clone, serde::Serialize)]
+struct FileInfo {
+    path: String,
+    duration: Option<f64>,
+    sample_rate: Option<u32>,
+    channels: Option<usize>,
+    codec: Option<String>,
+    container: Option<String>,
+    bits_per_sample: Option<u32>,
+    file_size: Option<u64>,
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<audio::Metadata>,
+}
+
+impl FileInfo {
+    fn new(path: String) -> Self {
+        Self {
+            path,
+            duration: None,
+            sample_rate: None,
+            channels: None,
+            codec: None,
+            container: None,
+            bits_per_sample: None,
+            file_size: None,
+            error: None,
+            metadata: None,
+        }
+    }
+}
+
+fn scan(path: &std::path::Path, opts: &audio::OpenOptions, with_metadata: bool) -> FileInfo {
+    // Use absolute paths where possible so that the manifest can be loaded from any directory.
+    let p = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let mut info = FileInfo::new(p.to_string_lossy().to_string());
+    if let Err(err) = scan_into(path, opts, with_metadata, &mut info) {
+        info.duration = None;
+        info.error = Some(err.to_string())
+    }
+    info
+}
+
+fn scan_into(
+    path: &std::path::Path,
+    opts: &audio::OpenOptions,
+    with_metadata: bool,
+    info: &mut FileInfo,
+) -> anyhow::Result<()> {
+    info.file_size = Some(archive::file_len(path)?);
+    let mut reader = audio::FileReader::new_with_options(path, opts)?;
+    info.sample_rate = Some(reader.sample_rate());
+    info.channels = Some(reader.channels());
+    info.codec = Some(reader.codec_name().to_string());
+    info.container = Some(reader.container().to_string());
+    info.bits_per_sample = reader.bits_per_sample();
+    if with_metadata {
+        info.metadata = Some(reader.metadata().clone())
+    }
+    // Try to read a small portion of the file to check that it works.
+    let (_data, _unpadded_len) = reader.decode(0., 0.1, false)?;
+    info.duration = Some(reader.duration_sec());
+    Ok(())
+}
+
+/// Scans some audio files and writes a jsonl manifest with one line per file.
+///
+/// Each line contains the path, duration, sample rate, number of channels, codec, container, bits
+/// per sample, file size and an error message if the file could not be read. The resulting file
+/// can be loaded with `dataset_jsonl`, files that failed to read have a null duration and are
+/// skipped. The number of files that could be read successfully is returned.
+/// When `exact_duration` is set, the durations are computed by scanning the audio packets rather
+/// than using the frame counts from the file headers. When `with_metadata` is set, the tags found
+/// in each file are included under the `metadata` key, see `read_metadata`.
+#[pyfunction]
+#[pyo3(signature = (paths, out_jsonl, *, num_threads=None, exact_duration=false, with_metadata=false))]
+pub fn scan_audio(
+    paths: Vec<std::path::PathBuf>,
+    out_jsonl: std::path::PathBuf,
+    num_threads: Option<usize>,
+    exact_duration: bool,
+    with_metadata: bool,
+    py: Python,
+) -> PyResult<usize> {
+    use rayon::prelude::*;
+    use std::io::Write;
+
+    let opts = audio::OpenOptions { exact_duration, ..Default::default() };
+    let infos = py.detach(|| {
+        let scan_all =
+            || paths.par_iter().map(|p| scan(p, &opts, with_metadata)).collect::<Vec<_>>();
+        match num_threads {
+            None => Ok(scan_all()),
+            Some(num_threads) => {
+                let pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads).build()?;
+                Ok::<_, anyhow::Error>(pool.install(scan_all))
+            }
+        }
+    });
+    let infos = infos.w()?;
+    let w = std::fs::File::create(&out_jsonl).w_f(&out_jsonl)?;
+    let mut w = std::io::BufWriter::new(w);
+    for info in infos.iter() {
+        let line = serde_json::to_string(info).w()?;
+        writeln!(w, "{line}").w_f(&out_jsonl)?;
+    }
+    w.flush().w_f(&out_jsonl)?;
+    Ok(infos.iter().filter(|i| i.error.is_none()).count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn scan_files() -> anyhow::Result<()> {
+        let dir = TempDir::new("manifest")?;
+        let path = dir.path().join("ramp.wav");
+        let samples: Vec<f32> = (0..8000).map(|i| (i % 100) as f32 / 200. - 0.25).collect();
+        let mut file = std::fs::File::create(&path)?;
+        crate::wav::write_stereo(&mut file, &samples, 16000, &Default::default())?;
+        drop(file);
+
+        let opts = audio::OpenOptions::default();
+        let info = scan(&path, &opts, false);
+        assert_eq!(info.error, None);
+        assert_eq!(info.duration, Some(0.25));
+        assert_eq!(info.sample_rate, Some(16000));
+        assert_eq!(info.channels, Some(2));
+        assert_eq!(info.bits_per_sample, Some(16));
+        assert_eq!(info.file_size, Some(std::fs::metadata(&path)?.len()));
+        assert!(info.metadata.is_none());
+        let line = serde_json::to_value(&info)?;
+        assert!(line.get("metadata").is_none());
+        assert_eq!(line["duration"], serde_json::json!(0.25));
+
+        let missing = dir.path().join("missing.wav");
+        let info = scan(&missing, &opts, true);
+        assert!(info.error.is_some());
+        assert_eq!(info.duration, None);
+        assert_eq!(info.path, missing.to_string_lossy());
+        let line = serde_json::to_value(&info)?;
+        assert_eq!(line["duration"], serde_json::Value::Null);
+        Ok(())
+    }
+}
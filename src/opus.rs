@@ -5,6 +5,9 @@ use anyhow::Result;
 // https://opus-codec.org/docs/opus_api-1.2/group__opus__encoder.html#ga4ae9905859cd241ef4bb5c59cd5e5309
 const OPUS_ENCODER_FRAME_SIZE: usize = 960;
 const OPUS_SAMPLE_RATE: u32 = 48000;
+// Number of samples at 48kHz to discard at the beginning of the decoded stream, this is written in
+// the OpusHead header and is included in all the granule positions.
+const OPUS_PRE_SKIP: u16 = 3840;
 
 /// See https://www.opus-codec.org/docs/opusfile_api-0.4/structOpusHead.html
 #[allow(unused)]
@@ -40,16 +43,66 @@ fn parse_opus_header(packet: &[u8]) -> Result<OpusHeader> {
     Ok(header)
 }
 
+/// The content of the comment header, see https://wiki.xiph.org/OggOpus#Comment_Header
+/// Each comment is a key-value pair, the same key can appear multiple times.
+#[derive(Debug, Clone, Default)]
+pub struct OpusTags {
+    #[allow(unused)]
+    pub vendor: String,
+    pub comments: Vec<(String, String)>,
+}
+
+fn parse_opus_tags(packet: &[u8]) -> Result<OpusTags> {
+    fn read_string<'a>(packet: &mut &'a [u8]) -> Result<&'a [u8]> {
+        if packet.len() < 4 {
+            anyhow::bail!("unexpected end of OpusTags packet")
+        }
+        let len = u32::from_le_bytes([packet[0], packet[1], packet[2], packet[3]]) as usize;
+        if packet.len() < 4 + len {
+            anyhow::bail!("unexpected end of OpusTags packet")
+        }
+        let s = &packet[4..4 + len];
+        *packet = &packet[4 + len..];
+        Ok(s)
+    }
+
+    if packet.len() < 8 || &packet[0..8] != b"OpusTags" {
+        anyhow::bail!("not a OpusTags packet")
+    }
+    let mut packet = &packet[8..];
+    let vendor = String::from_utf8_lossy(read_string(&mut packet)?).to_string();
+    if packet.len() < 4 {
+        anyhow::bail!("unexpected end of OpusTags packet")
+    }
+    let n_comments = u32::from_le_bytes([packet[0], packet[1], packet[2], packet[3]]);
+    packet = &packet[4..];
+    let mut comments = Vec::with_capacity(usize::min(n_comments as usize, 1024));
+    for _ in 0..n_comments {
+        let comment = String::from_utf8_lossy(read_string(&mut packet)?).to_string();
+        // Comments that do not contain a '=' are invalid and get skipped.
+        if let Some((key, value)) = comment.split_once('=') {
+            comments.push((key.to_string(), value.to_string()))
+        }
+    }
+    Ok(OpusTags { vendor, comments })
+}
+
 /// Read an ogg stream using the opus codec.
-pub fn read_ogg<R: std::io::Read + std::io::Seek>(reader: R) -> Result<(Vec<Vec<f32>>, u32)> {
+pub fn read_ogg<R: std::io::Read + std::io::Seek>(
+    reader: R,
+) -> Result<(numpy::ndarray::Array2<f32>, u32, Option<OpusTags>)> {
     let mut packet_reader = ogg::PacketReader::new(reader);
     let mut opus_decoder = None;
     let mut channels = 1;
+    let mut pre_skip = 0;
+    let mut granule = None;
     let mut all_data = vec![];
+    let mut tags = None;
     while let Some(packet) = packet_reader.read_packet()? {
         let is_header = packet.data.len() >= 8 && &packet.data[0..8] == b"OpusHead";
         let is_tags = packet.data.len() >= 8 && &packet.data[0..8] == b"OpusTags";
         if is_tags {
+            tags = Some(parse_opus_tags(&packet.data)?);
             continue;
         }
         match (is_header, opus_decoder.as_mut()) {
@@ -57,6 +110,7 @@ pub fn read_ogg<R: std::io::Read + std::io::Seek>(reader: R) -> Result<(Vec<Vec<
             (true, None) => {
                 let header = parse_opus_header(&packet.data)?;
                 channels = header.channel_count as usize;
+                pre_skip = header.pre_skip as usize;
                 let channels = match header.channel_count {
                     1 => opus::Channels::Mono,
                     2 => opus::Channels::Stereo,
@@ -76,27 +130,42 @@ pub fn read_ogg<R: std::io::Read + std::io::Seek>(reader: R) -> Result<(Vec<Vec<
                     /* Forward Error Correction */ false,
                 )?;
                 all_data.resize(prev_len + samples * channels, 0f32);
+                // A granule position of -1 indicates that no packet ends on this page.
+                if packet.last_in_page() && packet.absgp_page() != u64::MAX {
+                    granule = Some(packet.absgp_page())
+                }
             }
         }
     }
+    // Drop the pre-skip samples and the padding past the last granule position.
+    let end = match granule {
+        None => all_data.len() / channels,
+        Some(granule) => usize::min(granule as usize, all_data.len() / channels),
+    };
+    let start = usize::min(pre_skip, end);
+    all_data.truncate(end * channels);
+    all_data.drain(..start * channels);
     let sample_rate = match opus_decoder.as_mut() {
         None => anyhow::bail!("no data"),
         Some(od) => od.get_sample_rate()?,
     };
+    let len = all_data.len() / channels;
     let data = match channels {
-        1 => vec![all_data],
+        1 => all_data,
         2 => {
-            let mut c0 = Vec::with_capacity(all_data.len() / 2);
-            let mut c1 = Vec::with_capacity(all_data.len() / 2);
-            for c in all_data.chunks(2) {
-                c0.push(c[0]);
-                c1.push(c[1]);
+            // De-interleave into a single planar buffer.
+            let mut data = vec![0f32; all_data.len()];
+            let (c0, c1) = data.split_at_mut(len);
+            for ((c, d0), d1) in all_data.chunks(2).zip(c0.iter_mut()).zip(c1.iter_mut()) {
+                *d0 = c[0];
+                *d1 = c[1];
             }
-            vec![c0, c1]
+            data
         }
         c => anyhow::bail!("unexpected number of channels {c}"),
     };
-    Ok((data, sample_rate))
+    let data = numpy::ndarray::Array2::from_shape_vec((channels, len), data)?;
+    Ok((data, sample_rate, tags))
 }
 
 fn write_opus_header<W: std::io::Write>(
@@ -110,14 +179,17 @@ fn write_opus_header<W: std::io::Write>(
     w.write_all(b"OpusHead")?;
     w.write_u8(1)?; // version
     w.write_u8(channels)?; // channel count
-    w.write_u16::<byteorder::LittleEndian>(3840)?; // pre-skip
+    w.write_u16::<byteorder::LittleEndian>(OPUS_PRE_SKIP)?; // pre-skip
     w.write_u32::<byteorder::LittleEndian>(sample_rate)?; //  sample-rate in Hz
     w.write_i16::<byteorder::LittleEndian>(0)?; // output gain Q7.8 in dB
     w.write_u8(0)?; // channel map
     Ok(())
 }
 
-fn write_opus_tags<W: std::io::Write>(w: &mut W) -> std::io::Result<()> {
+fn write_opus_tags<W: std::io::Write>(
+    w: &mut W,
+    comments: &[(String, String)],
+) -> std::io::Result<()> {
     use byteorder::WriteBytesExt;
 
     // https://wiki.xiph.org/OggOpus#Comment_Header
@@ -125,7 +197,28 @@ fn write_opus_tags<W: std::io::Write>(w: &mut W) -> std::io::Result<()> {
     w.write_all(b"OpusTags")?;
     w.write_u32::<byteorder::LittleEndian>(vendor.len() as u32)?; // vendor string length
     w.write_all(vendor.as_bytes())?; // vendor string, UTF8 encoded
-    w.write_u32::<byteorder::LittleEndian>(0u32)?; // number of tags
+    w.write_u32::<byteorder::LittleEndian>(comments.len() as u32)?; // number of tags
+    for (key, value) in comments.iter() {
+        let comment = format!("{key}={value}");
+        w.write_u32::<byteorder::LittleEndian>(comment.len() as u32)?; // comment length
+        w.write_all(comment.as_bytes())?; // comment, UTF8 encoded
+    }
+    Ok(())
+}
+
+/// Write the OpusHead and OpusTags packets, each on their own page as required by the spec.
+pub fn write_headers<W: std::io::Write>(
+    pw: &mut ogg::PacketWriter<W>,
+    channels: u8,
+    input_sample_rate: u32,
+    comments: &[(String, String)],
+) -> Result<()> {
+    let mut head = Vec::new();
+    write_opus_header(&mut head, channels, input_sample_rate)?;
+    pw.write_packet(head, 42, ogg::PacketWriteEndInfo::EndPage, 0)?;
+    let mut tags = Vec::new();
+    write_opus_tags(&mut tags, comments)?;
+    pw.write_packet(tags, 42, ogg::PacketWriteEndInfo::EndPage, 0)?;
     Ok(())
 }
 
@@ -137,17 +230,13 @@ fn write_ogg_48khz<W: std::io::Write>(
     pcm: &[f32],
     input_sample_rate: u32,
     stereo: bool,
+    comments: &[(String, String)],
 ) -> Result<()> {
     let mut pw = ogg::PacketWriter::new(w);
     let channels = if stereo { 2 } else { 1 };
 
     // Write the opus headers and tags
-    let mut head = Vec::new();
-    write_opus_header(&mut head, channels as u8, input_sample_rate)?;
-    pw.write_packet(head, 42, ogg::PacketWriteEndInfo::EndPage, 0)?;
-    let mut tags = Vec::new();
-    write_opus_tags(&mut tags)?;
-    pw.write_packet(tags, 42, ogg::PacketWriteEndInfo::EndPage, 0)?;
+    write_headers(&mut pw, channels as u8, input_sample_rate, comments)?;
 
     // Write the actual pcm data
     let mut encoder = {
@@ -156,29 +245,45 @@ fn write_ogg_48khz<W: std::io::Write>(
     };
     let mut out_encoded = vec![0u8; 50_000];
 
+    // The encoder delays its output by `lookahead` samples, prepend some silence so that the
+    // delay matches the pre-skip and pad the end so that the last samples are flushed.
+    let lookahead = encoder.get_lookahead()? as usize;
+    let n_samples = (pcm.len() / channels) as u64;
+    let mut padded = vec![0f32; (OPUS_PRE_SKIP as usize).saturating_sub(lookahead) * channels];
+    padded.extend_from_slice(pcm);
+    let frame_len = OPUS_ENCODER_FRAME_SIZE * channels;
+    let padded_len = (padded.len() + lookahead * channels).div_ceil(frame_len) * frame_len;
+    padded.resize(padded_len, 0.);
+
     let mut total_data = 0;
-    let n_frames = pcm.len() / (channels * OPUS_ENCODER_FRAME_SIZE);
-    for (frame_idx, pcm) in pcm.chunks_exact(OPUS_ENCODER_FRAME_SIZE * channels).enumerate() {
+    let n_frames = padded.len() / frame_len;
+    for (frame_idx, pcm) in padded.chunks_exact(frame_len).enumerate() {
         total_data += (pcm.len() / channels) as u64;
         let size = encoder.encode_float(pcm, &mut out_encoded)?;
         let msg = out_encoded[..size].to_vec();
-        let inf = if frame_idx + 1 == n_frames {
-            ogg::PacketWriteEndInfo::EndPage
+        // The last page uses the actual number of samples so that the padding gets trimmed.
+        if frame_idx + 1 == n_frames {
+            let granule = OPUS_PRE_SKIP as u64 + n_samples;
+            pw.write_packet(msg, 42, ogg::PacketWriteEndInfo::EndStream, granule)?;
         } else {
-            ogg::PacketWriteEndInfo::NormalPacket
-        };
-        pw.write_packet(msg, 42, inf, total_data)?;
+            pw.write_packet(msg, 42, ogg::PacketWriteEndInfo::NormalPacket, total_data)?;
+        }
     }
 
     Ok(())
 }
 
-pub fn write_ogg_mono<W: std::io::Write>(w: &mut W, pcm: &[f32], sample_rate: u32) -> Result<()> {
+pub fn write_ogg_mono<W: std::io::Write>(
+    w: &mut W,
+    pcm: &[f32],
+    sample_rate: u32,
+    comments: &[(String, String)],
+) -> Result<()> {
     if sample_rate == OPUS_SAMPLE_RATE {
-        write_ogg_48khz(w, pcm, sample_rate, false)
+        write_ogg_48khz(w, pcm, sample_rate, false, comments)
     } else {
         let pcm = crate::audio::resample(pcm, sample_rate as usize, OPUS_SAMPLE_RATE as usize)?;
-        write_ogg_48khz(w, &pcm, sample_rate, false)
+        write_ogg_48khz(w, &pcm, sample_rate, false, comments)
     }
 }
 
@@ -187,14 +292,162 @@ pub fn write_ogg_stereo<W: std::io::Write>(
     pcm1: &[f32],
     pcm2: &[f32],
     sample_rate: u32,
+    comments: &[(String, String)],
 ) -> Result<()> {
     if sample_rate == OPUS_SAMPLE_RATE {
         let pcm = pcm1.iter().zip(pcm2.iter()).flat_map(|(s1, s2)| [*s1, *s2]).collect::<Vec<_>>();
-        write_ogg_48khz(w, &pcm, sample_rate, true)
+        write_ogg_48khz(w, &pcm, sample_rate, true, comments)
     } else {
         let pcm1 = crate::audio::resample(pcm1, sample_rate as usize, OPUS_SAMPLE_RATE as usize)?;
         let pcm2 = crate::audio::resample(pcm2, sample_rate as usize, OPUS_SAMPLE_RATE as usize)?;
         let pcm = pcm1.iter().zip(pcm2.iter()).flat_map(|(s1, s2)| [*s1, *s2]).collect::<Vec<_>>();
-        write_ogg_48khz(w, &pcm, sample_rate, true)
+        write_ogg_48khz(w, &pcm, sample_rate, true, comments)
+    }
+}
+
+/// Encodes pcm data incrementally to an ogg/opus stream. The pcm data can be provided in chunks of
+/// any size, a page is written for each chunk so that the stream can be decoded up to the last
+/// flushed chunk even if `finalize` is never called.
+pub struct StreamEncoder<W: std::io::Write> {
+    pw: ogg::PacketWriter<'static, W>,
+    encoder: opus::Encoder,
+    channels: usize,
+    frame_size: usize,
+    pending: Vec<f32>,
+    out_encoded: Vec<u8>,
+    // The granule position is always expressed at 48kHz.
+    granule: u64,
+    n_samples: u64,
+    sample_rate: u32,
+    // The encoder delay, expressed at `sample_rate`.
+    lookahead: usize,
+}
+
+impl<W: std::io::Write> StreamEncoder<W> {
+    /// The sample rate must be one of the rates supported natively by opus, i.e. 8000, 12000,
+    /// 16000, 24000, or 48000.
+    pub fn new(
+        w: W,
+        sample_rate: u32,
+        channels: usize,
+        comments: &[(String, String)],
+    ) -> Result<Self> {
+        if ![8000, 12000, 16000, 24000, 48000].contains(&sample_rate) {
+            anyhow::bail!("unsupported sample rate {sample_rate} for opus streaming")
+        }
+        let opus_channels = match channels {
+            1 => opus::Channels::Mono,
+            2 => opus::Channels::Stereo,
+            c => anyhow::bail!("unexpected number of channels {c}"),
+        };
+        let mut pw = ogg::PacketWriter::new(w);
+        write_headers(&mut pw, channels as u8, sample_rate, comments)?;
+        pw.inner_mut().flush()?;
+        let mut encoder = opus::Encoder::new(sample_rate, opus_channels, opus::Application::Voip)?;
+        // Use 20ms frames, similar to OPUS_ENCODER_FRAME_SIZE at 48kHz.
+        let frame_size = OPUS_ENCODER_FRAME_SIZE * sample_rate as usize / OPUS_SAMPLE_RATE as usize;
+        // Prepend some silence so that the encoder delay matches the pre-skip.
+        let lookahead = encoder.get_lookahead()? as usize;
+        let pre_skip = OPUS_PRE_SKIP as usize * sample_rate as usize / OPUS_SAMPLE_RATE as usize;
+        let pending = vec![0f32; pre_skip.saturating_sub(lookahead) * channels];
+        Ok(Self {
+            pw,
+            encoder,
+            channels,
+            frame_size,
+            pending,
+            out_encoded: vec![0u8; 50_000],
+            granule: 0,
+            n_samples: 0,
+            sample_rate,
+            lookahead,
+        })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn encode_frame(&mut self, pcm: &[f32], end_info: ogg::PacketWriteEndInfo) -> Result<()> {
+        let size = self.encoder.encode_float(pcm, &mut self.out_encoded)?;
+        let msg = self.out_encoded[..size].to_vec();
+        self.granule += OPUS_ENCODER_FRAME_SIZE as u64;
+        // The last page uses the actual number of samples so that the padding gets trimmed.
+        let granule = match end_info {
+            ogg::PacketWriteEndInfo::EndStream => {
+                OPUS_PRE_SKIP as u64
+                    + self.n_samples * OPUS_SAMPLE_RATE as u64 / self.sample_rate as u64
+            }
+            _ => self.granule,
+        };
+        self.pw.write_packet(msg, 42, end_info, granule)?;
+        Ok(())
+    }
+
+    /// The samples are assumed to be interleaved by channel.
+    pub fn write(&mut self, pcm: &[f32]) -> Result<()> {
+        self.n_samples += (pcm.len() / self.channels) as u64;
+        self.pending.extend_from_slice(pcm);
+        let frame_len = self.frame_size * self.channels;
+        let n_frames = self.pending.len() / frame_len;
+        let pending = std::mem::take(&mut self.pending);
+        for (frame_idx, frame) in pending.chunks_exact(frame_len).enumerate() {
+            let end_info = if frame_idx + 1 == n_frames {
+                ogg::PacketWriteEndInfo::EndPage
+            } else {
+                ogg::PacketWriteEndInfo::NormalPacket
+            };
+            self.encode_frame(frame, end_info)?;
+        }
+        self.pending = pending[n_frames * frame_len..].to_vec();
+        Ok(())
+    }
+
+    /// Flushes the pages written so far to the underlying writer.
+    pub fn flush(&mut self) -> Result<()> {
+        self.pw.inner_mut().flush()?;
+        Ok(())
+    }
+
+    /// Encodes the remaining samples, padded with zeros, and marks the end of the stream.
+    pub fn finalize(mut self) -> Result<W> {
+        let mut pending = std::mem::take(&mut self.pending);
+        // The padding has to cover the encoder delay for the last samples to be flushed.
+        let frame_len = self.frame_size * self.channels;
+        let len = (pending.len() + self.lookahead * self.channels).div_ceil(frame_len) * frame_len;
+        pending.resize(len, 0.);
+        let n_frames = pending.len() / frame_len;
+        for (frame_idx, frame) in pending.chunks_exact(frame_len).enumerate() {
+            let end_info = if frame_idx + 1 == n_frames {
+                ogg::PacketWriteEndInfo::EndStream
+            } else {
+                ogg::PacketWriteEndInfo::NormalPacket
+            };
+            self.encode_frame(frame, end_info)?;
+        }
+        let mut w = self.pw.into_inner();
+        w.flush()?;
+        Ok(w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_roundtrip() -> Result<()> {
+        let comments: Vec<(String, String)> = [("SPEAKER", "alice"), ("TITLE", "a=b"), ("X", "")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mut packet = vec![];
+        write_opus_tags(&mut packet, &comments)?;
+        let tags = parse_opus_tags(&packet)?;
+        assert_eq!(tags.vendor, "sphn-pyo3");
+        assert_eq!(tags.comments, comments);
+        // Truncated packets are rejected rather than returning partial comments.
+        assert!(parse_opus_tags(&packet[..packet.len() - 1]).is_err());
+        Ok(())
     }
 }
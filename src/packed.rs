@@ -0,0 +1,213 @@
+// Packed pcm shards, written offline by `pack` and read back by `DatasetReader.from_packed`.
+//
+// The audio files listed in a manifest are decoded, resampled to a common sample rate, and stored
+// one after the other in shard files of bounded size, each file being written as planar pcm data.
+// An `index.json` file lists the shards and, for each audio file, the shard it is stored in, its
+// byte offset, number of channels, and number of samples. Reading a window then only requires a
+// memory map of the shard and no decoding at all.
+use crate::{audio, dataset, par_map, pcm_cache, py_bail, PyRes};
+use anyhow::{Context, Result};
+use numpy::ndarray::Array2;
+use pyo3::prelude::*;
+use std::io::Write;
+use std::path::Path;
+
+const INDEX_FILE: &str = "index.json";
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    pub path: String,
+    shard: usize,
+    offset: u64,
+    channels: usize,
+    num_samples: u64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct Index {
+    sample_rate: u32,
+    dtype: String,
+    shards: Vec<String>,
+    entries: Vec<Entry>,
+}
+
+/// A set of packed shards, memory mapped for reading.
+pub struct Packed {
+    sample_rate: u32,
+    storage: pcm_cache::Storage,
+    shards: Vec<memmap2::Mmap>,
+    entries: Vec<Entry>,
+}
+
+impl Packed {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        let index_path = dir.join(INDEX_FILE);
+        let index =
+            std::fs::read(&index_path).with_context(|| format!("reading {index_path:?}"))?;
+        let index: Index = serde_json::from_slice(&index)?;
+        let storage: pcm_cache::Storage = index.dtype.parse()?;
+        let mut shards = Vec::with_capacity(index.shards.len());
+        for shard in index.shards.iter() {
+            let shard_path = dir.join(shard);
+            let file = std::fs::File::open(&shard_path)
+                .with_context(|| format!("opening {shard_path:?}"))?;
+            // Safety: the shards are not supposed to be modified once written by `pack`.
+            shards.push(unsafe { memmap2::Mmap::map(&file)? });
+        }
+        for entry in index.entries.iter() {
+            let end = (entry.channels as u64)
+                .checked_mul(entry.num_samples)
+                .and_then(|v| v.checked_mul(storage.sample_len() as u64))
+                .and_then(|len| entry.offset.checked_add(len));
+            match (shards.get(entry.shard), end) {
+                (Some(shard), Some(end)) if end <= shard.len() as u64 => {}
+                _ => anyhow::bail!("inconsistent index for {}", entry.path),
+            }
+        }
+        Ok(Self { sample_rate: index.sample_rate, storage, shards, entries: index.entries })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn duration_sec(&self, index: usize) -> f64 {
+        self.entries[index].num_samples as f64 / self.sample_rate as f64
+    }
+
+    /// The pcm data for the entry at `index`.
+    pub fn pcm(&self, index: usize) -> pcm_cache::PlanarPcm<'_> {
+        let entry = &self.entries[index];
+        let data = &self.shards[entry.shard][entry.offset as usize..];
+        pcm_cache::PlanarPcm::new(data, self.storage, entry.channels, entry.num_samples as usize)
+            .expect("index checked when opening")
+    }
+}
+
+/// Decodes and resamples a file, returns its index entry with the shard and offset left to be
+/// filled, together with the encoded pcm data.
+fn decode_for_pack(
+    path: &str,
+    sample_rate: u32,
+    storage: pcm_cache::Storage,
+) -> Result<(Entry, Vec<u8>)> {
+    let mut reader = audio::FileReader::new(path)?;
+    let data: Array2<f32> = reader.decode_all_as()?;
+    let data = if reader.sample_rate() == sample_rate {
+        data
+    } else {
+        audio::resample2(data.view(), reader.sample_rate() as usize, sample_rate as usize)?
+    };
+    let mut bytes = vec![];
+    storage.encode(&data, &mut bytes);
+    let entry = Entry {
+        path: path.to_string(),
+        shard: 0,
+        offset: 0,
+        channels: data.nrows(),
+        num_samples: data.ncols() as u64,
+    };
+    Ok((entry, bytes))
+}
+
+fn pack_files(
+    paths: Vec<String>,
+    out_dir: &Path,
+    sample_rate: u32,
+    storage: pcm_cache::Storage,
+    shard_size_gb: f64,
+    num_threads: usize,
+) -> Result<usize> {
+    std::fs::create_dir_all(out_dir)?;
+    let max_shard_len = (shard_size_gb * 1e9) as u64;
+    let mut index =
+        Index { sample_rate, dtype: storage.as_str().to_string(), shards: vec![], entries: vec![] };
+    // The files are decoded in parallel but come back in order, so that the shard layout only
+    // depends on the manifest.
+    let decoded = par_map::par_map(
+        paths.clone(),
+        num_threads,
+        1,
+        true,
+        1,
+        |_thread_idx| (),
+        move |(), _index, path| decode_for_pack(path, sample_rate, storage),
+    );
+    let mut shard: Option<std::io::BufWriter<std::fs::File>> = None;
+    let mut shard_len = 0u64;
+    for (path, decoded) in paths.iter().zip(decoded) {
+        let (mut entry, bytes) = match decoded? {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                eprintln!("{path}: {err:?}");
+                continue;
+            }
+        };
+        let bytes_len = bytes.len() as u64;
+        if shard.is_none() || (shard_len > 0 && shard_len + bytes_len > max_shard_len) {
+            if let Some(mut shard) = shard.take() {
+                shard.flush()?
+            }
+            let name = format!("shard-{:05}.pcm", index.shards.len());
+            shard = Some(std::io::BufWriter::new(std::fs::File::create(out_dir.join(&name))?));
+            index.shards.push(name);
+            shard_len = 0;
+        }
+        if let Some(shard) = shard.as_mut() {
+            shard.write_all(&bytes)?
+        }
+        entry.shard = index.shards.len() - 1;
+        entry.offset = shard_len;
+        index.entries.push(entry);
+        shard_len += bytes_len;
+    }
+    if let Some(mut shard) = shard.take() {
+        shard.flush()?
+    }
+    // The index is written last, and renamed once complete, so that an interrupted run does not
+    // leave a usable index.
+    let index_path = out_dir.join(INDEX_FILE);
+    let tmp_path = out_dir.join(format!("{INDEX_FILE}.tmp"));
+    let mut index_file = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+    serde_json::to_writer(&mut index_file, &index)?;
+    index_file.flush()?;
+    drop(index_file);
+    std::fs::rename(&tmp_path, &index_path)?;
+    Ok(index.entries.len())
+}
+
+/// Decodes the audio files from a jsonl manifest and writes them as packed pcm shards.
+///
+/// The manifest uses the same format as for `dataset_jsonl`. Each file is resampled to
+/// `sample_rate` and stored as `dtype` data, one of "int16", "int32", "float32", or "float64",
+/// in `out_dir`. The files are written one after the other in shards of at most `shard_size_gb`
+/// gigabytes, a file larger than this gets a shard of its own. An `index.json` file records where
+/// each file is stored and the resulting directory can be read with `DatasetReader.from_packed`.
+/// Files that cannot be decoded are logged and skipped, the number of files that have been packed
+/// is returned.
+#[pyfunction]
+#[pyo3(signature = (manifest_jsonl, out_dir, *, sample_rate, dtype="float32", shard_size_gb=1.0, num_threads=None))]
+pub fn pack(
+    manifest_jsonl: std::path::PathBuf,
+    out_dir: std::path::PathBuf,
+    sample_rate: u32,
+    dtype: &str,
+    shard_size_gb: f64,
+    num_threads: Option<usize>,
+    py: Python,
+) -> PyResult<usize> {
+    if sample_rate == 0 {
+        py_bail!("sample_rate must be positive")
+    }
+    let storage: pcm_cache::Storage = dtype.parse().w()?;
+    let paths = dataset::read_jsonl(&manifest_jsonl).w_f(&manifest_jsonl)?;
+    let paths = paths.into_iter().map(|p| p.path).collect();
+    let num_threads = num_threads.unwrap_or_else(rayon::current_num_threads);
+    py.detach(|| pack_files(paths, &out_dir, sample_rate, storage, shard_size_gb, num_threads))
+        .w_f(&out_dir)
+}
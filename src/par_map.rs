@@ -1,10 +1,21 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::{Arc, Mutex};
 
+/// The results sent by the worker threads, a panic in the mapped function is reported as an
+/// error with the panic message.
+type WorkerResult<T> = Result<T, String>;
+
 struct Sender<T> {
     s: std::sync::mpsc::SyncSender<T>,
     current_len: Arc<std::sync::atomic::AtomicUsize>,
 }
 
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self { s: self.s.clone(), current_len: self.current_len.clone() }
+    }
+}
+
 impl<T> Sender<T> {
     fn send(&self, t: T) -> Result<(), std::sync::mpsc::SendError<T>> {
         self.current_len.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
@@ -38,41 +49,156 @@ fn sync_channel<T>(channel_len: usize) -> (Sender<T>, Receiver<T>) {
     (s, r)
 }
 
+/// How the worker threads pick the next index to process. The indexes are handed out in blocks
+/// of `block_len` consecutive indexes, so that a thread processes runs of neighbouring values.
+enum WorkQueue {
+    /// Thread `i` processes the blocks `i`, `i + step`, `i + 2 * step`, ... so that the results
+    /// can be read in order by cycling over the threads block after block.
+    Strided { block: usize, offset: usize, step: usize, block_len: usize },
+    /// The threads share a counter and process the next block that has not been picked yet.
+    Shared { next: Arc<AtomicUsize>, index: usize, remaining: usize, block_len: usize },
+}
+
+impl WorkQueue {
+    fn next(&mut self) -> usize {
+        match self {
+            Self::Strided { block, offset, step, block_len } => {
+                let index = block.saturating_mul(*block_len).saturating_add(*offset);
+                *offset += 1;
+                if *offset == *block_len {
+                    *offset = 0;
+                    *block = block.saturating_add(*step);
+                }
+                index
+            }
+            Self::Shared { next, index, remaining, block_len } => {
+                if *remaining == 0 {
+                    *index = next.fetch_add(*block_len, std::sync::atomic::Ordering::SeqCst);
+                    *remaining = *block_len;
+                }
+                let res = *index;
+                *index = index.saturating_add(1);
+                *remaining -= 1;
+                res
+            }
+        }
+    }
+}
+
+/// Creates the channels used by the worker threads, when `ordered` is set each thread gets its
+/// own channel, otherwise all the threads send their results on a single channel.
+fn channels<T>(
+    nthreads: usize,
+    channel_len: usize,
+    ordered: bool,
+    block_len: usize,
+) -> (Vec<Sender<T>>, Vec<Receiver<T>>, Vec<WorkQueue>) {
+    if ordered {
+        let (senders, receivers) = (0..nthreads).map(|_| sync_channel(channel_len)).unzip();
+        let queues = (0..nthreads)
+            .map(|i| WorkQueue::Strided { block: i, offset: 0, step: nthreads, block_len })
+            .collect();
+        (senders, receivers, queues)
+    } else {
+        let (sender, receiver) = sync_channel(channel_len * nthreads);
+        let next = Arc::new(AtomicUsize::new(0));
+        let senders = (0..nthreads).map(|_| sender.clone()).collect();
+        let queues = (0..nthreads)
+            .map(|_| WorkQueue::Shared { next: next.clone(), index: 0, remaining: 0, block_len })
+            .collect();
+        (senders, vec![receiver], queues)
+    }
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+/// Spawns a worker thread that sends the values returned by `next` until it returns `None`, the
+/// receiving side is dropped, or the work gets cancelled. If `next` panics, the panic message is
+/// sent and the thread stops.
+fn spawn_worker<U, F>(
+    sender: Sender<WorkerResult<U>>,
+    cancelled: Arc<AtomicBool>,
+    mut next: F,
+) -> std::thread::JoinHandle<()>
+where
+    U: Send + 'static,
+    F: FnMut() -> Option<U> + Send + 'static,
+{
+    std::thread::spawn(move || {
+        while !cancelled.load(std::sync::atomic::Ordering::SeqCst) {
+            let res = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(&mut next)) {
+                Ok(None) => break,
+                Ok(Some(res)) => Ok(res),
+                Err(payload) => Err(panic_message(payload)),
+            };
+            let panicked = res.is_err();
+            if sender.send(res).is_err() || panicked {
+                break;
+            }
+        }
+    })
+}
+
 pub struct ParMap<T> {
     cnt: usize,
     len: usize,
-    receivers: Mutex<Vec<Receiver<T>>>,
+    block_len: usize,
+    receivers: Mutex<Vec<Receiver<WorkerResult<T>>>>,
+    cancelled: Arc<AtomicBool>,
+    handles: Vec<std::thread::JoinHandle<()>>,
 }
 
+/// Applies `op` to all the values using `nthreads` threads, each thread first creates its state
+/// using `init`. When `ordered` is set, the results are returned in the same order as the values,
+/// otherwise they are returned as soon as they are available so that a slow value does not
+/// prevent the results computed by the other threads from being consumed.
+///
+/// The values are handed out to the threads in blocks of `block_len` consecutive values, this
+/// lets a thread reuse its state across neighbouring values. In ordered mode, `channel_len` should
+/// be at least `block_len` for all the threads to be kept busy.
 pub fn par_map<
     T: Send + Sync + 'static,
+    S: Send + 'static,
     U: Send + 'static,
-    OP: Fn(usize, &T) -> U + Send + Sync + 'static,
+    INIT: Fn(usize) -> S + Send + Sync + 'static,
+    OP: Fn(&mut S, usize, &T) -> U + Send + Sync + 'static,
 >(
     values: Vec<T>,
     nthreads: usize,
     channel_len: usize,
+    ordered: bool,
+    block_len: usize,
+    init: INIT,
     op: OP,
 ) -> ParMap<U> {
     let values = Arc::new(values);
-    let nthreads = usize::min(nthreads, values.len());
-    let mut receivers = Vec::with_capacity(nthreads);
+    let block_len = block_len.max(1);
+    let nthreads = usize::min(nthreads, values.len().div_ceil(block_len));
+    let (senders, receivers, queues) = channels(nthreads, channel_len, ordered, block_len);
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let mut handles = Vec::with_capacity(nthreads);
     let op = Arc::new(op);
-    for thread_idx in 0..nthreads {
+    for (thread_idx, (sender, mut queue)) in senders.into_iter().zip(queues).enumerate() {
         let values = values.clone();
         let op = op.clone();
-        let (sender, receiver) = sync_channel(channel_len);
-        receivers.push(receiver);
-        let _handle = std::thread::spawn(move || {
-            for (index, value) in values.iter().enumerate().skip(thread_idx).step_by(nthreads) {
-                let res = op(index, value);
-                if sender.send(res).is_err() {
-                    break;
-                }
-            }
+        let mut state = init(thread_idx);
+        let handle = spawn_worker(sender, cancelled.clone(), move || {
+            let index = queue.next();
+            let value = values.get(index)?;
+            Some(op(&mut state, index, value))
         });
+        handles.push(handle)
     }
-    ParMap { cnt: 0, len: values.len(), receivers: Mutex::new(receivers) }
+    let receivers = Mutex::new(receivers);
+    ParMap { cnt: 0, len: values.len(), block_len, receivers, cancelled, handles }
 }
 
 pub fn par_range<
@@ -84,43 +210,53 @@ pub fn par_range<
     len: Option<usize>,
     nthreads: usize,
     channel_len: usize,
+    ordered: bool,
     init: INIT,
     op: OP,
 ) -> ParMap<U> {
     let len = len.unwrap_or(usize::MAX);
     let nthreads = usize::min(len, nthreads);
-    let mut receivers = Vec::with_capacity(nthreads);
+    let (senders, receivers, queues) = channels(nthreads, channel_len, ordered, 1);
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let mut handles = Vec::with_capacity(nthreads);
     let op = Arc::new(op);
-    for thread_index in 0..nthreads {
+    for (thread_index, (sender, mut queue)) in senders.into_iter().zip(queues).enumerate() {
         let op = op.clone();
-        let (sender, receiver) = sync_channel(channel_len);
-        receivers.push(receiver);
-        let mut index = 0;
         let mut t = init(thread_index);
-        let _handle = std::thread::spawn(move || loop {
-            if len <= index {
-                break;
+        let handle = spawn_worker(sender, cancelled.clone(), move || {
+            if len <= queue.next() {
+                return None;
             }
-            let res = op(&mut t);
-            if sender.send(res).is_err() {
-                break;
-            }
-            index += nthreads;
+            Some(op(&mut t))
         });
+        handles.push(handle)
     }
-    ParMap { cnt: 0, len, receivers: receivers.into() }
+    ParMap { cnt: 0, len, block_len: 1, receivers: receivers.into(), cancelled, handles }
 }
 
 impl<T> Iterator for ParMap<T> {
-    type Item = T;
+    type Item = anyhow::Result<T>;
     fn next(&mut self) -> Option<Self::Item> {
         if self.cnt >= self.len {
-            None
-        } else {
+            return None;
+        }
+        let res = {
             let receivers = self.receivers.lock().unwrap();
-            let thread_idx = self.cnt % receivers.len();
+            // The receivers are removed once cancelled.
+            if receivers.is_empty() {
+                return None;
+            }
+            let thread_idx = (self.cnt / self.block_len) % receivers.len();
             self.cnt += 1;
-            receivers[thread_idx].recv().ok()
+            receivers[thread_idx].recv().ok()?
+        };
+        match res {
+            Ok(res) => Some(Ok(res)),
+            Err(msg) => {
+                // Stop the other workers, the iteration ends after reporting the panic.
+                self.cancel();
+                Some(Err(anyhow::format_err!("worker thread panicked: {msg}")))
+            }
         }
     }
 }
@@ -129,4 +265,116 @@ impl<T> ParMap<T> {
     pub fn buffered_lens(&self) -> Vec<usize> {
         self.receivers.lock().unwrap().iter().map(|r| r.current_len()).collect()
     }
+
+    /// Signals the worker threads to stop and drops the pending results. The threads exit after
+    /// the value that they are currently processing, if any.
+    pub fn cancel(&mut self) {
+        self.cancelled.store(true, std::sync::atomic::Ordering::SeqCst);
+        // Dropping the receivers unblocks the threads waiting for some space in their channel.
+        self.receivers.lock().unwrap().clear();
+    }
+
+    /// Cancels the work and waits for all the worker threads to exit.
+    pub fn close(&mut self) {
+        self.cancel();
+        for handle in self.handles.drain(..) {
+            // The panics are caught in the worker threads so joining cannot fail.
+            let _ = handle.join();
+        }
+    }
+
+    /// Cancels the work without waiting for the worker threads, these exit on their own after the
+    /// value that they are currently processing.
+    pub fn detach(&mut self) {
+        self.cancel();
+        self.handles.clear();
+    }
+}
+
+impl<T> Drop for ParMap<T> {
+    // Joining can deadlock if a worker needs the GIL while it is held by the dropping thread, the
+    // GIL should be released first or `detach` be used.
+    fn drop(&mut self) {
+        self.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn ordered_and_unordered() -> anyhow::Result<()> {
+        let values: Vec<usize> = (0..1000).collect();
+        for block_len in [1, 3] {
+            let pm = par_map(values.clone(), 4, 2, true, block_len, |_| (), |(), _, v| v * 2);
+            let res = pm.collect::<anyhow::Result<Vec<_>>>()?;
+            assert_eq!(res, values.iter().map(|v| v * 2).collect::<Vec<_>>());
+
+            let pm = par_map(values.clone(), 4, 2, false, block_len, |_| (), |(), _, v| v * 2);
+            let mut res = pm.collect::<anyhow::Result<Vec<_>>>()?;
+            res.sort();
+            assert_eq!(res, values.iter().map(|v| v * 2).collect::<Vec<_>>());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn panics_are_reported() {
+        for ordered in [true, false] {
+            let pm = par_map(
+                (0..1000).collect(),
+                4,
+                2,
+                ordered,
+                1,
+                |_| (),
+                |(), _, &v: &usize| {
+                    if v == 37 {
+                        panic!("failed on {v}")
+                    }
+                    v
+                },
+            );
+            let res: Vec<_> = pm.collect();
+            let err = res.last().unwrap().as_ref().unwrap_err();
+            assert_eq!(err.to_string(), "worker thread panicked: failed on 37");
+            assert!(res[..res.len() - 1].iter().all(|v| v.is_ok()));
+        }
+    }
+
+    /// Counts the worker threads that have exited, the state is dropped when its thread exits.
+    struct ExitCounter(Arc<AtomicUsize>);
+
+    impl Drop for ExitCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn drop_joins_workers() -> anyhow::Result<()> {
+        for ordered in [true, false] {
+            let exited = Arc::new(AtomicUsize::new(0));
+            let mut pm = {
+                let exited = exited.clone();
+                par_map(
+                    (0..1000).collect(),
+                    4,
+                    2,
+                    ordered,
+                    1,
+                    move |_| ExitCounter(exited.clone()),
+                    |_, _, v: &usize| *v,
+                )
+            };
+            for _ in 0..5 {
+                pm.next().unwrap()?;
+            }
+            drop(pm);
+            assert_eq!(exited.load(Ordering::SeqCst), 4);
+        }
+        Ok(())
+    }
 }
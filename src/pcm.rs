@@ -0,0 +1,344 @@
+// A format reader for uncompressed pcm data stored at a known location of a file. This is used
+// for RF64/BW64 wav files which are not supported by symphonia, and for headerless pcm files.
+use numpy::ndarray::Array2;
+use std::io::Seek;
+use symphonia::core::audio::Channels;
+use symphonia::core::codecs::{CodecParameters, CodecType};
+use symphonia::core::errors::{
+    end_of_stream_error, seek_error, unsupported_error, Result, SeekErrorKind,
+};
+use symphonia::core::formats::{Cue, FormatOptions, FormatReader, Packet, SeekMode};
+use symphonia::core::formats::{SeekTo, SeekedTo, Track};
+use symphonia::core::io::{MediaSource, MediaSourceStream, ReadBytes};
+use symphonia::core::meta::{Metadata, MetadataLog};
+use symphonia::core::units::TimeBase;
+
+// The number of frames returned in each packet, similar to the symphonia wav reader.
+const MAX_FRAMES_PER_PACKET: u64 = 1152;
+
+/// The layout of the pcm data.
+#[derive(Debug, Clone, Copy)]
+pub struct PcmFormat {
+    pub codec: CodecType,
+    pub channels: usize,
+    pub sample_rate: u32,
+    pub bits_per_sample: u32,
+}
+
+impl PcmFormat {
+    /// Returns the format for a wav format tag, 1 for integer pcm and 3 for floats.
+    fn from_wav_format_tag(
+        format_tag: u16,
+        channels: usize,
+        sample_rate: u32,
+        bits_per_sample: u32,
+    ) -> Result<Self> {
+        use symphonia::core::codecs as c;
+        let codec = match (format_tag, bits_per_sample) {
+            (1, 8) => c::CODEC_TYPE_PCM_U8,
+            (1, 16) => c::CODEC_TYPE_PCM_S16LE,
+            (1, 24) => c::CODEC_TYPE_PCM_S24LE,
+            (1, 32) => c::CODEC_TYPE_PCM_S32LE,
+            (3, 32) => c::CODEC_TYPE_PCM_F32LE,
+            (3, 64) => c::CODEC_TYPE_PCM_F64LE,
+            _ => return unsupported_error("pcm: unsupported wav format"),
+        };
+        Ok(Self { codec, channels, sample_rate, bits_per_sample })
+    }
+
+    /// Returns the format for headerless pcm data, `dtype` describes the samples, e.g. "s16le" or
+    /// "f32be", and the data is interleaved by channel.
+    pub fn raw(dtype: &str, channels: usize, sample_rate: u32) -> anyhow::Result<Self> {
+        use symphonia::core::codecs as c;
+        let (codec, bits_per_sample) = match dtype {
+            "u8" => (c::CODEC_TYPE_PCM_U8, 8),
+            "s8" => (c::CODEC_TYPE_PCM_S8, 8),
+            "s16le" => (c::CODEC_TYPE_PCM_S16LE, 16),
+            "s16be" => (c::CODEC_TYPE_PCM_S16BE, 16),
+            "s24le" => (c::CODEC_TYPE_PCM_S24LE, 24),
+            "s24be" => (c::CODEC_TYPE_PCM_S24BE, 24),
+            "s32le" => (c::CODEC_TYPE_PCM_S32LE, 32),
+            "s32be" => (c::CODEC_TYPE_PCM_S32BE, 32),
+            "f32le" => (c::CODEC_TYPE_PCM_F32LE, 32),
+            "f32be" => (c::CODEC_TYPE_PCM_F32BE, 32),
+            "f64le" => (c::CODEC_TYPE_PCM_F64LE, 64),
+            "f64be" => (c::CODEC_TYPE_PCM_F64BE, 64),
+            _ => anyhow::bail!("unsupported dtype '{dtype}'"),
+        };
+        if channels == 0 {
+            anyhow::bail!("the number of channels must be positive")
+        }
+        if sample_rate == 0 {
+            anyhow::bail!("the sample rate must be positive")
+        }
+        Ok(Self { codec, channels, sample_rate, bits_per_sample })
+    }
+
+    pub fn frame_len(&self) -> u64 {
+        self.channels as u64 * self.bits_per_sample as u64 / 8
+    }
+
+    fn is_big_endian(&self) -> bool {
+        use symphonia::core::codecs as c;
+        [c::CODEC_TYPE_PCM_S16BE, c::CODEC_TYPE_PCM_S24BE, c::CODEC_TYPE_PCM_S32BE]
+            .into_iter()
+            .chain([c::CODEC_TYPE_PCM_F32BE, c::CODEC_TYPE_PCM_F64BE])
+            .any(|codec| codec == self.codec)
+    }
+
+    fn is_float(&self) -> bool {
+        use symphonia::core::codecs as c;
+        [c::CODEC_TYPE_PCM_F32LE, c::CODEC_TYPE_PCM_F32BE, c::CODEC_TYPE_PCM_F64LE]
+            .into_iter()
+            .chain([c::CODEC_TYPE_PCM_F64BE])
+            .any(|codec| codec == self.codec)
+    }
+
+    /// Converts some interleaved pcm data to an array of shape `(channels, samples)`, trailing
+    /// bytes that do not form a complete frame are ignored.
+    pub fn decode(&self, data: &[u8]) -> Array2<f32> {
+        let sample_len = self.bits_per_sample as usize / 8;
+        let n_frames = data.len() / self.frame_len() as usize;
+        let mut pcm = Array2::zeros((self.channels, n_frames));
+        let (is_big_endian, is_float) = (self.is_big_endian(), self.is_float());
+        let is_unsigned = self.codec == symphonia::core::codecs::CODEC_TYPE_PCM_U8;
+        for (i, sample) in data.chunks_exact(sample_len).take(n_frames * self.channels).enumerate()
+        {
+            // Put the sample bytes in big endian order in the most significant bytes.
+            let mut bytes = [0u8; 8];
+            bytes[..sample_len].copy_from_slice(sample);
+            if !is_big_endian {
+                bytes[..sample_len].reverse()
+            }
+            let v = u64::from_be_bytes(bytes);
+            let v = match (is_float, sample_len) {
+                (true, 4) => f32::from_bits((v >> 32) as u32),
+                (true, _) => f64::from_bits(v) as f32,
+                (false, _) if is_unsigned => ((v >> 56) as f32 - 128.) / 128.,
+                // Signed integers are sign extended by the arithmetic shift.
+                (false, _) => {
+                    (v as i64 >> (64 - 8 * sample_len)) as f32
+                        / (1u64 << (8 * sample_len - 1)) as f32
+                }
+            };
+            pcm[(i % self.channels, i / self.channels)] = v
+        }
+        pcm
+    }
+
+    /// Converts one slice of floats per channel to some interleaved pcm data. Integer samples are
+    /// clamped between -1 and 1.
+    pub fn encode(&self, pcm: &[&[f32]]) -> anyhow::Result<Vec<u8>> {
+        if pcm.len() != self.channels {
+            anyhow::bail!("expected {} channels, got {}", self.channels, pcm.len())
+        }
+        let n_frames = pcm.iter().map(|c| c.len()).min().unwrap_or(0);
+        let sample_len = self.bits_per_sample as usize / 8;
+        let mut data = Vec::with_capacity(n_frames * self.frame_len() as usize);
+        let (is_big_endian, is_float) = (self.is_big_endian(), self.is_float());
+        let is_unsigned = self.codec == symphonia::core::codecs::CODEC_TYPE_PCM_U8;
+        for i in 0..n_frames {
+            for c in pcm.iter() {
+                let v = c[i];
+                let bytes = match (is_float, sample_len) {
+                    (true, 4) => (v.to_bits() as u64) << 32,
+                    (true, _) => (v as f64).to_bits(),
+                    (false, _) if is_unsigned => ((v.clamp(-1., 1.) * 127.) as i64 + 128) as u64,
+                    (false, _) => {
+                        let max = ((1u64 << (8 * sample_len - 1)) - 1) as f64;
+                        ((v.clamp(-1., 1.) as f64 * max) as i64 as u64) << (64 - 8 * sample_len)
+                    }
+                };
+                let bytes = if is_unsigned { bytes << 56 } else { bytes };
+                let bytes = bytes.to_be_bytes();
+                let bytes = &bytes[..sample_len];
+                if is_big_endian {
+                    data.extend_from_slice(bytes)
+                } else {
+                    data.extend(bytes.iter().rev())
+                }
+            }
+        }
+        Ok(data)
+    }
+}
+
+pub struct PcmReader {
+    reader: MediaSourceStream,
+    tracks: Vec<Track>,
+    cues: Vec<Cue>,
+    metadata: MetadataLog,
+    frame_len: u64,
+    data_start: u64,
+    data_end: u64,
+}
+
+impl PcmReader {
+    /// Creates a reader for the pcm data starting at `data_start` in the source. When `data_len`
+    /// is not specified, the data extends to the end of the source.
+    pub fn new(
+        mut reader: MediaSourceStream,
+        format: &PcmFormat,
+        data_start: u64,
+        data_len: Option<u64>,
+    ) -> Result<Self> {
+        let channels = Channels::from_bits_truncate(((1u64 << format.channels.min(32)) - 1) as u32);
+        if format.channels == 0 || channels.count() != format.channels {
+            return unsupported_error("pcm: unsupported number of channels");
+        }
+        let frame_len = format.frame_len();
+        let data_len = match data_len {
+            Some(data_len) => data_len,
+            None => match reader.byte_len() {
+                Some(byte_len) => byte_len.saturating_sub(data_start),
+                None => return unsupported_error("pcm: unknown data length"),
+            },
+        };
+        let pos = reader.pos();
+        if pos > data_start {
+            reader.seek(std::io::SeekFrom::Start(data_start))?;
+        } else {
+            reader.ignore_bytes(data_start - pos)?;
+        }
+        let mut codec_params = CodecParameters::new();
+        codec_params
+            .for_codec(format.codec)
+            .with_sample_rate(format.sample_rate)
+            .with_time_base(TimeBase::new(1, format.sample_rate))
+            .with_n_frames(data_len / frame_len)
+            .with_channels(channels)
+            .with_bits_per_sample(format.bits_per_sample)
+            .with_bits_per_coded_sample(format.bits_per_sample)
+            .with_max_frames_per_packet(MAX_FRAMES_PER_PACKET)
+            .with_frames_per_block(1);
+        Ok(Self {
+            reader,
+            tracks: vec![Track::new(0, codec_params)],
+            cues: Vec::new(),
+            metadata: Default::default(),
+            frame_len,
+            data_start,
+            data_end: data_start + data_len,
+        })
+    }
+}
+
+impl FormatReader for PcmReader {
+    /// Reads a RF64 or BW64 wav file, the data length is read from the ds64 chunk.
+    fn try_new(mut source: MediaSourceStream, _options: &FormatOptions) -> Result<Self> {
+        let marker = source.read_quad_bytes()?;
+        if &marker != b"RF64" && &marker != b"BW64" {
+            return unsupported_error("pcm: missing rf64 stream marker");
+        }
+        let _riff_len = source.read_u32()?;
+        if &source.read_quad_bytes()? != b"WAVE" {
+            return unsupported_error("pcm: riff form is not wave");
+        }
+        let mut ds64_data_len = None;
+        let mut format = None;
+        loop {
+            let id = source.read_quad_bytes()?;
+            let len = source.read_u32()? as u64;
+            match &id {
+                b"ds64" => {
+                    let _riff_len = source.read_u64()?;
+                    ds64_data_len = Some(source.read_u64()?);
+                    source.ignore_bytes(len.saturating_sub(16))?;
+                }
+                b"fmt " => {
+                    let mut fmt = vec![0u8; len as usize];
+                    source.read_buf_exact(&mut fmt)?;
+                    if fmt.len() < 16 {
+                        return unsupported_error("pcm: invalid fmt chunk");
+                    }
+                    let u16_at = |p: usize| u16::from_le_bytes([fmt[p], fmt[p + 1]]);
+                    let sample_rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+                    let mut format_tag = u16_at(0);
+                    // WAVE_FORMAT_EXTENSIBLE, the actual format tag is at the start of the GUID.
+                    if format_tag == 0xFFFE && fmt.len() >= 26 {
+                        format_tag = u16_at(24)
+                    }
+                    let channels = u16_at(2) as usize;
+                    let bits_per_sample = u16_at(14) as u32;
+                    format = Some(PcmFormat::from_wav_format_tag(
+                        format_tag,
+                        channels,
+                        sample_rate,
+                        bits_per_sample,
+                    )?);
+                }
+                b"data" => {
+                    let format = match format {
+                        None => return unsupported_error("pcm: missing fmt chunk"),
+                        Some(format) => format,
+                    };
+                    let data_len = match (len, ds64_data_len) {
+                        (0xFFFF_FFFF, Some(data_len)) => data_len,
+                        _ => len,
+                    };
+                    let data_start = source.pos();
+                    return Self::new(source, &format, data_start, Some(data_len));
+                }
+                _ => source.ignore_bytes(len)?,
+            }
+            // Chunks are aligned on two bytes.
+            if len % 2 == 1 {
+                source.ignore_bytes(1)?
+            }
+        }
+    }
+
+    fn next_packet(&mut self) -> Result<Packet> {
+        let pos = self.reader.pos();
+        let n_frames = self.data_end.saturating_sub(pos) / self.frame_len;
+        if n_frames == 0 {
+            return end_of_stream_error();
+        }
+        let n_frames = n_frames.min(MAX_FRAMES_PER_PACKET);
+        let data = self.reader.read_boxed_slice_exact((n_frames * self.frame_len) as usize)?;
+        let ts = (pos - self.data_start) / self.frame_len;
+        Ok(Packet::new_from_boxed_slice(0, ts, n_frames, data))
+    }
+
+    fn metadata(&mut self) -> Metadata<'_> {
+        self.metadata.metadata()
+    }
+
+    fn cues(&self) -> &[Cue] {
+        &self.cues
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    /// Seeking is sample accurate and does not require reading any data.
+    fn seek(&mut self, _mode: SeekMode, to: SeekTo) -> Result<SeekedTo> {
+        let params = &self.tracks[0].codec_params;
+        let ts = match to {
+            SeekTo::TimeStamp { ts, .. } => ts,
+            SeekTo::Time { time, .. } => match params.time_base {
+                Some(time_base) => time_base.calc_timestamp(time),
+                None => return seek_error(SeekErrorKind::Unseekable),
+            },
+        };
+        if params.n_frames.is_some_and(|n_frames| ts > n_frames) {
+            return seek_error(SeekErrorKind::OutOfRange);
+        }
+        let seek_pos = self.data_start + ts * self.frame_len;
+        if self.reader.is_seekable() {
+            self.reader.seek(std::io::SeekFrom::Start(seek_pos))?;
+        } else {
+            let pos = self.reader.pos();
+            if seek_pos < pos {
+                return seek_error(SeekErrorKind::ForwardOnly);
+            }
+            self.reader.ignore_bytes(seek_pos - pos)?;
+        }
+        Ok(SeekedTo { track_id: 0, actual_ts: ts, required_ts: ts })
+    }
+
+    fn into_inner(self: Box<Self>) -> MediaSourceStream {
+        self.reader
+    }
+}
@@ -0,0 +1,349 @@
+// On-disk cache of decoded pcm data, used by the dataset readers so that the files only get
+// decoded once when iterating over the same data for multiple epochs.
+//
+// Each audio file is decoded in full the first time it is needed and stored in its own cache file
+// named after a hash of the path, size, modification time, and decoding options, so that stale
+// entries are not reused when the source file changes. A cache file starts with a fixed size
+// header followed by the samples of each channel one after the other. The cache files are written
+// to a temporary location and renamed once complete, so concurrent workers can build the cache
+// without locking, the content is then accessed through a memory map.
+use crate::audio;
+use anyhow::{Context, Result};
+use numpy::ndarray::Array2;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use symphonia::core::conv::FromSample;
+
+const MAGIC: &[u8; 8] = b"SPHNPCM\0";
+const HEADER_LEN: usize = 32;
+
+/// The sample type used in the cache files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    Int16,
+    Int32,
+    Float32,
+    Float64,
+}
+
+impl Storage {
+    /// The data is stored using the requested dtype so that reading it back is lossless.
+    pub fn for_dtype(dtype: audio::Dtype) -> Self {
+        match dtype {
+            audio::Dtype::Int16 => Self::Int16,
+            audio::Dtype::Int32 => Self::Int32,
+            audio::Dtype::Float32 => Self::Float32,
+            audio::Dtype::Float64 => Self::Float64,
+        }
+    }
+
+    fn code(&self) -> u32 {
+        match self {
+            Self::Int16 => 0,
+            Self::Float32 => 1,
+            Self::Int32 => 2,
+            Self::Float64 => 3,
+        }
+    }
+
+    fn from_code(code: u32) -> Result<Self> {
+        match code {
+            0 => Ok(Self::Int16),
+            1 => Ok(Self::Float32),
+            2 => Ok(Self::Int32),
+            3 => Ok(Self::Float64),
+            _ => anyhow::bail!("unknown sample type {code} in pcm cache file"),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Int16 => "int16",
+            Self::Int32 => "int32",
+            Self::Float32 => "float32",
+            Self::Float64 => "float64",
+        }
+    }
+
+    pub fn sample_len(&self) -> usize {
+        match self {
+            Self::Int16 => 2,
+            Self::Int32 | Self::Float32 => 4,
+            Self::Float64 => 8,
+        }
+    }
+
+    /// Appends the samples of `data` to `out`, channel after channel.
+    pub fn encode(&self, data: &Array2<f32>, out: &mut Vec<u8>) {
+        out.reserve(data.len() * self.sample_len());
+        match self {
+            Self::Int16 => data.iter().for_each(|v| out.extend(i16::from_sample(*v).to_le_bytes())),
+            Self::Int32 => data.iter().for_each(|v| out.extend(i32::from_sample(*v).to_le_bytes())),
+            Self::Float32 => data.iter().for_each(|v| out.extend(v.to_le_bytes())),
+            Self::Float64 => data.iter().for_each(|v| out.extend((*v as f64).to_le_bytes())),
+        }
+    }
+}
+
+impl std::str::FromStr for Storage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "int16" => Ok(Self::Int16),
+            "int32" => Ok(Self::Int32),
+            "float32" => Ok(Self::Float32),
+            "float64" => Ok(Self::Float64),
+            _ => anyhow::bail!(
+                "unsupported storage dtype '{s}', expected int16, int32, float32, or float64"
+            ),
+        }
+    }
+}
+
+/// Planar pcm data stored in a byte buffer, the samples of each channel are contiguous.
+#[derive(Clone, Copy)]
+pub struct PlanarPcm<'a> {
+    data: &'a [u8],
+    storage: Storage,
+    channels: usize,
+    len: usize,
+}
+
+impl<'a> PlanarPcm<'a> {
+    pub fn new(data: &'a [u8], storage: Storage, channels: usize, len: usize) -> Result<Self> {
+        if data.len() < channels * len * storage.sample_len() {
+            anyhow::bail!("pcm buffer is too small for {channels} channels of {len} samples")
+        }
+        Ok(Self { data, storage, channels, len })
+    }
+
+    /// Reads `num_samples` samples per channel starting at `start_sample`, this has the same
+    /// semantics as `audio::FileReader::decode_samples_dtype`.
+    pub fn read_dtype(
+        &self,
+        start_sample: u64,
+        num_samples: usize,
+        pad_with_zeros: bool,
+        dtype: audio::Dtype,
+    ) -> (audio::Pcm, usize) {
+        match dtype {
+            audio::Dtype::Int16 => {
+                let (d, l) = self.read(start_sample, num_samples, pad_with_zeros);
+                (audio::Pcm::Int16(d), l)
+            }
+            audio::Dtype::Int32 => {
+                let (d, l) = self.read(start_sample, num_samples, pad_with_zeros);
+                (audio::Pcm::Int32(d), l)
+            }
+            audio::Dtype::Float32 => {
+                let (d, l) = self.read(start_sample, num_samples, pad_with_zeros);
+                (audio::Pcm::Float32(d), l)
+            }
+            audio::Dtype::Float64 => {
+                let (d, l) = self.read(start_sample, num_samples, pad_with_zeros);
+                (audio::Pcm::Float64(d), l)
+            }
+        }
+    }
+
+    pub fn read<T: audio::OutputSample>(
+        &self,
+        start_sample: u64,
+        num_samples: usize,
+        pad_with_zeros: bool,
+    ) -> (Array2<T>, usize) {
+        let start = usize::min(start_sample as usize, self.len);
+        let unpadded_len = usize::min(num_samples, self.len - start);
+        let out_len = if pad_with_zeros { num_samples } else { unpadded_len };
+        let sample_len = self.storage.sample_len();
+        let mut data = vec![T::default(); self.channels * out_len];
+        for (c, out) in data.chunks_exact_mut(out_len.max(1)).enumerate().take(self.channels) {
+            let offset = (c * self.len + start) * sample_len;
+            let src = &self.data[offset..offset + unpadded_len * sample_len];
+            let out = &mut out[..unpadded_len];
+            match self.storage {
+                Storage::Int16 => {
+                    for (dst, src) in out.iter_mut().zip(src.chunks_exact(2)) {
+                        *dst = T::from_sample(i16::from_le_bytes([src[0], src[1]]))
+                    }
+                }
+                Storage::Int32 => {
+                    for (dst, src) in out.iter_mut().zip(src.chunks_exact(4)) {
+                        *dst = T::from_sample(i32::from_le_bytes([src[0], src[1], src[2], src[3]]))
+                    }
+                }
+                Storage::Float32 => {
+                    for (dst, src) in out.iter_mut().zip(src.chunks_exact(4)) {
+                        *dst = T::from_sample(f32::from_le_bytes([src[0], src[1], src[2], src[3]]))
+                    }
+                }
+                Storage::Float64 => {
+                    for (dst, src) in out.iter_mut().zip(src.chunks_exact(8)) {
+                        *dst = T::from_sample(f64::from_le_bytes(src.try_into().unwrap()))
+                    }
+                }
+            }
+        }
+        let data =
+            Array2::from_shape_vec((self.channels, out_len), data).expect("consistent pcm buffer");
+        (data, unpadded_len)
+    }
+}
+
+/// The decoded content of an audio file, memory mapped from the cache.
+pub struct CachedPcm {
+    mmap: memmap2::Mmap,
+    storage: Storage,
+    sample_rate: u32,
+    channels: usize,
+    len: usize,
+}
+
+fn cache_key(path: &str, opts: &audio::OpenOptions, storage: Storage) -> Result<String> {
+    use md5::Digest;
+
+    let file_len = crate::archive::file_len(path)?;
+    let mtime = crate::archive::modified(path).ok();
+    let key =
+        format!("{path}\0{file_len}\0{mtime:?}\0{:?}\0{:?}\0{storage:?}", opts.raw, opts.track);
+    let digest = md5::Md5::digest(key.as_bytes());
+    Ok(digest.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+impl CachedPcm {
+    /// Opens the cache file for `path` in `cache_dir`, decoding the audio file and writing the
+    /// cache file first if needed.
+    pub fn open_or_build(
+        cache_dir: &Path,
+        path: &str,
+        opts: &audio::OpenOptions,
+        storage: Storage,
+    ) -> Result<Self> {
+        let cache_path = cache_dir.join(format!("{}.pcm", cache_key(path, opts, storage)?));
+        if !cache_path.exists() {
+            build(&cache_path, path, opts, storage)?
+        }
+        Self::open(&cache_path).with_context(|| format!("reading {cache_path:?}"))
+    }
+
+    fn open(cache_path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(cache_path)?;
+        // Safety: the cache files are never modified once renamed to their final location.
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        if mmap.len() < HEADER_LEN || &mmap[..8] != MAGIC {
+            anyhow::bail!("invalid pcm cache file")
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(mmap[i..i + 4].try_into().unwrap());
+        let storage = Storage::from_code(u32_at(8))?;
+        let sample_rate = u32_at(12);
+        let channels = u32_at(16) as usize;
+        let len = u64::from_le_bytes(mmap[24..32].try_into().unwrap()) as usize;
+        if mmap.len() != HEADER_LEN + channels * len * storage.sample_len() {
+            anyhow::bail!("truncated pcm cache file")
+        }
+        Ok(Self { mmap, storage, sample_rate, channels, len })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn duration_sec(&self) -> f64 {
+        self.len as f64 / self.sample_rate as f64
+    }
+
+    pub fn pcm(&self) -> PlanarPcm<'_> {
+        PlanarPcm {
+            data: &self.mmap[HEADER_LEN..],
+            storage: self.storage,
+            channels: self.channels,
+            len: self.len,
+        }
+    }
+}
+
+fn build(cache_path: &Path, path: &str, opts: &audio::OpenOptions, storage: Storage) -> Result<()> {
+    let mut reader = audio::FileReader::new_with_options(path, opts)?;
+    let mut header = [0u8; HEADER_LEN];
+    header[..8].copy_from_slice(MAGIC);
+    header[8..12].copy_from_slice(&storage.code().to_le_bytes());
+    header[12..16].copy_from_slice(&reader.sample_rate().to_le_bytes());
+    header[16..20].copy_from_slice(&(reader.channels() as u32).to_le_bytes());
+
+    if let Some(dir) = cache_path.parent() {
+        std::fs::create_dir_all(dir)?
+    }
+    let tmp_path =
+        PathBuf::from(format!("{}.{:016x}.tmp", cache_path.display(), rand::random::<u64>()));
+    let res = (|| {
+        let mut w = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+        match storage {
+            Storage::Int16 => write_data(&mut reader, &mut header, &mut w, i16::to_le_bytes)?,
+            Storage::Int32 => write_data(&mut reader, &mut header, &mut w, i32::to_le_bytes)?,
+            Storage::Float32 => write_data(&mut reader, &mut header, &mut w, f32::to_le_bytes)?,
+            Storage::Float64 => write_data(&mut reader, &mut header, &mut w, f64::to_le_bytes)?,
+        }
+        w.flush()?;
+        std::fs::rename(&tmp_path, cache_path)?;
+        Ok(())
+    })();
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    res
+}
+
+/// Decodes the whole file as `T` and writes the header, completed with the number of samples,
+/// followed by the data.
+fn write_data<T: audio::OutputSample, const N: usize, W: Write>(
+    reader: &mut audio::FileReader,
+    header: &mut [u8; HEADER_LEN],
+    w: &mut W,
+    to_le_bytes: fn(T) -> [u8; N],
+) -> Result<()> {
+    let data: Array2<T> = reader.decode_all_as()?;
+    header[24..32].copy_from_slice(&(data.ncols() as u64).to_le_bytes());
+    w.write_all(header)?;
+    for v in data.iter() {
+        w.write_all(&to_le_bytes(*v))?
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn check_roundtrip<T: audio::OutputSample + PartialEq + std::fmt::Debug>(
+        dir: &Path,
+        path: &str,
+        storage: Storage,
+    ) -> Result<()> {
+        let opts = audio::OpenOptions::default();
+        let cached = CachedPcm::open_or_build(dir, path, &opts, storage)?;
+        let expected: Array2<T> = audio::FileReader::new(path)?.decode_all_as()?;
+        let (data, len) = cached.pcm().read::<T>(0, expected.ncols(), false);
+        assert_eq!(len, expected.ncols());
+        assert_eq!(data, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn lossless_storage() -> Result<()> {
+        let dir = TempDir::new("pcm-cache")?;
+        let dir = dir.path();
+        let path = dir.join("ramp.wav");
+        let samples: Vec<f32> = (0..4000).map(|i| (i % 100) as f32 / 200. - 0.25).collect();
+        let mut file = std::fs::File::create(&path)?;
+        crate::wav::write_stereo(&mut file, &samples, 8000, &Default::default())?;
+        drop(file);
+        let path = path.to_string_lossy().to_string();
+        check_roundtrip::<i16>(dir, &path, Storage::Int16)?;
+        check_roundtrip::<i32>(dir, &path, Storage::Int32)?;
+        check_roundtrip::<f32>(dir, &path, Storage::Float32)?;
+        check_roundtrip::<f64>(dir, &path, Storage::Float64)?;
+        Ok(())
+    }
+}
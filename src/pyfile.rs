@@ -0,0 +1,95 @@
+use pyo3::prelude::*;
+
+/// Wraps a python file-like object so that it can be used as a rust reader, this requires the
+/// `read` and `seek` methods, or as a rust writer which only requires the `write` method.
+pub struct PyFileLike {
+    inner: Py<PyAny>,
+}
+
+fn to_io_err(err: PyErr) -> std::io::Error {
+    std::io::Error::other(err.to_string())
+}
+
+impl PyFileLike {
+    pub fn new(inner: Py<PyAny>) -> Self {
+        Self { inner }
+    }
+
+    /// Returns true if the object looks like a readable python file.
+    pub fn is_readable(obj: &Bound<'_, PyAny>) -> bool {
+        obj.hasattr("read").unwrap_or(false)
+    }
+
+    /// Returns true if the object looks like a writable python file.
+    pub fn is_writable(obj: &Bound<'_, PyAny>) -> bool {
+        obj.hasattr("write").unwrap_or(false)
+    }
+}
+
+impl std::io::Read for PyFileLike {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Python::attach(|py| {
+            let data = self.inner.call_method1(py, "read", (buf.len(),)).map_err(to_io_err)?;
+            let data: Vec<u8> = data.extract(py).map_err(to_io_err)?;
+            if data.len() > buf.len() {
+                return Err(std::io::Error::other("read returned more bytes than requested"));
+            }
+            buf[..data.len()].copy_from_slice(&data);
+            Ok(data.len())
+        })
+    }
+}
+
+impl std::io::Write for PyFileLike {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Python::attach(|py| {
+            let bytes = pyo3::types::PyBytes::new(py, buf);
+            let written = self.inner.call_method1(py, "write", (bytes,)).map_err(to_io_err)?;
+            // Some file-like objects return None rather than the number of bytes written.
+            match written.extract::<Option<usize>>(py).map_err(to_io_err)? {
+                None => Ok(buf.len()),
+                Some(written) => Ok(written),
+            }
+        })
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Python::attach(|py| {
+            let inner = self.inner.bind(py);
+            if inner.hasattr("flush").map_err(to_io_err)? {
+                inner.call_method0("flush").map_err(to_io_err)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl std::io::Seek for PyFileLike {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let (offset, whence) = match pos {
+            std::io::SeekFrom::Start(offset) => (offset as i64, 0),
+            std::io::SeekFrom::Current(offset) => (offset, 1),
+            std::io::SeekFrom::End(offset) => (offset, 2),
+        };
+        Python::attach(|py| {
+            let pos = self.inner.call_method1(py, "seek", (offset, whence)).map_err(to_io_err)?;
+            pos.extract::<u64>(py).map_err(to_io_err)
+        })
+    }
+}
+
+impl symphonia::core::io::MediaSource for PyFileLike {
+    fn is_seekable(&self) -> bool {
+        Python::attach(|py| {
+            let inner = self.inner.bind(py);
+            match inner.call_method0("seekable") {
+                Ok(seekable) => seekable.is_truthy().unwrap_or(false),
+                Err(_) => inner.hasattr("seek").unwrap_or(false),
+            }
+        })
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
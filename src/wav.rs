@@ -22,22 +22,156 @@ impl Sample for i16 {
     }
 }
 
-/// The samples are copied as is in the resulting wav files so are assumed to be interleaved by
-/// channel.
-pub fn write_multi<W: Write, S: Sample>(
+/// Broadcast wave metadata stored in the `bext` chunk, see
+/// https://tech.ebu.ch/docs/tech/tech3285.pdf
+#[derive(Debug, Clone, Default)]
+pub struct Bext {
+    pub description: String,
+    pub originator: String,
+    pub originator_reference: String,
+    /// Formatted as yyyy-mm-dd.
+    pub origination_date: String,
+    /// Formatted as hh:mm:ss.
+    pub origination_time: String,
+    /// The number of samples since midnight for the first sample of the file.
+    pub time_reference: u64,
+    pub coding_history: String,
+}
+
+/// A marker stored in the `cue ` chunk, the optional label is stored in a `LIST adtl` chunk.
+#[derive(Debug, Clone)]
+pub struct Marker {
+    /// The position of the marker, in samples from the start of the data.
+    pub position: u32,
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub bext: Option<Bext>,
+    pub markers: Vec<Marker>,
+}
+
+// The length of the fixed part of a version 1 bext chunk, the coding history follows.
+const BEXT_LEN: usize = 602;
+
+fn write_fixed_str(buf: &mut Vec<u8>, v: &str, len: usize) {
+    let v = v.as_bytes();
+    let n = usize::min(v.len(), len);
+    buf.extend_from_slice(&v[..n]);
+    buf.resize(buf.len() + len - n, 0);
+}
+
+fn read_fixed_str(buf: &[u8]) -> String {
+    let len = buf.iter().position(|&v| v == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).trim_end().to_string()
+}
+
+fn push_chunk(buf: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    buf.extend_from_slice(id);
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(data);
+    // Chunks are aligned on two bytes.
+    if data.len() % 2 == 1 {
+        buf.push(0)
+    }
+}
+
+impl Metadata {
+    /// Returns the serialized `bext`, `cue ` and `LIST` chunks.
+    fn to_chunks(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        if let Some(bext) = self.bext.as_ref() {
+            let mut data = Vec::with_capacity(BEXT_LEN + bext.coding_history.len());
+            write_fixed_str(&mut data, &bext.description, 256);
+            write_fixed_str(&mut data, &bext.originator, 32);
+            write_fixed_str(&mut data, &bext.originator_reference, 32);
+            write_fixed_str(&mut data, &bext.origination_date, 10);
+            write_fixed_str(&mut data, &bext.origination_time, 8);
+            data.extend_from_slice(&bext.time_reference.to_le_bytes()); // low then high u32
+            data.extend_from_slice(&1u16.to_le_bytes()); // version
+            data.resize(BEXT_LEN, 0); // UMID and reserved bytes
+            data.extend_from_slice(bext.coding_history.as_bytes());
+            push_chunk(&mut buf, b"bext", &data);
+        }
+        if !self.markers.is_empty() {
+            let mut cue = Vec::with_capacity(4 + 24 * self.markers.len());
+            let mut adtl = b"adtl".to_vec();
+            cue.extend_from_slice(&(self.markers.len() as u32).to_le_bytes());
+            for (id, marker) in self.markers.iter().enumerate() {
+                let id = id as u32 + 1;
+                cue.extend_from_slice(&id.to_le_bytes());
+                cue.extend_from_slice(&marker.position.to_le_bytes());
+                cue.extend_from_slice(b"data");
+                cue.extend_from_slice(&0u32.to_le_bytes()); // chunk start
+                cue.extend_from_slice(&0u32.to_le_bytes()); // block start
+                cue.extend_from_slice(&marker.position.to_le_bytes()); // sample offset
+                if let Some(label) = marker.label.as_ref() {
+                    let mut labl = id.to_le_bytes().to_vec();
+                    labl.extend_from_slice(label.as_bytes());
+                    labl.push(0);
+                    push_chunk(&mut adtl, b"labl", &labl);
+                }
+            }
+            push_chunk(&mut buf, b"cue ", &cue);
+            if adtl.len() > 4 {
+                push_chunk(&mut buf, b"LIST", &adtl);
+            }
+        }
+        buf
+    }
+}
+
+// RIFF lengths are stored on 32 bits, larger files use the RF64 format.
+const MAX_RIFF_LEN: u64 = u32::MAX as u64;
+// The length of a ds64 chunk without a table, including its 8 bytes header.
+const DS64_CHUNK_LEN: u64 = 36;
+
+/// Writes the wav header, the RF64 format is used with a ds64 chunk when the data is too large for
+/// the 32 bits RIFF lengths, i.e. when the RIFF length would exceed `max_riff_len`. When
+/// `reserve_ds64` is set, a JUNK chunk is written in place of the ds64 chunk for regular files so
+/// that the header can be converted to RF64 later on.
+/// The `chunks` are written as is between the fmt and data chunks.
+fn write_header<W: Write>(
     w: &mut W,
-    samples: &[S],
     n_channels: u16,
     sample_rate: u32,
+    data_len: u64,
+    reserve_ds64: bool,
+    chunks: &[u8],
+    max_riff_len: u64,
 ) -> std::io::Result<()> {
     // https://en.wikipedia.org/wiki/WAV#WAV_file_header
-    let len = 12u32; // header
-    let len = len + 24u32; // fmt
-    let len = len + samples.len() as u32 * 2 + 8; // data
+    let len = 12u64; // header
+    let len = len + 24u64; // fmt
+    let len = len + chunks.len() as u64; // metadata
+    let len = len + data_len + 8; // data
     let bytes_per_second = sample_rate * 2 * n_channels as u32;
-    w.write_all(b"RIFF")?;
-    w.write_all(&(len - 8).to_le_bytes())?; // total length minus 8 bytes
-    w.write_all(b"WAVE")?;
+    let rf64 = len + DS64_CHUNK_LEN - 8 > max_riff_len;
+    if rf64 {
+        // https://tech.ebu.ch/docs/tech/tech3306v1_1.pdf
+        let n_frames = data_len / (2 * n_channels as u64);
+        w.write_all(b"RF64")?;
+        w.write_all(&u32::MAX.to_le_bytes())?;
+        w.write_all(b"WAVE")?;
+        w.write_all(b"ds64")?;
+        w.write_all(&(DS64_CHUNK_LEN as u32 - 8).to_le_bytes())?;
+        w.write_all(&(len + DS64_CHUNK_LEN - 8).to_le_bytes())?; // riff length
+        w.write_all(&data_len.to_le_bytes())?;
+        w.write_all(&n_frames.to_le_bytes())?;
+        w.write_all(&0u32.to_le_bytes())?; // no table entries
+    } else if reserve_ds64 {
+        w.write_all(b"RIFF")?;
+        w.write_all(&((len + DS64_CHUNK_LEN - 8) as u32).to_le_bytes())?;
+        w.write_all(b"WAVE")?;
+        w.write_all(b"JUNK")?;
+        w.write_all(&(DS64_CHUNK_LEN as u32 - 8).to_le_bytes())?;
+        w.write_all(&[0u8; DS64_CHUNK_LEN as usize - 8])?;
+    } else {
+        w.write_all(b"RIFF")?;
+        w.write_all(&((len - 8) as u32).to_le_bytes())?; // total length minus 8 bytes
+        w.write_all(b"WAVE")?;
+    }
 
     // Format block
     w.write_all(b"fmt ")?;
@@ -49,9 +183,27 @@ pub fn write_multi<W: Write, S: Sample>(
     w.write_all(&(n_channels * 2).to_le_bytes())?; // 2 bytes of data per sample and channel
     w.write_all(&16u16.to_le_bytes())?; // bits per sample
 
+    w.write_all(chunks)?;
+
     // Data block
     w.write_all(b"data")?;
-    w.write_all(&(samples.len() as u32 * 2).to_le_bytes())?;
+    let data_len = if rf64 { u32::MAX } else { data_len as u32 };
+    w.write_all(&data_len.to_le_bytes())?;
+    Ok(())
+}
+
+/// The samples are copied as is in the resulting wav files so are assumed to be interleaved by
+/// channel. The RF64 format is used if the data does not fit in a regular wav file.
+pub fn write_multi<W: Write, S: Sample>(
+    w: &mut W,
+    samples: &[S],
+    n_channels: u16,
+    sample_rate: u32,
+    metadata: &Metadata,
+) -> std::io::Result<()> {
+    let chunks = metadata.to_chunks();
+    let data_len = samples.len() as u64 * 2;
+    write_header(w, n_channels, sample_rate, data_len, false, &chunks, MAX_RIFF_LEN)?;
     for sample in samples.iter() {
         w.write_all(&sample.to_i16().to_le_bytes())?
     }
@@ -62,14 +214,234 @@ pub fn write_mono<W: Write, S: Sample>(
     w: &mut W,
     samples: &[S],
     sample_rate: u32,
+    metadata: &Metadata,
 ) -> std::io::Result<()> {
-    write_multi(w, samples, 1, sample_rate)
+    write_multi(w, samples, 1, sample_rate, metadata)
 }
 
 pub fn write_stereo<W: Write, S: Sample>(
     w: &mut W,
     samples: &[S],
     sample_rate: u32,
+    metadata: &Metadata,
 ) -> std::io::Result<()> {
-    write_multi(w, samples, 2, sample_rate)
+    write_multi(w, samples, 2, sample_rate, metadata)
+}
+
+/// Writes a wav file incrementally. The header lengths are patched by `flush` and `finalize`, so
+/// that if the process gets interrupted the file remains valid up to the last flush. Space is
+/// reserved in the header so that it can be switched to RF64 once the data gets too large.
+pub struct StreamWriter<W: Write + Seek> {
+    w: W,
+    n_channels: u16,
+    sample_rate: u32,
+    data_len: u64,
+    buf: Vec<u8>,
+    max_riff_len: u64,
+    // The position of the header in the underlying writer.
+    start: u64,
+}
+
+impl<W: Write + Seek> StreamWriter<W> {
+    pub fn new(w: W, n_channels: u16, sample_rate: u32) -> std::io::Result<Self> {
+        Self::new_with_max_riff_len(w, n_channels, sample_rate, MAX_RIFF_LEN)
+    }
+
+    fn new_with_max_riff_len(
+        mut w: W,
+        n_channels: u16,
+        sample_rate: u32,
+        max_riff_len: u64,
+    ) -> std::io::Result<Self> {
+        let start = w.stream_position()?;
+        write_header(&mut w, n_channels, sample_rate, 0, true, &[], max_riff_len)?;
+        w.flush()?;
+        let buf = Vec::new();
+        Ok(Self { w, n_channels, sample_rate, data_len: 0, buf, max_riff_len, start })
+    }
+
+    pub fn n_channels(&self) -> u16 {
+        self.n_channels
+    }
+
+    /// The samples are assumed to be interleaved by channel.
+    pub fn write<S: Sample>(&mut self, samples: &[S]) -> std::io::Result<()> {
+        self.buf.clear();
+        for sample in samples.iter() {
+            self.buf.extend_from_slice(&sample.to_i16().to_le_bytes())
+        }
+        self.w.write_all(&self.buf)?;
+        self.data_len += self.buf.len() as u64;
+        Ok(())
+    }
+
+    /// Updates the header with the current data length and flushes the underlying writer.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        let pos = self.w.stream_position()?;
+        self.w.seek(std::io::SeekFrom::Start(self.start))?;
+        let (n_channels, sample_rate) = (self.n_channels, self.sample_rate);
+        write_header(
+            &mut self.w,
+            n_channels,
+            sample_rate,
+            self.data_len,
+            true,
+            &[],
+            self.max_riff_len,
+        )?;
+        self.w.seek(std::io::SeekFrom::Start(pos))?;
+        self.w.flush()
+    }
+
+    pub fn finalize(mut self) -> std::io::Result<W> {
+        self.flush()?;
+        Ok(self.w)
+    }
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads the `bext` metadata and the markers from a wav file, RF64 files are also supported.
+pub fn read_metadata<R: Read + Seek>(r: &mut R) -> std::io::Result<Metadata> {
+    let mut header = [0u8; 12];
+    r.read_exact(&mut header)?;
+    if !matches!(&header[..4], b"RIFF" | b"RF64" | b"BW64") || &header[8..] != b"WAVE" {
+        return Err(invalid_data("not a wav file"));
+    }
+    let mut metadata = Metadata::default();
+    let mut ds64_data_len = None;
+    let mut labels = std::collections::HashMap::new();
+    let mut cue_points = vec![];
+    loop {
+        let mut chunk_header = [0u8; 8];
+        match r.read_exact(&mut chunk_header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        }
+        let id: [u8; 4] = chunk_header[..4].try_into().unwrap();
+        let len = u32::from_le_bytes(chunk_header[4..].try_into().unwrap()) as u64;
+        let len = match (&id, len, ds64_data_len) {
+            (b"data", 0xFFFF_FFFF, Some(data_len)) => data_len,
+            _ => len,
+        };
+        let padded_len = len + len % 2;
+        if !matches!(&id, b"ds64" | b"bext" | b"cue " | b"LIST") {
+            r.seek(std::io::SeekFrom::Current(padded_len as i64))?;
+            continue;
+        }
+        let mut data = vec![0u8; len as usize];
+        r.read_exact(&mut data)?;
+        if len % 2 == 1 {
+            r.seek(std::io::SeekFrom::Current(1))?;
+        }
+        let u32_at = |data: &[u8], p: usize| u32::from_le_bytes(data[p..p + 4].try_into().unwrap());
+        match &id {
+            b"ds64" if data.len() >= 16 => {
+                ds64_data_len = Some(u64::from_le_bytes(data[8..16].try_into().unwrap()))
+            }
+            b"bext" if data.len() >= BEXT_LEN => {
+                metadata.bext = Some(Bext {
+                    description: read_fixed_str(&data[..256]),
+                    originator: read_fixed_str(&data[256..288]),
+                    originator_reference: read_fixed_str(&data[288..320]),
+                    origination_date: read_fixed_str(&data[320..330]),
+                    origination_time: read_fixed_str(&data[330..338]),
+                    time_reference: u64::from_le_bytes(data[338..346].try_into().unwrap()),
+                    coding_history: String::from_utf8_lossy(&data[BEXT_LEN..])
+                        .trim_end_matches('\0')
+                        .to_string(),
+                })
+            }
+            b"cue " if data.len() >= 4 => {
+                let n_points = u32_at(&data, 0) as usize;
+                if data.len() < 4 + 24 * n_points {
+                    return Err(invalid_data("invalid cue chunk"));
+                }
+                for i in 0..n_points {
+                    let point = &data[4 + 24 * i..4 + 24 * (i + 1)];
+                    cue_points.push((u32_at(point, 0), u32_at(point, 20)));
+                }
+            }
+            b"LIST" if data.starts_with(b"adtl") => {
+                let mut pos = 4;
+                while pos + 8 <= data.len() {
+                    let sub_len = u32_at(&data, pos + 4) as usize;
+                    let end = usize::min(pos + 8 + sub_len, data.len());
+                    if &data[pos..pos + 4] == b"labl" && end >= pos + 12 {
+                        let cue_id = u32_at(&data, pos + 8);
+                        labels.insert(cue_id, read_fixed_str(&data[pos + 12..end]));
+                    }
+                    pos = end + sub_len % 2;
+                }
+            }
+            _ => {}
+        }
+    }
+    metadata.markers = cue_points
+        .into_iter()
+        .map(|(id, position)| Marker { position, label: labels.remove(&id) })
+        .collect();
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio;
+    use numpy::ndarray::Array2;
+
+    fn decode(bytes: Vec<u8>) -> anyhow::Result<audio::FileReader> {
+        audio::FileReader::from_bytes(bytes, Some("wav"), &Default::default())
+    }
+
+    #[test]
+    fn rf64_header() -> anyhow::Result<()> {
+        let samples: Vec<i16> = (0..2000).map(|i| (i * 7 % 4000 - 2000) as i16).collect();
+        let metadata = Metadata {
+            bext: None,
+            markers: vec![Marker { position: 10, label: Some("start".to_string()) }],
+        };
+        let chunks = metadata.to_chunks();
+        let mut bytes = vec![];
+        write_header(&mut bytes, 2, 8000, samples.len() as u64 * 2, false, &chunks, 0)?;
+        samples.iter().for_each(|v| bytes.extend(v.to_le_bytes()));
+        assert_eq!(&bytes[..4], b"RF64");
+        assert_eq!(&bytes[12..16], b"ds64");
+
+        let markers = read_metadata(&mut std::io::Cursor::new(&bytes))?.markers;
+        assert_eq!(markers.len(), 1);
+        assert_eq!((markers[0].position, markers[0].label.as_deref()), (10, Some("start")));
+        let mut reader = decode(bytes)?;
+        assert_eq!(reader.channels(), 2);
+        let data: Array2<i16> = reader.decode_all_as()?;
+        let expected = Array2::from_shape_vec((1000, 2), samples)?.reversed_axes();
+        assert_eq!(data, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn stream_writer_promotes_to_rf64() -> anyhow::Result<()> {
+        let samples: Vec<i16> = (0..900).map(|i| (i * 13 % 2000 - 1000) as i16).collect();
+        let w = std::io::Cursor::new(vec![]);
+        let mut w = StreamWriter::new_with_max_riff_len(w, 1, 16000, 1000)?;
+        w.write(&samples[..300])?;
+        w.flush()?;
+        assert_eq!(&w.w.get_ref()[..4], b"RIFF");
+        assert_eq!(&w.w.get_ref()[12..16], b"JUNK");
+        assert_eq!(decode(w.w.get_ref().clone())?.decode_all_as::<i16>()?.len(), 300);
+        // The data does not fit anymore, the reserved JUNK chunk gets replaced by a ds64 chunk.
+        w.write(&samples[300..])?;
+        let bytes = w.finalize()?.into_inner();
+        assert_eq!(&bytes[..4], b"RF64");
+        assert_eq!(&bytes[12..16], b"ds64");
+        assert_eq!(bytes.len(), 12 + DS64_CHUNK_LEN as usize + 24 + 8 + 900 * 2);
+
+        let mut reader = decode(bytes)?;
+        let data: Array2<i16> = reader.decode_all_as()?;
+        assert_eq!(data.as_slice(), Some(samples.as_slice()));
+        Ok(())
+    }
 }
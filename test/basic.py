@@ -1,6 +1,12 @@
+import pathlib
+import tempfile
+
 import numpy as np
 import sphn
 
+# All the files written by the tests go to a temporary directory.
+tmp_path = pathlib.Path(tempfile.mkdtemp())
+
 filename = "bria.mp3"
 durations = sphn.durations([filename])
 print(durations)
@@ -14,10 +20,318 @@ print(data.shape)
 data, sr = sphn.read(filename)
 print(data.shape, sr)
 
-sphn.write_wav("bria_mono.wav", data[0], sr)
-sphn.write_wav("bria_stereo.wav", np.concatenate([data, data]), sr)
-sphn.write_opus("bria.opus", data, sr)
+sphn.write_wav(tmp_path / "bria_mono.wav", data[0], sr)
+sphn.write_wav(tmp_path / "bria_stereo.wav", np.concatenate([data, data]), sr)
+sphn.write_opus(tmp_path / "bria.opus", data, sr)
 
-data_roundtrip, sr_roundtrip = sphn.read_opus("bria.opus")
+data_roundtrip, sr_roundtrip = sphn.read_opus(tmp_path / "bria.opus")
 assert sr_roundtrip == 48000, "sample rate from opus file is not 48khz"
 data_resampled = sphn.resample(data, sr, sr_roundtrip)
+
+# The flac encoder is lossless on the quantized samples, the decoded data should match exactly.
+rng = np.random.default_rng(42)
+t = np.arange(20_000, dtype=np.float32) / 24000
+for channels in [1, 2, 3]:
+    for bits_per_sample in [16, 24]:
+        for compression_level in [0, 3, 5, 8]:
+            pcm = np.stack([np.sin(2 * np.pi * (220 + 110 * c) * t) * 0.8 for c in range(channels)])
+            pcm = pcm + rng.uniform(-0.1, 0.1, size=pcm.shape)
+            pcm = pcm.astype(np.float32)
+            if channels == 1:
+                pcm = pcm[0]
+            sphn.write_flac(
+                tmp_path / "roundtrip.flac",
+                pcm,
+                24000,
+                bits_per_sample=bits_per_sample,
+                compression_level=compression_level,
+            )
+            data, sr = sphn.read(tmp_path / "roundtrip.flac")
+            max_value = np.float32(2 ** (bits_per_sample - 1) - 1)
+            expected = np.trunc(np.clip(pcm, -1, 1) * max_value) / np.float32(2 ** (bits_per_sample - 1))
+            expected = expected.reshape(channels, -1).astype(np.float32)
+            assert sr == 24000, f"unexpected sample rate {sr}"
+            assert data.shape == expected.shape, f"unexpected shape {data.shape}"
+            assert np.array_equal(data, expected), f"flac mismatch {channels} {bits_per_sample} {compression_level}"
+
+try:
+    sphn.write_flac(tmp_path / "empty.flac", np.zeros((1, 0), dtype=np.float32), 24000)
+    raise AssertionError("writing an empty flac stream should fail")
+except ValueError:
+    pass
+
+# Audio files stored in tar and zip archives, the fixtures cover the different ways the archive
+# formats store the member names and sizes.
+import io
+import tarfile
+import zipfile
+
+pcm = rng.uniform(-0.5, 0.5, size=(1, 8000)).astype(np.float32)
+sphn.write_wav(tmp_path / "member.wav", pcm, 16000)
+expected, _ = sphn.read(tmp_path / "member.wav")
+with open(tmp_path / "member.wav", "rb") as f:
+    member_bytes = f.read()
+
+
+def tar_member(name, data):
+    info = tarfile.TarInfo(name)
+    info.size = len(data)
+    return info, io.BytesIO(data)
+
+
+# A member name longer than 100 bytes is stored using a ustar prefix, a GNU long name entry, or a
+# pax path record depending on the format.
+long_name = "d" * 60 + "/" + "m" * 60 + ".wav"
+members = []
+for fmt, fmt_name in [(tarfile.USTAR_FORMAT, "ustar"), (tarfile.GNU_FORMAT, "gnu"), (tarfile.PAX_FORMAT, "pax")]:
+    with tarfile.open(f"{tmp_path}/members_{fmt_name}.tar", "w", format=fmt) as tar:
+        tar.addfile(*tar_member("short.wav", member_bytes))
+        tar.addfile(*tar_member(long_name, member_bytes))
+    members += [f"{tmp_path}/members_{fmt_name}.tar#short.wav", f"{tmp_path}/members_{fmt_name}.tar#{long_name}"]
+
+# Sizes that do not fit in the octal field use a base-256 encoding, patch the size of the first
+# member to use it and recompute the header checksum.
+with open(tmp_path / "members_gnu.tar", "rb") as f:
+    tar_bytes = bytearray(f.read())
+tar_bytes[124:136] = b"\x80" + len(member_bytes).to_bytes(11, "big")
+tar_bytes[148:156] = b" " * 8
+tar_bytes[148:156] = b"%06o\0 " % sum(tar_bytes[:512])
+with open(tmp_path / "members_base256.tar", "wb") as f:
+    f.write(tar_bytes)
+members.append(f"{tmp_path}/members_base256.tar#short.wav")
+
+with zipfile.ZipFile(tmp_path / "members.zip", "w") as zf:
+    zf.writestr("dir/stored.wav", member_bytes, compress_type=zipfile.ZIP_STORED)
+    zf.writestr("dir/deflated.wav", member_bytes, compress_type=zipfile.ZIP_DEFLATED)
+# Lowering the zip64 limit makes zipfile write the zip64 extra fields and end of central directory
+# records that are otherwise only used for archives larger than 4GiB.
+zip64_limit = zipfile.ZIP64_LIMIT
+zipfile.ZIP64_LIMIT = 0
+try:
+    with zipfile.ZipFile(tmp_path / "members_zip64.zip", "w") as zf:
+        zf.writestr("stored.wav", member_bytes, compress_type=zipfile.ZIP_STORED)
+        zf.writestr("dir/deflated.wav", member_bytes, compress_type=zipfile.ZIP_DEFLATED)
+finally:
+    zipfile.ZIP64_LIMIT = zip64_limit
+members += [
+    f"{tmp_path}/members.zip!/dir/stored.wav",
+    f"{tmp_path}/members.zip!/dir/deflated.wav",
+    f"{tmp_path}/members_zip64.zip!/stored.wav",
+    f"{tmp_path}/members_zip64.zip!/dir/deflated.wav",
+]
+
+for member in members:
+    data, sr = sphn.read(member)
+    assert sr == 16000, f"unexpected sample rate {sr} for {member}"
+    assert np.array_equal(data, expected), f"data mismatch for {member}"
+    assert sphn.FileReader(member).duration_sec == 0.5, f"unexpected duration for {member}"
+
+# The archive indexes are cached, rewriting an archive should not return stale members.
+for length in [4000, 6000]:
+    sphn.write_wav(tmp_path / "member.wav", np.zeros((1, length), dtype=np.float32), 16000)
+    with open(tmp_path / "member.wav", "rb") as f:
+        wav_bytes = f.read()
+    with tarfile.open(tmp_path / "rewritten.tar", "w") as tar:
+        tar.addfile(*tar_member("member.wav", wav_bytes))
+    with zipfile.ZipFile(tmp_path / "rewritten.zip", "w") as zf:
+        zf.writestr("member.wav", wav_bytes)
+    for member in [f"{tmp_path}/rewritten.tar#member.wav", f"{tmp_path}/rewritten.zip!/member.wav"]:
+        data, _ = sphn.read(member)
+        assert data.shape == (1, length), f"unexpected shape {data.shape} for {member}"
+
+# Incremental writers, writing to a path or to a file-like object should give the same output.
+chunks = [rng.uniform(-0.5, 0.5, size=(2, n)).astype(np.float32) for n in [100, 2000, 1]]
+sphn.write_wav(tmp_path / "writer_expected.wav", np.concatenate(chunks, axis=1), 16000)
+expected, _ = sphn.read(tmp_path / "writer_expected.wav")
+wav_buf = io.BytesIO()
+for dest in [tmp_path / "writer.wav", wav_buf]:
+    with sphn.WavWriter(dest, 16000, channels=2) as w:
+        w.write(chunks[0])
+        w.flush()
+        if dest is wav_buf:
+            partial = sphn.FileReader.from_bytes(wav_buf.getvalue(), format_hint="wav").decode_all()
+            assert partial.shape == (2, 100), f"unexpected shape after flush {partial.shape}"
+        for chunk in chunks[1:]:
+            w.write(chunk)
+data, sr = sphn.read(tmp_path / "writer.wav")
+assert sr == 16000 and np.array_equal(data, expected), "WavWriter mismatch"
+data = sphn.FileReader.from_bytes(wav_buf.getvalue(), format_hint="wav").decode_all()
+assert np.array_equal(data, expected), "WavWriter mismatch for file-like objects"
+
+opus_buf = io.BytesIO()
+for dest in [tmp_path / "writer.opus", opus_buf]:
+    with sphn.OpusFileWriter(dest, 24000, channels=2) as w:
+        for chunk in chunks:
+            w.write(chunk)
+        w.flush()
+with open(tmp_path / "writer.opus", "rb") as f:
+    assert f.read() == opus_buf.getvalue(), "OpusFileWriter mismatch for file-like objects"
+data, sr = sphn.read_opus_bytes(opus_buf.getvalue())
+assert sr == 48000 and data.shape[0] == 2, f"unexpected opus output {data.shape} {sr}"
+
+# The pre-skip and the final granule position trim the decoded opus data to the written length.
+for n_samples in [0, 100, 960, 12_345]:
+    pcm = rng.uniform(-0.5, 0.5, size=(2, n_samples)).astype(np.float32)
+    sphn.write_opus(tmp_path / "trimmed.opus", pcm, 48000)
+    data, _ = sphn.read_opus(tmp_path / "trimmed.opus")
+    assert data.shape == (2, n_samples), f"unexpected opus shape {data.shape} for {n_samples} samples"
+    with sphn.OpusFileWriter(tmp_path / "trimmed.opus", 24000, channels=2) as w:
+        w.write(pcm)
+    data, _ = sphn.read_opus(tmp_path / "trimmed.opus")
+    assert data.shape == (2, 2 * n_samples), f"unexpected opus shape {data.shape} for {n_samples} samples"
+
+# The metadata can be read from the same sources as the audio data.
+metadata = sphn.read_metadata(filename)
+with open(filename, "rb") as f:
+    mp3_bytes = f.read()
+with tarfile.open(tmp_path / "metadata.tar", "w") as tar:
+    tar.addfile(*tar_member("bria.mp3", mp3_bytes))
+for source in [mp3_bytes, io.BytesIO(mp3_bytes), f"{tmp_path}/metadata.tar#bria.mp3"]:
+    assert sphn.read_metadata(source, format_hint="mp3") == metadata, f"metadata mismatch for {type(source)}"
+
+# Headerless pcm data can be read from an offset, the offset and count are in samples per channel.
+raw = np.arange(-20, 20, dtype="<i2")
+expected = raw.reshape(-1, 2).T.astype(np.float32) / 32768
+with open(tmp_path / "raw.s16le", "wb") as f:
+    f.write(raw.tobytes())
+for source in [raw.tobytes(), tmp_path / "raw.s16le"]:
+    data = sphn.read_raw(source, "s16le", 2, 16000)
+    assert np.array_equal(data, expected), "read_raw mismatch"
+    data = sphn.read_raw(source, "s16le", 2, 16000, offset=3, count=4)
+    assert np.array_equal(data, expected[:, 3:7]), "read_raw mismatch with offset and count"
+    data = sphn.read_raw(source, "s16le", 2, 16000, offset=18, count=4)
+    assert np.array_equal(data, expected[:, 18:]), "read_raw mismatch past the end"
+    data = sphn.read_raw(source, "s16le", 2, 16000, offset=100)
+    assert data.shape == (2, 0), f"unexpected read_raw shape {data.shape}"
+for offset, count in [(2**63, None), (0, 2**63), (20, 2**62 - 1)]:
+    try:
+        sphn.read_raw(raw.tobytes(), "s16le", 2, 16000, offset=offset, count=count)
+        assert False, f"read_raw did not fail for offset {offset} and count {count}"
+    except ValueError:
+        pass
+
+# The wav metadata can be read from the same sources as the audio data.
+markers = [{"position": 10, "label": "start"}, {"position": 50, "label": None}]
+wav_bytes = sphn.write_wav_bytes(np.zeros(100, dtype=np.float32), 16000, markers=markers)
+with open(tmp_path / "markers.wav", "wb") as f:
+    f.write(wav_bytes)
+for source in [tmp_path / "markers.wav", wav_bytes, io.BytesIO(wav_bytes)]:
+    metadata = sphn.read_wav_metadata(source)
+    assert metadata["bext"] is None and metadata["markers"] == markers, f"unexpected metadata {metadata}"
+try:
+    sphn.write_wav_bytes(np.zeros(100, dtype=np.float32), 16000, markers=[{"position": 2**32}])
+    assert False, "write_wav did not fail for a marker position that does not fit on 32 bits"
+except ValueError:
+    pass
+
+# The opus tags are written as vorbis comments and read back with `with_tags`.
+tags = {"SPEAKER": "alice", "LICENSE": "cc-by", "TITLE": "été"}
+pcm = rng.uniform(-0.5, 0.5, size=(1, 4800)).astype(np.float32)
+sphn.write_opus(tmp_path / "tags.opus", pcm, 48000, tags=tags)
+_, _, read_tags = sphn.read_opus(tmp_path / "tags.opus", with_tags=True)
+assert read_tags == tags, f"unexpected opus tags {read_tags}"
+_, _, read_tags = sphn.read_opus_bytes(sphn.write_opus_bytes(pcm, 48000, tags=tags), with_tags=True)
+assert read_tags == tags, f"unexpected opus tags {read_tags}"
+with sphn.OpusFileWriter(tmp_path / "tags.opus", 48000, tags=tags) as w:
+    w.write(pcm)
+_, _, read_tags = sphn.read_opus(tmp_path / "tags.opus", with_tags=True)
+assert read_tags == tags, f"unexpected opus tags {read_tags}"
+_, _, read_tags = sphn.read_opus_bytes(sphn.write_opus_bytes(pcm, 48000), with_tags=True)
+assert read_tags == {}, f"unexpected opus tags {read_tags}"
+
+# Reading from bytes or from file-like objects gives the same data as reading from a path.
+sphn.write_flac(tmp_path / "in_memory.flac", rng.uniform(-0.5, 0.5, size=(2, 5000)).astype(np.float32), 16000)
+for path, format_hint in [
+    (filename, "mp3"),
+    (tmp_path / "bria_stereo.wav", "wav"),
+    (tmp_path / "in_memory.flac", None),
+]:
+    expected, expected_sr = sphn.read(path)
+    with open(path, "rb") as f:
+        file_bytes = f.read()
+    for source in [file_bytes, io.BytesIO(file_bytes)]:
+        data, sr = sphn.read(source, format_hint=format_hint)
+        assert sr == expected_sr and np.array_equal(data, expected), f"read mismatch for {path} {type(source)}"
+    with open(path, "rb") as f:
+        data, sr = sphn.read(f, format_hint=format_hint)
+    assert sr == expected_sr and np.array_equal(data, expected), f"read mismatch for {path} and an open file"
+    fr = sphn.FileReader.from_bytes(file_bytes, format_hint=format_hint)
+    assert fr.duration_sec == sphn.FileReader(path).duration_sec, f"duration mismatch for {path}"
+    assert np.array_equal(fr.decode_all(), expected), f"FileReader.from_bytes mismatch for {path}"
+    data = fr.decode(0.1, 0.2)
+    assert np.array_equal(data, sphn.FileReader(path).decode(0.1, 0.2)), f"decode mismatch for {path}"
+
+# Writing to bytes or to file-like objects gives the same content as writing to a path.
+pcm = rng.uniform(-0.5, 0.5, size=(2, 3000)).astype(np.float32)
+for write, write_bytes, ext in [
+    (sphn.write_wav, sphn.write_wav_bytes, "wav"),
+    (sphn.write_opus, sphn.write_opus_bytes, "opus"),
+]:
+    write(tmp_path / f"to_bytes.{ext}", pcm, 16000)
+    with open(tmp_path / f"to_bytes.{ext}", "rb") as f:
+        expected = f.read()
+    buf = io.BytesIO()
+    write(buf, pcm, 16000)
+    assert buf.getvalue() == expected, f"{write.__name__} mismatch for file-like objects"
+    assert write_bytes(pcm, 16000) == expected, f"{write_bytes.__name__} mismatch"
+
+# The decoded arrays are owned by numpy, C-contiguous and writeable.
+fr = sphn.FileReader(tmp_path / "bria_stereo.wav")
+arrays = {
+    "read": sphn.read(tmp_path / "bria_stereo.wav")[0],
+    "read_resampled": sphn.read(tmp_path / "bria_stereo.wav", sample_rate=8000)[0],
+    "read_int16": sphn.read(tmp_path / "bria_stereo.wav", dtype="int16")[0],
+    "decode": fr.decode(0.5, 1.0),
+    "decode_with_padding": fr.decode_with_padding(fr.duration_sec - 0.5, 1.0),
+    "decode_all": fr.decode_all(dtype="float64"),
+    "read_opus": sphn.read_opus(tmp_path / "writer.opus")[0],
+}
+for name, array in arrays.items():
+    assert array.ndim == 2, f"unexpected shape {array.shape} for {name}"
+    assert array.flags.c_contiguous and array.flags.writeable, f"unexpected flags for {name}"
+    array[:, :1] = 0
+for name in ["read", "read_resampled", "read_int16", "decode", "decode_with_padding", "decode_all"]:
+    assert arrays[name].shape[0] == fr.channels, f"unexpected shape {arrays[name].shape} for {name}"
+for name in ["decode", "decode_with_padding"]:
+    assert arrays[name].shape == (fr.channels, fr.sample_rate), f"unexpected shape {arrays[name].shape} for {name}"
+assert arrays["read_opus"].shape[0] == 2, f"unexpected read_opus shape {arrays['read_opus'].shape}"
+assert arrays["read_int16"].dtype == np.int16 and arrays["decode_all"].dtype == np.float64, "unexpected dtypes"
+
+# Decoding into preallocated arrays gives the same data as the allocating functions.
+fr = sphn.FileReader(tmp_path / "bria_stereo.wav")
+expected = fr.decode(0.5, 0.25)
+out = np.zeros((fr.channels, expected.shape[1]), dtype=np.float32)
+assert fr.decode_into(out, 0.5) == expected.shape[1] and np.array_equal(out, expected), "decode_into mismatch"
+out = np.full((fr.channels, fr.sample_rate), 2.0, dtype=np.float32)
+expected = fr.decode(fr.duration_sec - 0.25, 1.0)
+n_samples = fr.decode_into(out, fr.duration_sec - 0.25)
+assert n_samples == expected.shape[1], f"unexpected decode_into length {n_samples}"
+assert np.array_equal(out[:, :n_samples], expected), "decode_into mismatch at the end of the file"
+assert np.all(out[:, n_samples:] == 2.0), "decode_into modified the samples past the end of the file"
+bad_outs = {
+    "float64": np.zeros((fr.channels, 10), dtype=np.float64),
+    "non-contiguous": np.zeros((fr.channels, 20), dtype=np.float32)[:, ::2],
+    "channels": np.zeros((fr.channels + 1, 10), dtype=np.float32),
+}
+for name, bad_out in bad_outs.items():
+    try:
+        fr.decode_into(bad_out, 0.0)
+        assert False, f"decode_into did not fail for a {name} array"
+    except (TypeError, ValueError):
+        pass
+
+# The samples that do not fit in the output array of append_bytes_into are returned by the next calls.
+pcm = rng.uniform(-0.5, 0.5, size=20 * 960).astype(np.float32)
+stream_writer = sphn.OpusStreamWriter(48000)
+reader, reader_into = sphn.OpusStreamReader(48000), sphn.OpusStreamReader(48000)
+expected, decoded = [], []
+out = np.zeros(700, dtype=np.float32)
+for lo in range(0, len(pcm), 960):
+    opus = stream_writer.append_pcm(pcm[lo : lo + 960])
+    expected.append(reader.append_bytes(opus))
+    n_samples = reader_into.append_bytes_into(opus, out)
+    decoded.append(out[:n_samples].copy())
+while (n_samples := reader_into.append_bytes_into(b"", out)) > 0:
+    decoded.append(out[:n_samples].copy())
+expected, decoded = np.concatenate(expected), np.concatenate(decoded)
+assert len(expected) > 0 and np.array_equal(decoded, expected), "append_bytes_into mismatch"